@@ -1,18 +1,35 @@
-use std::collections::HashMap;
 #[cfg(not(target_arch = "wasm32"))]
 use std::io::prelude::*;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    ops::Range,
+};
 
-use log::info;
+use log::{info, warn};
 
-use lib_rv32_common::constants::*;
+use lib_rv32_common::{bit_slice, constants::*};
 
 use crate::{
-    encode_b_imm, encode_func3, encode_func7, encode_i_imm, encode_j_imm, encode_opcode, encode_rd,
-    encode_rs1, encode_rs2, encode_s_imm, encode_u_imm, error::AssemblerError, match_func3,
-    match_func7, parse::*, tokenize,
+    diagnostic::{Diagnostic, Severity},
+    disassembler::{decode_fields, disassemble, DecodedFields},
+    encode, encode_func3, encode_func7, encode_opcode,
+    error::{AssemblerError, DisassembleError},
+    instruction::Instruction,
+    match_func3, match_func7, optimize,
+    parse::*,
+    program::*,
+    pseudo::expand_pseudo,
+    tokenize,
 };
 
-enum InstructionFormat {
+/// The canonical nop, `addi zero, zero, 0`.
+const NOP: u32 = 0x0000_0013;
+
+/// The encoding format of a base instruction, which decides where its
+/// operands are placed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InstructionFormat {
     Itype,
     Rtype,
     Jtype,
@@ -21,6 +38,19 @@ enum InstructionFormat {
     Btype,
 }
 
+/// The format of instructions with `opcode`, if it is a known opcode.
+pub(crate) fn instruction_format(opcode: u8) -> Option<InstructionFormat> {
+    Some(match opcode {
+        OPCODE_ARITHMETIC_IMM | OPCODE_JALR | OPCODE_LOAD => InstructionFormat::Itype,
+        OPCODE_ARITHMETIC => InstructionFormat::Rtype,
+        OPCODE_JAL => InstructionFormat::Jtype,
+        OPCODE_LUI | OPCODE_AUIPC => InstructionFormat::Utype,
+        OPCODE_BRANCH => InstructionFormat::Btype,
+        OPCODE_STORE => InstructionFormat::Stype,
+        _ => return None,
+    })
+}
+
 /// Assemble a single instruction.
 ///
 /// Parameters:
@@ -30,31 +60,165 @@ enum InstructionFormat {
 ///
 /// Returns:
 ///     `Result<Option<u32>>`: The assembled binary instruction, an error, or nothing.
+///
+/// Pseudo-instructions that expand to more than one instruction, like `li`
+/// with a large immediate, can't be assembled to a single word and return
+/// `MultipleInstructionsError`. Assemble them as part of a program instead.
 pub fn assemble_ir(
     ir_string: &str,
     labels: &mut HashMap<String, u32>,
     pc: u32,
 ) -> Result<Option<u32>, AssemblerError> {
-    let mut msg = String::new();
-    let mut ir: u32 = 0;
-
     let mut tokens: Vec<String> = tokenize!(ir_string);
 
+    // Add and remove leading labels.
+    while !tokens.is_empty() && tokens[0].ends_with(':') {
+        labels.insert(tokens[0].strip_suffix(':').unwrap().to_owned(), pc);
+        tokens.remove(0);
+    }
+
     if tokens.is_empty() {
         return Ok(None);
-    } else if tokens.len() > 5 {
-        return Err(AssemblerError::TooManyTokensError);
     }
 
-    // Add and remove leading label.
-    if tokens[0].ends_with(':') {
-        labels.insert(tokens[0].strip_suffix(':').unwrap().to_owned(), pc);
-        tokens.remove(0);
+    if let Some(mut expansion) = expand_pseudo(&tokens)? {
+        if expansion.len() > 1 {
+            return Err(AssemblerError::MultipleInstructionsError);
+        }
+        tokens = expansion.remove(0);
+    }
+
+    assemble_tokens(ir_string, &tokens, labels, pc).map(Some)
+}
+
+/// Assemble one line at `pc`, in the context of already-known labels.
+/// Unlike `assemble_ir`, pseudo-instructions may expand to any number of
+/// words, and `labels` is left untouched; labels on the line itself are
+/// visible to it at `pc`. Directives are not supported.
+///
+/// Parameters:
+///     `line: &str`: The line of source
+///     `labels: &HashMap<String, u32>`: Map of labels
+///     `pc: u32`: Address of the line
+///
+/// Returns:
+///     `Result<(Vec<u32>, u32), AssemblerError>`: The assembled words, which
+///     are empty for a line with no instruction, and the address after them.
+///
+/// ```
+/// use std::collections::HashMap;
+/// use lib_rv32_asm::{assemble_line, disassemble};
+///
+/// let mut labels = HashMap::new();
+/// labels.insert("loop".to_owned(), 0x10);
+///
+/// let (words, pc) = assemble_line("bne t0, t1, loop", &labels, 0x20).unwrap();
+/// assert_eq!(0x24, pc);
+/// assert_eq!("bne t0, t1, -16", disassemble(words[0]).unwrap().to_string());
+/// ```
+pub fn assemble_line(
+    line: &str,
+    labels: &HashMap<String, u32>,
+    pc: u32,
+) -> Result<(Vec<u32>, u32), AssemblerError> {
+    let tokens: Vec<String> = tokenize!(line);
+    let (line_labels, tokens) = split_labels(&tokens);
+    let mut local;
+    let labels = if line_labels.is_empty() {
+        labels
+    } else {
+        local = labels.clone();
+        local.extend(line_labels.into_iter().map(|l| (l, pc)));
+        &local
+    };
+
+    if tokens.is_empty() {
+        return Ok((Vec::new(), pc));
     }
 
+    let instructions = match expand_pseudo(tokens)? {
+        Some(expansion) => expansion,
+        None => vec![tokens.to_vec()],
+    };
+    let mut words = Vec::new();
+    let mut pc = pc;
+    for tokens in &instructions {
+        words.push(assemble_tokens(line, tokens, labels, pc)?);
+        pc = pc.wrapping_add(4);
+    }
+    Ok((words, pc))
+}
+
+/// Parse one line into an `Instruction` without expanding it, so a
+/// pseudo-instruction stays an `Instruction::Pseudo` with its operands as
+/// written. `Instruction::encode` expands it. Labels on the line are
+/// skipped. Base instructions are parsed into their typed form, so their
+/// operands must not use labels.
+///
+/// Parameters:
+///     `line: &str`: The line of source
+///
+/// Returns:
+///     `Result<Option<Instruction>, AssemblerError>`: The instruction, or
+///     nothing if the line has none.
+pub fn parse_instruction(line: &str) -> Result<Option<Instruction>, AssemblerError> {
+    let tokens: Vec<String> = tokenize!(line);
+    let (_, tokens) = split_labels(&tokens);
     if tokens.is_empty() {
         return Ok(None);
     }
+    if expand_pseudo(tokens)?.is_some() {
+        return Ok(Some(Instruction::Pseudo {
+            name: tokens[0].clone(),
+            operands: tokens[1..].to_vec(),
+        }));
+    }
+    let ir = assemble_tokens(line, tokens, &HashMap::new(), 0)?;
+    disassemble(ir)
+        .map(Some)
+        .map_err(|_| AssemblerError::InvalidOperationError(tokens[0].clone()))
+}
+
+/// Compute the offset a branch at `from_pc` needs to reach `target`.
+///
+/// Parameters:
+///     `from_pc: u32`: The address of the branch instruction.
+///     `target: u32`: The address to branch to.
+///
+/// Returns:
+///     `Result<i32>`: The signed displacement, or the same error `assemble_ir`
+///     would return if it does not fit in a B-type immediate.
+pub fn branch_offset(from_pc: u32, target: u32) -> Result<i32, AssemblerError> {
+    let offset = target.wrapping_sub(from_pc);
+    encode::encode_b_imm(offset)?;
+    Ok(offset as i32)
+}
+
+/// Compute the offset a `jal` at `from_pc` needs to reach `target`.
+///
+/// Parameters:
+///     `from_pc: u32`: The address of the jump instruction.
+///     `target: u32`: The address to jump to.
+///
+/// Returns:
+///     `Result<i32>`: The signed displacement, or the same error `assemble_ir`
+///     would return if it does not fit in a J-type immediate.
+pub fn jal_offset(from_pc: u32, target: u32) -> Result<i32, AssemblerError> {
+    let offset = target.wrapping_sub(from_pc);
+    encode::encode_j_imm(offset)?;
+    Ok(offset as i32)
+}
+
+/// Assemble the tokens of a base instruction, with any labels removed.
+/// `ir_string` is only used for logging.
+fn assemble_tokens(
+    ir_string: &str,
+    tokens: &[String],
+    labels: &HashMap<String, u32>,
+    pc: u32,
+) -> Result<u32, AssemblerError> {
+    let mut msg = String::new();
+    let mut ir: u32 = 0;
 
     msg += &format!("{:18} -> [{:02x}] ", ir_string, pc);
 
@@ -67,23 +231,44 @@ pub fn assemble_ir(
     ir |= encode_opcode!(opcode);
 
     // Use the opcode to identify the instruction format.
-    let format = match opcode {
-        OPCODE_ARITHMETIC_IMM | OPCODE_JALR | OPCODE_LOAD => InstructionFormat::Itype,
-        OPCODE_ARITHMETIC => InstructionFormat::Rtype,
-        OPCODE_JAL => InstructionFormat::Jtype,
-        OPCODE_LUI | OPCODE_AUIPC => InstructionFormat::Utype,
-        OPCODE_BRANCH => InstructionFormat::Btype,
-        OPCODE_STORE => InstructionFormat::Stype,
-        _ => unreachable!(),
+    let format = match instruction_format(opcode) {
+        Some(format) => format,
+        None => unreachable!(),
+    };
+
+    // The fields as they should decode, checked by the `verify` feature.
+    let mut expected = DecodedFields {
+        opcode,
+        rd: 0,
+        rs1: 0,
+        rs2: 0,
+        func3: 0,
+        func7: 0,
+        imm: 0,
+        format: Some(format),
+    };
+
+    // Every operand is required; there are no implied zero immediates.
+    let operands = match format {
+        InstructionFormat::Utype | InstructionFormat::Jtype => 2,
+        _ => 3,
     };
+    if tokens.len() != operands + 1 {
+        return Err(operand_count_error(op));
+    }
 
     // Use the destination register field.
-    if let InstructionFormat::Rtype | InstructionFormat::Itype | InstructionFormat::Utype = format {
+    if let InstructionFormat::Rtype
+    | InstructionFormat::Itype
+    | InstructionFormat::Utype
+    | InstructionFormat::Jtype = format
+    {
         let rd = match_register(&tokens[1]);
         if let Err(why) = rd {
             return Err(why);
         }
-        ir |= encode_rd!(rd.unwrap());
+        expected.rd = rd.unwrap();
+        ir |= encode::encode_rd(expected.rd)?;
     }
 
     // Use the first register operand and func3 fields.
@@ -94,7 +279,7 @@ pub fn assemble_ir(
     {
         let rs1 = match_register(
             &tokens[match opcode {
-                OPCODE_LOAD => 3,
+                OPCODE_LOAD | OPCODE_STORE => 3,
                 OPCODE_BRANCH => 1,
                 _ => 2,
             }],
@@ -102,9 +287,11 @@ pub fn assemble_ir(
         if let Err(why) = rs1 {
             return Err(why);
         }
-        ir |= encode_rs1!(rs1.unwrap());
+        expected.rs1 = rs1.unwrap();
+        ir |= encode::encode_rs1(expected.rs1)?;
 
-        ir |= encode_func3!(match_func3!(op));
+        expected.func3 = match_func3!(op);
+        ir |= encode_func3!(expected.func3);
     }
 
     // Use the second register operand field.
@@ -119,12 +306,14 @@ pub fn assemble_ir(
         if let Err(why) = rs2 {
             return Err(why);
         }
-        ir |= encode_rs2!(rs2.unwrap());
+        expected.rs2 = rs2.unwrap();
+        ir |= encode::encode_rs2(expected.rs2)?;
     }
 
     // Use the func7 field.
     if let InstructionFormat::Rtype = format {
-        ir |= encode_func7!(match_func7!(op));
+        expected.func7 = match_func7!(op);
+        ir |= encode_func7!(expected.func7);
     }
 
     match format {
@@ -141,7 +330,22 @@ pub fn assemble_ir(
                 return Err(why);
             }
             let imm = imm.unwrap();
-            ir |= encode_i_imm!(imm);
+            if let Some(ImmediateKind::Unsigned(bits)) = match_imm_kind(op) {
+                // Shifts keep their amount where rs2 would be, and select
+                // an arithmetic shift with the upper func7 bit.
+                if imm >= (1 << bits) {
+                    return Err(AssemblerError::ImmediateTooLargeError);
+                }
+                ir |= encode::encode_rs2(imm as u8)?;
+                expected.imm = imm as i32;
+                if op == "srai" {
+                    ir |= encode_func7!(FUNC7_SUB);
+                    expected.imm |= (FUNC7_SUB as i32) << 5;
+                }
+            } else {
+                ir |= encode::encode_i_imm(imm)?;
+                expected.imm = imm as i32;
+            }
         }
         InstructionFormat::Utype => {
             let imm = parse_imm(&tokens[2], labels, pc);
@@ -149,7 +353,9 @@ pub fn assemble_ir(
                 return Err(why);
             }
             let imm = imm.unwrap();
-            ir |= encode_u_imm!(imm);
+            ir |= encode::encode_u_imm(imm)?;
+            // The upper bit of the immediate decodes as its sign.
+            expected.imm = ((imm << 12) as i32) >> 12;
         }
         InstructionFormat::Jtype => {
             let imm = parse_imm(&tokens[2], labels, pc);
@@ -157,7 +363,8 @@ pub fn assemble_ir(
                 return Err(why);
             }
             let imm = imm.unwrap();
-            ir |= encode_j_imm!(imm);
+            ir |= encode::encode_j_imm(imm)?;
+            expected.imm = imm as i32;
         }
         InstructionFormat::Btype => {
             let imm = parse_imm(&tokens[3], labels, pc);
@@ -165,7 +372,8 @@ pub fn assemble_ir(
                 return Err(why);
             }
             let imm = imm.unwrap();
-            ir |= encode_b_imm!(imm);
+            ir |= encode::encode_b_imm(imm)?;
+            expected.imm = imm as i32;
         }
         InstructionFormat::Stype => {
             let imm = parse_imm(&tokens[2], labels, pc);
@@ -173,15 +381,20 @@ pub fn assemble_ir(
                 return Err(why);
             }
             let imm = imm.unwrap();
-            ir |= encode_s_imm!(imm);
+            ir |= encode::encode_s_imm(imm)?;
+            expected.imm = imm as i32;
         }
         InstructionFormat::Rtype => (),
     }
 
+    if cfg!(feature = "verify") {
+        encode::verify_encoding(ir, &expected)?;
+    }
+
     msg += &format!("{:08x}", ir);
     info!("{}", msg);
 
-    Ok(Some(ir))
+    Ok(ir)
 }
 
 /// Assemble a `BufRead` down to a vector of words. The input should contain
@@ -191,56 +404,2592 @@ pub fn assemble_program_buf<R>(reader: &mut R) -> Result<Vec<u32>, AssemblerErro
 where
     R: BufRead,
 {
-    let mut prog = Vec::new();
-    let mut buf = String::new();
-    let mut labels = HashMap::new();
-    let mut pc: u32 = 0;
+    let mut program = String::new();
+    if reader.read_to_string(&mut program).is_err() {
+        return Err(AssemblerError::IOError);
+    }
+    Ok(assemble_program(&program)?.into_words())
+}
 
-    loop {
-        let bytes_rd = reader.read_line(&mut buf);
+/// Run the first pass over a program, returning the address of every label.
+pub fn parse_labels(program: &str) -> Result<HashMap<String, u32>, AssemblerError> {
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    Ok(assembler.parse_labels()?.0)
+}
 
-        if bytes_rd.is_err() {
-            return Err(AssemblerError::IOError);
-        }
+/// The size in bytes a program will assemble to, computed with only the
+/// first pass.
+pub fn program_size(program: &str) -> Result<usize, AssemblerError> {
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    assembler.size()
+}
 
-        if bytes_rd.unwrap() == 0 {
-            break;
+/// Every directive the assembler accepts.
+static DIRECTIVES: &[&str] = &[
+    ".globl", ".global", ".option", ".text", ".data", ".word", ".half", ".byte", ".org", ".align",
+    ".p2align", ".stack", ".heap", ".equ", ".set", ".type", ".size", ".insn", ".file", ".line",
+];
+
+/// Every directive the assembler accepts, including ones it ignores.
+pub fn supported_directives() -> &'static [&'static str] {
+    DIRECTIVES
+}
+
+/// What a source line holds, as returned by `classify_lines`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+    /// An instruction or pseudo-instruction, including `.insn`.
+    Instruction,
+    /// A directive that emits or reserves data, like `.word` or `.stack`.
+    Data,
+    /// Only labels.
+    Label,
+    /// Nothing but whitespace or a comment.
+    Blank,
+    /// Any other directive or a constant definition, which emit nothing.
+    Directive,
+}
+
+/// Classify each line of a program as code, data, a bare label, blank, or
+/// a control directive, without assembling it. Labels in front of an
+/// instruction or directive do not change its kind.
+///
+/// Parameters:
+///     `program: &str`: The program source
+///
+/// Returns:
+///     `Vec<LineKind>`: The kind of each newline-separated line, in order.
+pub fn classify_lines(program: &str) -> Vec<LineKind> {
+    program
+        .split('\n')
+        .map(|line| {
+            let tokens: Vec<String> = tokenize!(line);
+            let (labels, rest) = split_labels(&tokens);
+            match rest.first().map(|t| &t[..]) {
+                None if labels.is_empty() => LineKind::Blank,
+                None => LineKind::Label,
+                Some(".insn") => LineKind::Instruction,
+                Some(".word") | Some(".half") | Some(".byte") | Some(".stack") | Some(".heap") => {
+                    LineKind::Data
+                }
+                Some(first) if first.starts_with('.') => LineKind::Directive,
+                Some(_) if constant_definition(rest).is_some() => LineKind::Directive,
+                Some(_) => LineKind::Instruction,
+            }
+        })
+        .collect()
+}
+
+/// Assemble a full program of newline-separated instructions.
+pub fn assemble_program(program: &str) -> Result<Program, AssemblerError> {
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    assembler.assemble()
+}
+
+/// Assemble a program and stream it to `out` in `format`.
+///
+/// Parameters:
+///     `program: &str`: The program
+///     `out: &mut W`: Where to write the output
+///     `format: OutputFormat`: The output format
+///
+/// Returns:
+///     `Result<(), AssemblerError>`: Nothing, or the error assembling the
+///     program, or `IOError` if writing fails.
+pub fn assemble_to_writer<W: std::io::Write>(
+    program: &str,
+    out: &mut W,
+    format: OutputFormat,
+) -> Result<(), AssemblerError> {
+    assemble_program(program)?
+        .write(out, format)
+        .map_err(|_| AssemblerError::IOError)
+}
+
+/// Assemble a program once and stream it to every output in `outputs`, each
+/// in its own format, such as a listing and Intel HEX side by side.
+///
+/// Parameters:
+///     `program: &str`: The program
+///     `outputs: &mut [(OutputFormat, &mut dyn std::io::Write)]`: Where to
+///     write the output, and in which format
+///
+/// Returns:
+///     `Result<(), AssemblerError>`: Nothing, or the error assembling the
+///     program, or `IOError` if writing to any output fails. Nothing is
+///     written if the program does not assemble.
+pub fn assemble_to_writers(
+    program: &str,
+    outputs: &mut [(OutputFormat, &mut dyn std::io::Write)],
+) -> Result<(), AssemblerError> {
+    let prog = assemble_program(program)?;
+    for (format, out) in outputs.iter_mut() {
+        prog.write(out, *format)
+            .map_err(|_| AssemblerError::IOError)?;
+    }
+    Ok(())
+}
+
+/// The outputs a command-line tool usually writes for a program, from
+/// `assemble_artifacts`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Artifacts {
+    /// The little-endian byte image, from `Program::to_bytes`.
+    pub bytes: Vec<u8>,
+    /// Intel HEX of the image, from `Program::to_ihex`.
+    pub ihex: String,
+    /// Every label, local or global, from `Program::to_symbol_map`.
+    pub symbol_map: String,
+    /// The listing, from `Program::to_listing`.
+    pub listing: String,
+}
+
+/// Assemble a program once at `base` and render every artifact in
+/// `Artifacts` from the result, so a tool can write whichever files were
+/// asked for without assembling again.
+///
+/// Parameters:
+///     `program: &str`: The program
+///     `base: u32`: Address of the first word
+///
+/// Returns:
+///     `Result<Artifacts, AssemblerError>`: The artifacts, or the error
+///     assembling the program.
+pub fn assemble_artifacts(program: &str, base: u32) -> Result<Artifacts, AssemblerError> {
+    let mut assembler = Assembler::new();
+    assembler.base(base).insert_source(program);
+    let prog = assembler.assemble()?;
+    Ok(Artifacts {
+        bytes: prog.to_bytes(Endian::Little),
+        ihex: prog.to_ihex(),
+        symbol_map: prog.to_symbol_map(true),
+        listing: prog.to_listing(),
+    })
+}
+
+/// Assemble a program straight into `buf`, as if `buf` were loaded at
+/// address `base`, so absolute label expressions see their final
+/// addresses.
+///
+/// Parameters:
+///     `program: &str`: The program
+///     `buf: &mut [u8]`: Where to write the little-endian image
+///     `base: u32`: Address of the first byte of `buf`
+///
+/// Returns:
+///     `Result<usize, AssemblerError>`: The number of bytes written, or
+///     `ImageTooLargeError` if the program does not fit. The size is checked
+///     before anything is assembled, and `buf` is untouched on error.
+pub fn assemble_into(program: &str, buf: &mut [u8], base: u32) -> Result<usize, AssemblerError> {
+    assemble_into_at(program, buf, 0, base)
+}
+
+/// Assemble a program into `buf` starting `offset` bytes in, leaving the
+/// bytes before it, such as a header, untouched. Labels still get
+/// addresses from `base`, the address the code runs at, which is
+/// independent of where it lands in `buf`.
+///
+/// Parameters:
+///     `program: &str`: The program
+///     `buf: &mut [u8]`: Where to write the little-endian image
+///     `offset: usize`: Index in `buf` of the first byte of the image
+///     `base: u32`: Address of the first byte of the image
+///
+/// Returns:
+///     `Result<usize, AssemblerError>`: The number of bytes written, or
+///     `ImageTooLargeError` if the program does not fit after `offset`.
+///     `buf` is untouched on error.
+pub fn assemble_into_at(
+    program: &str,
+    buf: &mut [u8],
+    offset: usize,
+    base: u32,
+) -> Result<usize, AssemblerError> {
+    let mut assembler = Assembler::new();
+    assembler.base(base).insert_source(program);
+
+    let needed = assembler.size()?;
+    if offset + needed > buf.len() {
+        return Err(AssemblerError::ImageTooLargeError {
+            needed: offset + needed,
+            capacity: buf.len(),
+        });
+    }
+
+    let prog = assembler.assemble()?;
+    let image = prog.to_bytes(Endian::Little);
+    buf[offset..offset + image.len()].copy_from_slice(&image);
+    Ok(needed)
+}
+
+/// Assemble a full program, pairing each word with the byte range of the
+/// source that produced it. Every word of a pseudo-instruction maps to the
+/// whole pseudo-instruction.
+pub fn assemble_program_spans(program: &str) -> Result<Vec<(u32, Range<usize>)>, AssemblerError> {
+    let prog = assemble_program(program)?;
+    Ok(prog.words.into_iter().zip(prog.spans).collect())
+}
+
+/// Check a full program without assembling it, returning every error and
+/// warning. See `Assembler::validate`.
+pub fn validate(program: &str) -> Vec<Diagnostic> {
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    assembler.validate()
+}
+
+/// Render an error like rustc does: the message, the one-based line it is
+/// on, the source line, and a caret under the token that failed, or under
+/// the start of the line's code if the error does not name a token. The
+/// location is the one a `LocatedError` from `Assembler::assemble_located`
+/// carries, so the error is located with every option the program was
+/// assembled with.
+///
+/// Parameters:
+///     `program: &str`: The source that was assembled, with every inserted
+///     source joined by newlines
+///     `err: &AssemblerError`: The error
+///
+/// Returns:
+///     `String`: The rendered message. Errors without a location, like
+///     `WarningsDeniedError`, are rendered as just the message.
+pub fn format_error(program: &str, err: &AssemblerError) -> String {
+    let (line_num, column, span, err) = match err {
+        AssemblerError::LocatedError {
+            line,
+            column,
+            span,
+            error,
+        } => (*line, *column, span, error),
+        _ => return format!("error: {}\n", err),
+    };
+    let text = program
+        .split('\n')
+        .nth(line_num)
+        .unwrap_or_default()
+        .trim_end_matches('\r');
+    let width = program
+        .get(span.clone())
+        .map_or(0, |token| token.chars().count());
+    // Tabs before the token are kept so the caret lines up however wide
+    // they are shown.
+    let indent: String = text
+        .chars()
+        .take(column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let number = (line_num + 1).to_string();
+    let gutter = " ".repeat(number.len());
+    format!(
+        "error: {}\n{}--> line {}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+        err,
+        gutter,
+        number,
+        column + 1,
+        gutter,
+        number,
+        text,
+        gutter,
+        indent,
+        "^".repeat(width.max(1))
+    )
+}
+
+/// The token an error is about, as written after tokenizing, if it names
+/// one.
+fn error_token(err: &AssemblerError) -> Option<&str> {
+    use AssemblerError::*;
+    match err {
+        InvalidOperationError(token)
+        | NoSuchLabelError(token)
+        | NoSuchRegisterError(token)
+        | InvalidImmediateError(token)
+        | UnknownDirectiveError(token)
+        | DuplicateLabelError(token)
+        | CircularConstantError(token)
+        | ReservedNameError(token)
+        | InvalidOrderingError(token)
+        | FloatNotSupportedError { token }
+        | ValueTooLargeError { token }
+        | OperandCountError { op: token, .. }
+        | ExtensionDisabledError {
+            mnemonic: token, ..
+        } => Some(token),
+        _ => None,
+    }
+}
+
+/// The column of the first whole-word occurrence of `token` in `line`,
+/// ignoring ASCII case, since tokens are lowercased.
+fn find_token(line: &[char], token: &str) -> Option<usize> {
+    let token: Vec<char> = token.chars().collect();
+    let is_name = |c: Option<&char>| c.is_some_and(|c| c.is_alphanumeric() || "_.$".contains(*c));
+    if token.is_empty() || token.len() > line.len() {
+        return None;
+    }
+    (0..=line.len() - token.len()).find(|i| {
+        line[*i..*i + token.len()]
+            .iter()
+            .zip(&token)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+            && !is_name(i.checked_sub(1).and_then(|j| line.get(j)))
+            && !is_name(line.get(*i + token.len()))
+    })
+}
+
+/// Assemble several source files as one program, as if they were joined in
+/// order. Labels are shared by every file.
+///
+/// Parameters:
+///     `sources: &[(&str, &str)]`: The name and text of each file
+///
+/// Returns:
+///     `Result<Program, AssemblerError>`: The program, with every diagnostic
+///     giving its file and the line in it, or `UnitError` with the file and
+///     line of the first error. `Program::line_map` still counts lines
+///     through all of the files.
+pub fn assemble_units(sources: &[(&str, &str)]) -> Result<Program, AssemblerError> {
+    let mut assembler = Assembler::new();
+    // The first line of each file in the whole program.
+    let mut starts = Vec::new();
+    let mut line = 0;
+    for (name, text) in sources {
+        starts.push((line, *name));
+        line += text.split('\n').count();
+        assembler.insert_source(text);
+    }
+    let locate = |line: usize| {
+        let (start, name) = starts
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= line)
+            .unwrap();
+        (name.to_string(), line - start)
+    };
+
+    let mut prog = assembler.assemble_located().map_err(|err| match err {
+        AssemblerError::LocatedError { line, error, .. } => {
+            let (file, line) = locate(line);
+            AssemblerError::UnitError { file, line, error }
         }
+        err => err,
+    })?;
+    for diagnostic in &mut prog.diagnostics {
+        let (file, line) = locate(diagnostic.line);
+        diagnostic.file = Some(file);
+        diagnostic.line = line;
+    }
+    Ok(prog)
+}
 
-        let ir = assemble_ir(buf.trim_end(), &mut labels, pc);
+/// Reassemble a program after an edit, re-encoding only the lines that
+/// changed when that cannot move anything else, as an editor would on
+/// every keystroke.
+///
+/// Parameters:
+///     `prev: &Program`: What `assemble_program` gave for `old_source`
+///     `old_source: &str`: The source before the edit
+///     `new_source: &str`: The source after the edit
+///
+/// Returns:
+///     `Result<Program, AssemblerError>`: The same program
+///     `assemble_program(new_source)` gives. The whole program is assembled
+///     again if lines were added or removed, if a changed line has a label,
+///     a directive, or a constant definition before or after the edit, since
+///     those define names or lay out data, if a changed line now takes a
+///     different number of words, which would move every later line, or if
+///     a changed line has an error, so that it is reported as usual.
+///     Otherwise only the words, relocations, and diagnostics of the changed
+///     lines are replaced, and later spans shifted by any change in length.
+pub fn reassemble(
+    prev: &Program,
+    old_source: &str,
+    new_source: &str,
+) -> Result<Program, AssemblerError> {
+    let old_lines: Vec<&str> = old_source.split('\n').collect();
+    let new_lines: Vec<&str> = new_source.split('\n').collect();
+    if old_lines.len() != new_lines.len() {
+        return assemble_program(new_source);
+    }
+    let (old_offsets, new_offsets) = (line_offsets(&old_lines), line_offsets(&new_lines));
+    let mut names: HashMap<String, u32> = prev
+        .symbols
+        .iter()
+        .chain(&prev.constants)
+        .map(|(k, v)| (k.clone(), *v))
+        .collect();
+    let assembler = Assembler::new();
+    let mut prog = prev.clone();
 
-        if let Err(why) = ir {
-            return Err(why);
+    for (line_num, (old, new)) in old_lines.iter().zip(&new_lines).enumerate() {
+        if old == new {
+            continue;
+        }
+        let tokens: Vec<String> = tokenize!(new);
+        for line in [&tokenize!(old), &tokens] {
+            let (labels, rest) = split_labels(line);
+            if !labels.is_empty()
+                || rest.first().is_some_and(|t| t.starts_with('.'))
+                || constant_definition(rest).is_some()
+            {
+                return assemble_program(new_source);
+            }
         }
+        let words: Vec<usize> = (0..prev.words.len())
+            .filter(|i| prev.line_map[*i] == line_num)
+            .collect();
+        let start = words.first().copied().unwrap_or(0);
+        let addr = prev.base.wrapping_add(4 * start as u32);
+        names.insert(".".to_owned(), addr);
+        let size = match assembler.line_size(split_labels(&tokens).1, &names, addr) {
+            Ok(size) if size as usize == 4 * words.len() => size,
+            _ => return assemble_program(new_source),
+        };
 
-        if let Some(i) = ir.unwrap() {
-            prog.push(i);
-            pc += 4;
+        let span = code_span(new);
+        let offset = new_offsets[line_num];
+        let line = SourceLine {
+            text: new.to_string(),
+            tokens,
+            span: offset + span.start..offset + span.end,
+            next_offset: offset + new.len() + 1,
+            expansion: None,
+        };
+        let mut patch = Program {
+            base: prev.base,
+            symbols: prev.symbols.clone(),
+            constants: prev.constants.clone(),
+            register_aliases: prev.register_aliases.clone(),
+            ..Program::default()
+        };
+        let mut pc = addr;
+        if assembler
+            .assemble_line(&line, line_num, &names, false, &mut pc, &mut patch)
+            .is_err()
+        {
+            return assemble_program(new_source);
         }
-        buf.clear();
+        prog.words.splice(start..start + words.len(), patch.words);
+        prog.spans.splice(start..start + words.len(), patch.spans);
+        prog.relocations
+            .retain(|r| !(addr..addr.wrapping_add(size)).contains(&r.address));
+        prog.relocations.extend(patch.relocations);
+        prog.diagnostics.retain(|d| d.line != line_num);
+        prog.diagnostics.extend(patch.diagnostics);
     }
 
+    // The spans of lines that did not change move with the text before them.
+    for (span, line_num) in prog.spans.iter_mut().zip(&prog.line_map) {
+        if old_lines[*line_num] == new_lines[*line_num] {
+            let (old, new) = (old_offsets[*line_num], new_offsets[*line_num]);
+            *span = span.start - old + new..span.end - old + new;
+        }
+    }
+    prog.relocations.sort_by_key(|r| r.address);
+    prog.diagnostics.sort_by_key(|d| d.line);
     Ok(prog)
 }
 
-/// Assemble a full program of newline-separated instructions.
-pub fn assemble_program(program: &str) -> Result<Vec<u32>, AssemblerError> {
-    let mut prog = Vec::new();
-    let mut labels = HashMap::new();
-    let mut pc: u32 = 0;
+/// The offset of the start of each line in the text they were split from.
+fn line_offsets(lines: &[&str]) -> Vec<usize> {
+    let mut offset = 0;
+    lines
+        .iter()
+        .map(|line| {
+            let start = offset;
+            offset += line.len() + 1;
+            start
+        })
+        .collect()
+}
+
+/// Parse a full program into instructions without encoding them. See
+/// `Assembler::parse`.
+pub fn parse_program(program: &str) -> (Vec<(Instruction, Range<usize>)>, Vec<Diagnostic>) {
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    assembler.parse()
+}
 
-    for line in program.split("\n") {
-        let ir = assemble_ir(line, &mut labels, pc);
+/// Encode instructions from `parse_program` into words, placed one after
+/// another.
+///
+/// Parameters:
+///     `instructions: &[Instruction]`: The instructions
+///
+/// Returns:
+///     `Result<Vec<u32>, AssemblerError>`: The words, or the first error
+///     encoding an instruction.
+pub fn encode_program(instructions: &[Instruction]) -> Result<Vec<u32>, AssemblerError> {
+    let mut words = Vec::new();
+    for instruction in instructions {
+        let pc = 4 * words.len() as u32;
+        words.extend(instruction.encode(&HashMap::new(), pc)?);
+    }
+    Ok(words)
+}
 
-        if let Err(why) = ir {
-            return Err(why);
+/// Replace operands naming a constant with its value, since a bare constant
+/// is its value and not an offset like a bare label, and operands naming a
+/// register alias with its register.
+fn substitute_names(
+    tokens: &[String],
+    constants: &HashMap<String, u32>,
+    aliases: &HashMap<String, u8>,
+) -> Vec<String> {
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(i, t)| match (constants.get(t), aliases.get(t)) {
+            (Some(value), _) if i > 0 => (*value as i32).to_string(),
+            (_, Some(reg)) if i > 0 => format!("x{}", reg),
+            _ => t.clone(),
+        })
+        .collect()
+}
+
+/// Resolve the operands of an instruction at `pc` and parse it into an
+/// `Instruction`. Pseudo-instructions keep their name, with each operand
+/// that is not a register or a literal replaced by the value the expansion
+/// would give it, so they encode without any labels.
+fn resolve_instruction(
+    text: &str,
+    tokens: &[String],
+    layout: &Layout,
+    names: &HashMap<String, u32>,
+    pc: u32,
+) -> Result<Vec<Instruction>, AssemblerError> {
+    let expansion = match expand_pseudo(tokens)? {
+        None => {
+            let tokens = substitute_names(tokens, &layout.constants, &layout.register_aliases);
+            let ir = assemble_tokens(text, &tokens, names, pc)?;
+            return match disassemble(ir) {
+                Ok(instruction) => Ok(vec![instruction]),
+                Err(_) => Err(AssemblerError::InvalidOperationError(tokens[0].clone())),
+            };
+        }
+        Some(expansion) => expansion,
+    };
+    let symbolic = |t: &String| match_register(t).is_err() && parse_literal(t).is_none();
+    if tokens[0] == "la" || tokens[0] == "call" || (tokens[0] == "li" && symbolic(&tokens[2])) {
+        // `la`, `call`, and a symbolic `li` always take two words, whatever
+        // their value, so keep the words they were laid out with.
+        let mut instructions = Vec::new();
+        for (i, tokens) in expansion.iter().enumerate() {
+            let pc = pc + 4 * i as u32;
+            instructions.extend(resolve_instruction(text, tokens, layout, names, pc)?);
+        }
+        return Ok(instructions);
+    }
+    let tokens = substitute_names(tokens, &layout.constants, &layout.register_aliases);
+    let mut operands = Vec::new();
+    for operand in &tokens[1..] {
+        operands.push(if symbolic(operand) {
+            (parse_imm(operand, names, pc)? as i32).to_string()
+        } else {
+            operand.clone()
+        });
+    }
+    Ok(vec![Instruction::Pseudo {
+        name: tokens[0].clone(),
+        operands,
+    }])
+}
+
+/// Match an assembled word to how a label operand is encoded in it.
+fn match_relocation_kind(ir: u32) -> RelocationKind {
+    match bit_slice!(ir, 6, 0) as u8 {
+        OPCODE_BRANCH => RelocationKind::Branch,
+        OPCODE_JAL => RelocationKind::Jal,
+        OPCODE_LUI | OPCODE_AUIPC => RelocationKind::PcrelHi20,
+        OPCODE_STORE => RelocationKind::PcrelLo12S,
+        _ => RelocationKind::PcrelLo12I,
+    }
+}
+
+/// Returns true if an assembled word is a computation whose only effect is
+/// writing `zero`, other than the canonical nop.
+fn discards_result(ir: u32) -> bool {
+    let pure = matches!(
+        bit_slice!(ir, 6, 0) as u8,
+        OPCODE_ARITHMETIC | OPCODE_ARITHMETIC_IMM | OPCODE_LUI | OPCODE_AUIPC
+    );
+    pure && bit_slice!(ir, 11, 7) == 0 && ir != NOP
+}
+
+/// If `tokens` are an `li` of a literal whose `addi` lower part has bit 11
+/// set, a warning that it is negative and the `lui` carries, as for `%lo`.
+/// An `li` that is only an `addi` has no upper part to carry into.
+fn li_sign_warning(tokens: &[String]) -> Option<String> {
+    if tokens[0] != "li" || tokens.len() != 3 {
+        return None;
+    }
+    let value = parse_literal(&tokens[2])? as u32;
+    let (_, lo) = split_hi_lo(value);
+    if lo >= 0 || lo as u32 == value {
+        return None;
+    }
+    Some(format!(
+        "the lower part of `li {}, {}` is {}, since bit 11 is set and sign-extends; the `lui` carries one into the upper bits",
+        tokens[1], tokens[2], lo
+    ))
+}
+
+/// If an instruction's 12-bit signed immediate is just past either end of
+/// its range, the most common off-by-one, explain which bound it missed.
+fn immediate_boundary_hint(
+    tokens: &[String],
+    labels: &HashMap<String, u32>,
+    pc: u32,
+) -> Option<String> {
+    if !matches!(match_imm_kind(&tokens[0]), Some(ImmediateKind::Signed(12))) {
+        return None;
+    }
+    let index = match match_opcode(&tokens[0]) {
+        Ok(OPCODE_LOAD) | Ok(OPCODE_STORE) => 2,
+        _ => 3,
+    };
+    let imm = parse_imm(tokens.get(index)?, labels, pc).ok()? as i32;
+    let (bound, edge) = match imm {
+        2048 => ("maximum", 2047),
+        -2049 => ("minimum", -2048),
+        _ => return None,
+    };
+    Some(format!(
+        "{} is one past the {} {}; did you mean a different instruction or a `li`?",
+        imm, bound, edge
+    ))
+}
+
+/// If the tokens are a conditional branch to a label or expression, return
+/// the branch, expanded from a pseudo-instruction if needed.
+fn relaxable_branch(tokens: &[String]) -> Option<Vec<String>> {
+    let branch = match expand_pseudo(tokens) {
+        Ok(Some(expansion)) if expansion.len() == 1 => expansion[0].clone(),
+        Ok(None) if !tokens.is_empty() => tokens.to_vec(),
+        _ => return None,
+    };
+    match match_opcode(&branch[0]) {
+        Ok(OPCODE_BRANCH) if branch.len() == 4 && parse_literal(&branch[3]).is_none() => {
+            Some(branch)
         }
+        _ => None,
+    }
+}
 
-        if let Some(i) = ir.unwrap() {
-            prog.push(i);
-            pc += 4;
+/// If the tokens are a `call`, the `jal` it shrinks to when its target is
+/// in range.
+fn shrunk_call(tokens: &[String]) -> Option<Vec<String>> {
+    match tokens {
+        [op, target] if op == "call" => {
+            Some(vec!["jal".to_owned(), "ra".to_owned(), target.clone()])
         }
+        _ => None,
     }
+}
 
-    Ok(prog)
+/// The lines with a `call` that relaxing may shrink: those outside
+/// `.option norelax` that the `transform` hook left alone.
+fn relaxable_calls(lines: &[Vec<SourceLine>]) -> HashSet<usize> {
+    let norelax = norelax_lines(lines);
+    lines
+        .iter()
+        .flatten()
+        .enumerate()
+        .filter(|(line_num, line)| {
+            !norelax.contains(line_num)
+                && line.expansion.is_none()
+                && shrunk_call(split_labels(&line.tokens).1).is_some()
+        })
+        .map(|(line_num, _)| line_num)
+        .collect()
+}
+
+/// The instructions a line is relaxed into: a branch over a `jal`, or a
+/// `call` shrunk to a `jal`.
+fn relax_line(tokens: &[String]) -> Vec<Vec<String>> {
+    match shrunk_call(tokens) {
+        Some(jal) => vec![jal],
+        None => relax_branch(&relaxable_branch(tokens).unwrap()),
+    }
+}
+
+/// The lines relaxed in `layout` and the calls that keep both words in it,
+/// plus every branch and shrunk call that is out of range in it, or `None`
+/// if there are no new ones.
+fn relax_branches(lines: &[Vec<SourceLine>], layout: &Layout) -> Option<HashSet<usize>> {
+    let names = layout.names();
+    let norelax = norelax_lines(lines);
+    let mut relaxed = layout.relaxed.clone();
+    relaxed.extend(relaxable_calls(lines).difference(&layout.shrunk));
+    let before = relaxed.len();
+    for (line_num, line) in lines.iter().flatten().enumerate() {
+        let (_, tokens) = split_labels(&line.tokens);
+        if layout.shrunk.contains(&line_num) {
+            let pc = layout.line_pcs[line_num];
+            if let Ok(offset) = parse_imm(&tokens[1], &names, pc) {
+                if !(-(1 << 20)..1 << 20).contains(&(offset as i32)) {
+                    relaxed.insert(line_num);
+                }
+            }
+            continue;
+        }
+        let branch = match relaxable_branch(tokens) {
+            Some(branch)
+                if !relaxed.contains(&line_num)
+                    && !norelax.contains(&line_num)
+                    && line.expansion.is_none() =>
+            {
+                branch
+            }
+            _ => continue,
+        };
+        // Errors are left for the second pass to report.
+        if let Ok(offset) = parse_imm(&branch[3], &names, layout.line_pcs[line_num]) {
+            if !(-4096..4096).contains(&(offset as i32)) {
+                relaxed.insert(line_num);
+            }
+        }
+    }
+    if relaxed.len() > before {
+        Some(relaxed)
+    } else {
+        None
+    }
+}
+
+/// The lines where relaxation is turned off by `.option norelax`, until
+/// `.option relax` or the `.option pop` matching an `.option push` before
+/// it.
+fn norelax_lines(lines: &[Vec<SourceLine>]) -> HashSet<usize> {
+    let mut norelax = HashSet::new();
+    let mut relax = true;
+    let mut stack = Vec::new();
+    for (line_num, line) in lines.iter().flatten().enumerate() {
+        let (_, tokens) = split_labels(&line.tokens);
+        if tokens.first().map(|t| &t[..]) == Some(".option") {
+            for arg in &tokens[1..] {
+                match &arg[..] {
+                    "push" => stack.push(relax),
+                    "pop" => relax = stack.pop().unwrap_or(true),
+                    "relax" => relax = true,
+                    "norelax" => relax = false,
+                    _ => (),
+                }
+            }
+        } else if !relax {
+            norelax.insert(line_num);
+        }
+    }
+    norelax
+}
+
+/// Relax a conditional branch into the inverse branch over a `jal` to the
+/// original target, which reaches much farther.
+fn relax_branch(branch: &[String]) -> Vec<Vec<String>> {
+    let inverse = match &branch[0][..] {
+        "beq" => "bne",
+        "bne" => "beq",
+        "blt" => "bge",
+        "bge" => "blt",
+        "bltu" => "bgeu",
+        _ => "bltu",
+    };
+    vec![
+        vec![
+            inverse.to_owned(),
+            branch[1].clone(),
+            branch[2].clone(),
+            "8".to_owned(),
+        ],
+        vec!["jal".to_owned(), "zero".to_owned(), branch[3].clone()],
+    ]
+}
+
+/// If `ir` at `pc` is a branch or `jal`, add an info diagnostic with its
+/// target address and the label there, if any.
+fn report_target(ir: u32, line_num: usize, pc: u32, prog: &mut Program) {
+    let fields = decode_fields(ir);
+    let op = match fields.opcode {
+        OPCODE_BRANCH | OPCODE_JAL => match disassemble(ir) {
+            Ok(instruction) => instruction.op().to_owned(),
+            Err(_) => return,
+        },
+        _ => return,
+    };
+    let target = pc.wrapping_add(fields.imm as u32);
+    let mut message = format!("`{}` resolves to {:#x}", op, target);
+    if let Some(label) = prog.label_at(target) {
+        message += &format!(" (label `{}`)", label);
+    }
+    info!("Line {}: {}", line_num, message);
+    prog.diagnostics.push(Diagnostic::info(line_num, message));
+}
+
+/// If the word at `index` is a branch or `jal` whose target lies inside
+/// one of the `data` ranges, add a warning: the target is an address, but
+/// not that of an instruction.
+fn warn_data_target(index: usize, line_num: usize, data: &[Range<u32>], prog: &mut Program) {
+    let ir = prog.words[index];
+    let fields = decode_fields(ir);
+    if fields.opcode != OPCODE_BRANCH && fields.opcode != OPCODE_JAL {
+        return;
+    }
+    let pc = prog.base.wrapping_add(4 * index as u32);
+    let target = pc.wrapping_add(fields.imm as u32);
+    if let Some(region) = data.iter().find(|region| region.contains(&target)) {
+        let message = format!(
+            "branch target {:#x} is inside data at {:#x}..{:#x}, not an instruction",
+            target, region.start, region.end
+        );
+        warn!("Line {}: {}", line_num, message);
+        prog.diagnostics
+            .push(Diagnostic::warning(line_num, message));
+    }
+}
+
+/// Warn about every source label that no line refers to and that is not
+/// global, keeping the diagnostics in source order.
+fn warn_unused_labels(lines: &[Vec<SourceLine>], prog: &mut Program) {
+    let mut used = HashSet::new();
+    for line in lines.iter().flatten() {
+        let (_, tokens) = split_labels(&line.tokens);
+        for token in tokens {
+            used.extend(token.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.')));
+        }
+    }
+
+    for (line_num, line) in lines.iter().flatten().enumerate() {
+        let (labels, _) = split_labels(&line.tokens);
+        for label in labels {
+            if !used.contains(&label[..]) && !prog.globals.contains(&label) {
+                warn!("Line {}: label `{}` is never used", line_num, label);
+                prog.diagnostics.push(Diagnostic::warning(
+                    line_num,
+                    format!("label `{}` is never used", label),
+                ));
+            }
+        }
+    }
+    prog.diagnostics.sort_by_key(|d| d.line);
+}
+
+/// Split the leading `label:` tokens off a line, returning the label names
+/// and the remaining tokens.
+fn split_labels(tokens: &[String]) -> (Vec<String>, &[String]) {
+    let labels_end = tokens
+        .iter()
+        .position(|t| !t.ends_with(':'))
+        .unwrap_or(tokens.len());
+    let labels = tokens[..labels_end]
+        .iter()
+        .map(|l| l.strip_suffix(':').unwrap().to_owned())
+        .collect();
+    (labels, &tokens[labels_end..])
+}
+
+/// Keep the empty operands of an `.align` or `.p2align` on a line as empty
+/// tokens, which the tokenizer drops, so that `.p2align 4,,8` has a maximum
+/// of 8 and the default fill rather than a fill of 8.
+fn keep_empty_operands(text: &str, tokens: Vec<String>) -> Vec<String> {
+    let (_, rest) = split_labels(&tokens);
+    let directive = match rest.first() {
+        Some(d) if d == ".align" || d == ".p2align" => d.clone(),
+        _ => return tokens,
+    };
+    let code = &text[code_span(text)];
+    let operands = match code.get(..directive.len()) {
+        Some(d) if d.eq_ignore_ascii_case(&directive) => &code[directive.len()..],
+        _ => return tokens,
+    };
+    if !operands.contains(',') {
+        return tokens;
+    }
+    let mut out = tokens[..tokens.len() - rest.len()].to_vec();
+    out.push(directive);
+    for operand in operands.split(',') {
+        match tokenize(operand) {
+            t if t.is_empty() => out.push(String::new()),
+            t => out.extend(t),
+        }
+    }
+    out
+}
+
+/// The symbol a `.stack` or `.heap` directive defines, and whether it is at
+/// the end of the region rather than the start. The stack grows down from
+/// its top, and the heap up from its start.
+fn region_symbol(directive: &str) -> Option<(&'static str, bool)> {
+    match directive {
+        ".stack" => Some(("__stack_top", true)),
+        ".heap" => Some(("__heap_start", false)),
+        _ => None,
+    }
+}
+
+/// Evaluate a `.insn` field that must fit in `bits` unsigned bits.
+fn insn_field(expr: &str, bits: u32, labels: &HashMap<String, u32>) -> Result<u32, AssemblerError> {
+    let value = eval_expr(expr, labels)?;
+    if value >= 1 << bits {
+        return Err(AssemblerError::ImmediateTooLargeError);
+    }
+    Ok(value)
+}
+
+/// Encode a `.insn` directive at `pc` from its fields, given its tokens
+/// starting with the directive name. The immediates are parsed like the
+/// operands of an instruction, so labels are relative to `pc`.
+fn assemble_insn(
+    tokens: &[String],
+    labels: &HashMap<String, u32>,
+    pc: u32,
+) -> Result<u32, AssemblerError> {
+    let (format, operands) = match tokens.get(1).map(|t| &t[..]) {
+        Some("r") => (InstructionFormat::Rtype, 6),
+        Some("i") => (InstructionFormat::Itype, 5),
+        Some("s") => (InstructionFormat::Stype, 5),
+        Some("b") => (InstructionFormat::Btype, 5),
+        Some("u") => (InstructionFormat::Utype, 3),
+        Some("j") => (InstructionFormat::Jtype, 3),
+        Some(format) => return Err(AssemblerError::InvalidOperationError(format.to_owned())),
+        None => return Err(AssemblerError::TooFewTokensError),
+    };
+    let fields = &tokens[2..];
+    match fields.len() {
+        n if n < operands => return Err(AssemblerError::TooFewTokensError),
+        n if n > operands => return Err(AssemblerError::TooManyTokensError),
+        _ => (),
+    }
+    let reg = |i: usize| match_register(&fields[i]);
+    let imm = |i: usize| parse_imm(&fields[i], labels, pc);
+
+    let mut ir = encode_opcode!(insn_field(&fields[0], 7, labels)?);
+    ir |= match format {
+        InstructionFormat::Rtype => {
+            encode_func3!(insn_field(&fields[1], 3, labels)?)
+                | encode_func7!(insn_field(&fields[2], 7, labels)?)
+                | encode::encode_rd(reg(3)?)?
+                | encode::encode_rs1(reg(4)?)?
+                | encode::encode_rs2(reg(5)?)?
+        }
+        InstructionFormat::Itype => {
+            encode_func3!(insn_field(&fields[1], 3, labels)?)
+                | encode::encode_rd(reg(2)?)?
+                | encode::encode_rs1(reg(3)?)?
+                | encode::encode_i_imm(imm(4)?)?
+        }
+        // `offset(rs1)` is split into two tokens.
+        InstructionFormat::Stype => {
+            encode_func3!(insn_field(&fields[1], 3, labels)?)
+                | encode::encode_rs2(reg(2)?)?
+                | encode::encode_s_imm(imm(3)?)?
+                | encode::encode_rs1(reg(4)?)?
+        }
+        InstructionFormat::Btype => {
+            encode_func3!(insn_field(&fields[1], 3, labels)?)
+                | encode::encode_rs1(reg(2)?)?
+                | encode::encode_rs2(reg(3)?)?
+                | encode::encode_b_imm(imm(4)?)?
+        }
+        InstructionFormat::Utype => encode::encode_rd(reg(1)?)? | encode::encode_u_imm(imm(2)?)?,
+        InstructionFormat::Jtype => encode::encode_rd(reg(1)?)? | encode::encode_j_imm(imm(2)?)?,
+    };
+    Ok(ir)
+}
+
+/// A message if `ir` has a standard opcode but a func3 or func7 that no
+/// RV32I instruction with that opcode uses, such as a shift with func7
+/// bits other than the one selecting an arithmetic shift. Custom opcodes
+/// are not reserved, since they are what `.insn` is for.
+fn reserved_encoding(ir: u32) -> Option<String> {
+    let (field, value) = match disassemble(ir) {
+        Err(DisassembleError::InvalidFunc3Error(_, func3)) => ("func3", func3),
+        Err(DisassembleError::InvalidFunc7Error(_, func7)) => ("func7", func7),
+        _ => return None,
+    };
+    Some(format!(
+        "{:#010x} is a reserved encoding: its opcode has no instruction with {} {:#x}",
+        ir, field, value
+    ))
+}
+
+/// The symbol and operand of `.type name, kind` or `.size name, expr`.
+fn symbol_attribute(tokens: &[String]) -> Result<(&String, &String), AssemblerError> {
+    match tokens {
+        [_, name, operand] => Ok((name, operand)),
+        [_] | [_, _] => Err(AssemblerError::TooFewTokensError),
+        _ => Err(AssemblerError::TooManyTokensError),
+    }
+}
+
+/// The number of bytes a `.stack` or `.heap` directive reserves, rounded
+/// up to a whole word.
+fn region_size(tokens: &[String], labels: &HashMap<String, u32>) -> Result<u32, AssemblerError> {
+    match tokens.len() {
+        0 | 1 => Err(AssemblerError::TooFewTokensError),
+        2 => match eval_expr(&tokens[1], labels)?.checked_add(3) {
+            Some(size) => Ok(size & !3),
+            None => Err(AssemblerError::ImmediateTooLargeError),
+        },
+        _ => Err(AssemblerError::TooManyTokensError),
+    }
+}
+
+/// The number of bytes in each value of a data directive.
+fn match_data_width(directive: &str) -> u32 {
+    match directive {
+        ".byte" => 1,
+        ".half" => 2,
+        _ => 4,
+    }
+}
+
+/// Split the operands of a data directive into values and their optional
+/// repeat counts, written `value : count`.
+fn parse_data_items(tokens: &[String]) -> Result<Vec<(&str, Option<&str>)>, AssemblerError> {
+    // `a:4` and `a: 4` tokenize with the colon attached, so split it off.
+    let mut parts = Vec::new();
+    for token in tokens {
+        let mut pieces = token.split(':');
+        parts.push(pieces.next().unwrap());
+        for piece in pieces {
+            parts.push(":");
+            parts.push(piece);
+        }
+    }
+    let parts: Vec<&str> = parts.into_iter().filter(|p| !p.is_empty()).collect();
+
+    let mut items = Vec::new();
+    let mut i = 0;
+    while i < parts.len() {
+        if parts[i] == ":" {
+            return Err(AssemblerError::InvalidImmediateError(":".to_owned()));
+        }
+        if parts.get(i + 1) == Some(&":") {
+            match parts.get(i + 2) {
+                Some(count) if *count != ":" => items.push((parts[i], Some(*count))),
+                _ => return Err(AssemblerError::TooFewTokensError),
+            }
+            i += 3;
+        } else {
+            items.push((parts[i], None));
+            i += 1;
+        }
+    }
+    if items.is_empty() {
+        return Err(AssemblerError::TooFewTokensError);
+    }
+    Ok(items)
+}
+
+/// Evaluate the repeat count of a data item, which defaults to one.
+fn repeat_count(count: Option<&str>, labels: &HashMap<String, u32>) -> Result<u32, AssemblerError> {
+    match count {
+        Some(count) => eval_expr(count, labels),
+        None => Ok(1),
+    }
+}
+
+/// The number of bytes a `.word`, `.half`, or `.byte` directive lays out,
+/// before padding to a whole word, or `ImmediateTooLargeError` if repeat
+/// counts make it more than fits in 32 bits.
+fn data_len(tokens: &[String], labels: &HashMap<String, u32>) -> Result<u32, AssemblerError> {
+    let width = match_data_width(&tokens[0]);
+    let mut len: u32 = 0;
+    for (_, count) in parse_data_items(&tokens[1..])? {
+        len = repeat_count(count, labels)?
+            .checked_mul(width)
+            .and_then(|bytes| len.checked_add(bytes))
+            .ok_or(AssemblerError::ImmediateTooLargeError)?;
+    }
+    Ok(len)
+}
+
+/// Returns true if `value` fits in `width` bytes, as either an unsigned
+/// or a sign-extended value.
+fn fits_width(value: u32, width: usize) -> bool {
+    let bits = 8 * width as u32;
+    bits >= 32 || value >> bits == 0 || ((value as i32) >> (bits - 1)) == -1
+}
+
+/// If a line defines a constant, with `.equ name, expr`, `.set name, expr`,
+/// or `name = expr`, return the name and the expression.
+fn constant_definition(tokens: &[String]) -> Option<Result<(String, String), AssemblerError>> {
+    let (name, expr) = match tokens.first().map(|t| &t[..]) {
+        Some(".equ") | Some(".set") => match tokens.len() {
+            0..=2 => return Some(Err(AssemblerError::TooFewTokensError)),
+            _ => (tokens[1].clone(), tokens[2..].join(" ")),
+        },
+        Some(first) if !first.starts_with('.') && tokens.iter().any(|t| t.contains('=')) => {
+            let line = tokens.join(" ");
+            let (name, expr) = line.split_once('=').unwrap();
+            (name.trim().to_owned(), expr.trim().to_owned())
+        }
+        _ => return None,
+    };
+    if name.is_empty() || name.contains(' ') || parse_literal(&name).is_some() {
+        return Some(Err(AssemblerError::InvalidOperationError(name)));
+    }
+    if match_register(&name).is_ok() {
+        return Some(Err(AssemblerError::ReservedNameError(name)));
+    }
+    if expr.is_empty() {
+        return Some(Err(AssemblerError::TooFewTokensError));
+    }
+    Some(Ok((name, expr)))
+}
+
+/// Replace the location counter `.` in an expression with `pc`, the
+/// address of the line it is on, so that a constant resolved after later
+/// lines are laid out still measures from where it was defined.
+fn substitute_location(expr: &str, pc: u32) -> String {
+    let chars: Vec<char> = expr.chars().collect();
+    let is_name = |c: Option<&char>| c.is_some_and(|c| c.is_alphanumeric() || "_.$".contains(*c));
+    let mut out = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '.'
+            && !is_name(i.checked_sub(1).and_then(|i| chars.get(i)))
+            && !is_name(chars.get(i + 1))
+        {
+            out += &format!("{:#x}", pc);
+        } else {
+            out.push(*c);
+        }
+    }
+    out
+}
+
+/// Returns true if a directive only carries debug information the assembler
+/// does not record, like `.loc` or `.cfi_startproc` in compiler output.
+fn is_debug_directive(name: &str) -> bool {
+    matches!(name, ".loc" | ".stabs" | ".stabn" | ".stabd") || name.starts_with(".cfi_")
+}
+
+/// Returns true if `name` is already a label or constant, including
+/// constants that are not resolved yet.
+fn is_defined(
+    names: &HashMap<String, u32>,
+    pending: &[(usize, String, String)],
+    name: &str,
+) -> bool {
+    names.contains_key(name) || pending.iter().any(|(_, n, _)| n == name)
+}
+
+/// Define a label or constant, which must not already be defined or be
+/// named like a register. A name may be a mnemonic, since mnemonics only
+/// appear first on a line and names only as operands.
+fn define_name(
+    names: &mut HashMap<String, u32>,
+    pending: &[(usize, String, String)],
+    name: String,
+    value: u32,
+) -> Result<(), AssemblerError> {
+    if match_register(&name).is_ok() {
+        return Err(AssemblerError::ReservedNameError(name));
+    }
+    if is_defined(names, pending, &name) {
+        return Err(AssemblerError::DuplicateLabelError(name));
+    }
+    names.insert(name, value);
+    Ok(())
+}
+
+/// Define a constant given as `(line, name, expr)`. If the expression
+/// refers to names not defined yet, it is added to `pending` instead.
+fn define_constant(
+    names: &mut HashMap<String, u32>,
+    pending: &mut Vec<(usize, String, String)>,
+    constants: &mut HashMap<String, u32>,
+    constant: (usize, String, String),
+) -> Result<(), AssemblerError> {
+    let (_, name, expr) = &constant;
+    if is_defined(names, pending, name) {
+        return Err(AssemblerError::DuplicateLabelError(name.clone()));
+    }
+    match eval_expr(expr, names) {
+        Ok(value) => {
+            names.insert(name.clone(), value);
+            constants.insert(name.clone(), value);
+        }
+        Err(_) => pending.push(constant),
+    }
+    Ok(())
+}
+
+/// Resolve constants that referred to names defined after them, given as
+/// `(line, name, expr)`. Each round resolves every constant whose names are
+/// now all defined, and stops once a round resolves nothing, so this always
+/// ends, even for `.equ a, a+1`. Constants left over only refer to
+/// themselves or each other in a cycle, or to names that do not exist.
+fn resolve_constants(
+    names: &mut HashMap<String, u32>,
+    constants: &mut HashMap<String, u32>,
+    mut pending: Vec<(usize, String, String)>,
+    errors: &mut Vec<(usize, AssemblerError)>,
+) {
+    while !pending.is_empty() {
+        let before = pending.len();
+        pending.retain(|(_, name, expr)| match eval_expr(expr, names) {
+            Ok(value) => {
+                names.insert(name.clone(), value);
+                constants.insert(name.clone(), value);
+                false
+            }
+            Err(_) => true,
+        });
+        if pending.len() == before {
+            break;
+        }
+    }
+
+    // With the unresolved constants standing in as zero, any expression
+    // that still fails has an error of its own.
+    let mut stand_ins = names.clone();
+    stand_ins.extend(pending.iter().map(|(_, name, _)| (name.clone(), 0)));
+    for (line, name, expr) in pending {
+        let err = match eval_expr(&expr, &stand_ins) {
+            Ok(_) => AssemblerError::CircularConstantError(name),
+            Err(err) => err,
+        };
+        errors.push((line, err));
+    }
+}
+
+/// The length of inserted bytes after padding to a whole number of words.
+fn padded_len(data: &[u8]) -> u32 {
+    (data.len() as u32).div_ceil(4) * 4
+}
+
+/// The result of the first pass.
+#[derive(Default)]
+struct Layout {
+    labels: HashMap<String, u32>,
+    constants: HashMap<String, u32>,
+    register_aliases: HashMap<String, u8>,
+    /// The number of bytes each source line takes.
+    line_sizes: Vec<u32>,
+    /// The address of each source line.
+    line_pcs: Vec<u32>,
+    /// Source lines with a branch relaxed into a branch over a `jal`.
+    relaxed: HashSet<usize>,
+    /// Source lines with a `call` shrunk into a `jal`.
+    shrunk: HashSet<usize>,
+    /// Source lines that would run past the end of the address space, and
+    /// are laid out as empty.
+    overflowed: HashSet<usize>,
+    /// Address ranges laid out by data directives and byte chunks.
+    data: Vec<Range<u32>>,
+    size: u32,
+}
+
+impl Layout {
+    /// Every label and constant, which share a namespace.
+    fn names(&self) -> HashMap<String, u32> {
+        let mut names = self.labels.clone();
+        names.extend(self.constants.iter().map(|(k, v)| (k.clone(), *v)));
+        names
+    }
+}
+
+/// A line of source, tokenized once and shared by both passes.
+struct SourceLine {
+    /// The text that is assembled, after optimizing if enabled.
+    text: String,
+    tokens: Vec<String>,
+    /// The byte range of the code in the original source.
+    span: Range<usize>,
+    /// The offset of the start of the next line.
+    next_offset: usize,
+    /// The instructions the `transform` hook replaced the line's
+    /// instruction with, as tokens, if it changed it.
+    expansion: Option<Vec<Vec<String>>>,
+}
+
+impl SourceLine {
+    /// The tokens of each instruction on the line after any labels, which
+    /// is the line itself unless the `transform` hook replaced it.
+    fn instructions(&self) -> Vec<&[String]> {
+        match &self.expansion {
+            Some(expansion) => expansion.iter().map(|tokens| &tokens[..]).collect(),
+            None => vec![split_labels(&self.tokens).1],
+        }
+    }
+}
+
+/// A piece of input queued on an `Assembler`.
+enum Chunk {
+    Source(String),
+    Bytes {
+        label: Option<String>,
+        data: Vec<u8>,
+    },
+}
+
+/// Assembles a program built up from source text and raw binary data.
+///
+/// Chunks are placed one after another in the order they are inserted,
+/// so labels in later source see the addresses after any inserted bytes.
+///
+/// Supported directives:
+///
+/// - `.globl sym` / `.global sym`: mark labels as global.
+/// - `.option arg`: accepted for compatibility. `norelax`, `relax`, `push`,
+///   and `pop` scope branch and call relaxation, see `Assembler::relax`. `rvc`,
+///   unknown options, and `relax` without `Assembler::relax` are ignored
+///   with a warning.
+/// - `.word`, `.half`, `.byte`: emit comma-separated values of 4, 2, or 1
+///   bytes. Values are expressions with labels as absolute addresses, and
+///   `value : count` repeats a value. Each directive is zero-padded to a
+///   whole word.
+/// - `.org offset`: zero-fill up to `offset` bytes past the base address.
+///   The location may not move backwards.
+/// - `.align n`: zero-fill up to the next multiple of `2^n` bytes.
+/// - `.insn format opcode, ...`: emit an instruction from its fields, for
+///   instructions the assembler does not know. The forms are
+///   `.insn r opcode, func3, func7, rd, rs1, rs2`,
+///   `.insn i opcode, func3, rd, rs1, imm`,
+///   `.insn s opcode, func3, rs2, imm(rs1)`,
+///   `.insn b opcode, func3, rs1, rs2, offset`,
+///   `.insn u opcode, rd, imm`, and `.insn j opcode, rd, offset`. Fields that
+///   do not fit their width are an error.
+/// - `.type name, @function` / `.size name, expr`: record the ELF symbol
+///   type (`@function`, `@object`, or `@notype`) and size of a symbol.
+///   Unknown types are ignored with a warning.
+/// - `.equ name, expr` / `.set name, expr` / `name = expr`: define a
+///   constant. Constants share a namespace with labels and can be used in
+///   any expression, and may refer to labels and constants defined anywhere.
+///   If `expr` is a register, like `.equ counter, t0`, the name is instead an
+///   alias usable wherever a register is. Names may not be registers.
+///
+/// - `.file "name"` / `.line n`: record where the following words came
+///   from in the source of generated assembly, as `Program::source_location`
+///   reports. Other debug directives, like `.loc` and `.cfi_*`, are
+///   ignored with a warning, even in strict mode.
+///
+/// `.org` and `.align` operands and `.word` repeat counts must only use
+/// labels defined above them.
+///
+/// In any expression, `.` is the address of the line it is on, so
+/// `.equ len, .-start` measures from `start` to the line defining `len`.
+#[derive(Default)]
+pub struct Assembler {
+    chunks: Vec<Chunk>,
+    strict: bool,
+    optimize: bool,
+    warn_zero_destination: bool,
+    warn_unused_labels: bool,
+    warnings_as_errors: bool,
+    relax: bool,
+    pad_to: usize,
+    pad_to_pow2: bool,
+    pad_fill: u8,
+    report_targets: bool,
+    base: u32,
+    max_bytes: Option<usize>,
+    entry: Option<String>,
+    m_extension: bool,
+    rv32i_only: bool,
+    jump_style: JumpStyle,
+    immediate_format: ImmediateFormat,
+    instruction_endian: Endian,
+    data_endian: Endian,
+    transform: RefCell<Option<TransformHook>>,
+}
+
+/// A hook given to `Assembler::transform`.
+type TransformHook = Box<dyn FnMut(&Instruction) -> Vec<Instruction>>;
+
+impl Assembler {
+    /// Construct an assembler with no input.
+    pub fn new() -> Self {
+        Assembler {
+            chunks: Vec::new(),
+            strict: false,
+            optimize: false,
+            warn_zero_destination: false,
+            warn_unused_labels: false,
+            warnings_as_errors: false,
+            relax: false,
+            pad_to: 0,
+            pad_to_pow2: false,
+            pad_fill: 0,
+            report_targets: false,
+            base: 0,
+            max_bytes: None,
+            entry: None,
+            m_extension: false,
+            rv32i_only: false,
+            jump_style: JumpStyle::Jal,
+            immediate_format: ImmediateFormat::default(),
+            instruction_endian: Endian::Little,
+            data_endian: Endian::Little,
+            transform: RefCell::new(None),
+        }
+    }
+
+    /// In strict mode, unknown directives are an error. Otherwise, they are
+    /// logged as a warning and skipped. Strict mode also rejects
+    /// instructions at an address that is not word-aligned, as can happen
+    /// after `.org`; otherwise they are placed in the next whole word of
+    /// the image. Strict mode also warns about a branch or `jal` whose
+    /// target lies inside data laid out by `.word`, `.half`, `.byte` or
+    /// inserted bytes, and about `.insn` encodings that are reserved.
+    /// Defaults to `false`.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Run a conservative peephole optimizer over the source before laying
+    /// it out, removing instructions that provably have no effect (see
+    /// below). Labels move along with the remaining instructions. Defaults
+    /// to `false`, since the output no longer matches the source
+    /// instruction-for-instruction.
+    ///
+    /// - Writes to `zero` and moves of a register to itself are removed,
+    ///   except for the canonical `addi zero, zero, 0` nop.
+    /// - A write that the next instruction overwrites without reading is
+    ///   removed.
+    /// - `li a, imm` followed by `mv b, a` becomes `li b, imm` when the
+    ///   instruction after overwrites `a` without reading it.
+    /// - `seqz r, a` or `snez r, a` followed by `beqz r` or `bnez r` to a
+    ///   label becomes a single `beqz a` or `bnez a`, when no other line in
+    ///   the source uses `r` and nothing jumps to the branch.
+    pub fn optimize(&mut self, optimize: bool) -> &mut Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Pass the instruction on each line through `hook` before laying it
+    /// out, assembling whatever instructions it returns in its place, so
+    /// instructions can be inserted, replaced, or removed with labels still
+    /// landing on the right words. Instructions with labels or constants
+    /// for operands are given as an `Instruction::Pseudo` with the operands
+    /// as written. Literal branch offsets are not adjusted for instructions
+    /// the hook inserts. Directives are not passed to the hook.
+    pub fn transform<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: FnMut(&Instruction) -> Vec<Instruction> + 'static,
+    {
+        self.transform = RefCell::new(Some(Box::new(hook)));
+        self
+    }
+
+    /// Warn when an instruction writes to `zero`, which discards the result
+    /// and is usually a forgotten destination register. The `addi zero,
+    /// zero, 0` nop, jumps (`j`/`ret` discard the link), and loads (which
+    /// may be done only to touch memory) are not reported. Defaults to
+    /// `false`.
+    pub fn warn_zero_destination(&mut self, warn: bool) -> &mut Self {
+        self.warn_zero_destination = warn;
+        self
+    }
+
+    /// Warn about labels that are never referred to and are not declared
+    /// global. Defaults to `false`.
+    pub fn warn_unused_labels(&mut self, warn: bool) -> &mut Self {
+        self.warn_unused_labels = warn;
+        self
+    }
+
+    /// Treat warnings as errors: `assemble` fails with
+    /// `WarningsDeniedError`, holding every warning, if there are any, and
+    /// `validate` reports them as errors. Defaults to `false`.
+    pub fn warnings_as_errors(&mut self, deny: bool) -> &mut Self {
+        self.warnings_as_errors = deny;
+        self
+    }
+
+    /// Relax conditional branches whose target is out of range (4 KiB
+    /// either way) into the inverse branch over a `jal`, which reaches
+    /// 1 MiB either way, e.g. `beqz a0, far` becomes `bnez a0, 8` and
+    /// `jal zero, far`. Only branches to labels or expressions are relaxed.
+    /// A `call` whose target is in range of a `jal` (1 MiB either way) is
+    /// shrunk to `jal ra, target`. Since this moves everything after a
+    /// relaxed line, the layout is redone until no more branches or calls
+    /// need relaxing. As with GNU as, branches and calls after
+    /// `.option norelax` are left as written, up to `.option relax` or the
+    /// `.option pop` that restores the state saved by an `.option push`.
+    /// Defaults to `false`.
+    pub fn relax(&mut self, relax: bool) -> &mut Self {
+        self.relax = relax;
+        self
+    }
+
+    /// Report the target address of every branch and `jal`, and the label
+    /// there if there is one, as an info diagnostic (and log message) like
+    /// "`bne` resolves to 0x40 (label `loop`)". Defaults to `false`.
+    pub fn report_targets(&mut self, report: bool) -> &mut Self {
+        self.report_targets = report;
+        self
+    }
+
+    /// Pad the image with `pad_fill` bytes after the last word up to `size`
+    /// bytes, rounded up to a whole word, e.g. to fill a ROM. Labels are
+    /// unaffected. A program larger than `size` is an
+    /// `ImageTooLargeError`. Defaults to `0`, for no padding.
+    pub fn pad_to(&mut self, size: usize) -> &mut Self {
+        self.pad_to = size;
+        self
+    }
+
+    /// Designate the label `symbol`, such as `_start`, as the entry point.
+    /// Its address is recorded in `Program::entry` and written as the ELF
+    /// entry address. If no label is named `symbol`, assembling fails with
+    /// `UndefinedEntryError`. Defaults to no entry point, which the ELF
+    /// output gives as the base address.
+    pub fn entry(&mut self, symbol: &str) -> &mut Self {
+        self.entry = Some(symbol.to_owned());
+        self
+    }
+
+    /// Limit the image to `max` bytes, including padding. The limit is
+    /// checked before each line is emitted, so an oversized program, like
+    /// a runaway generated one, stops with an `ImageTooLargeError` at the
+    /// first line that does not fit instead of being built in full.
+    /// Defaults to no limit.
+    pub fn max_bytes(&mut self, max: usize) -> &mut Self {
+        self.max_bytes = Some(max);
+        self
+    }
+
+    /// Pad the image with `pad_fill` bytes up to the next power of two
+    /// bytes, after any `pad_to` padding. Defaults to `false`.
+    pub fn pad_to_pow2(&mut self, pad: bool) -> &mut Self {
+        self.pad_to_pow2 = pad;
+        self
+    }
+
+    /// The byte to pad the image with. Defaults to `0x00`.
+    pub fn pad_fill(&mut self, fill: u8) -> &mut Self {
+        self.pad_fill = fill;
+        self
+    }
+
+    /// The address the program is loaded at, which is the pc of the first
+    /// instruction. Labels, and so `%hi`/`%lo` and data directives, get
+    /// absolute addresses from it, while pc-relative offsets are unchanged.
+    /// Defaults to `0`.
+    pub fn base(&mut self, base: u32) -> &mut Self {
+        self.base = base;
+        self
+    }
+
+    /// How the program's listing prints unconditional jumps: as `jal zero`,
+    /// as encoded, or as the `j` pseudo-instruction. The words are the same
+    /// either way. Defaults to `JumpStyle::Jal`.
+    pub fn jump_style(&mut self, style: JumpStyle) -> &mut Self {
+        self.jump_style = style;
+        self
+    }
+
+    /// How the program's listing prints immediates, by kind. Defaults to
+    /// signed decimal, except for hex `lui` and `auipc` upper immediates,
+    /// as objdump prints them.
+    pub fn immediate_format(&mut self, format: ImmediateFormat) -> &mut Self {
+        self.immediate_format = format;
+        self
+    }
+
+    /// The byte order of instruction words in `Program::to_image`, for
+    /// cores that fetch big-endian. Defaults to `Endian::Little`.
+    pub fn instruction_endian(&mut self, endian: Endian) -> &mut Self {
+        self.instruction_endian = endian;
+        self
+    }
+
+    /// The byte order of words from `.word`, `.half`, `.byte`, and
+    /// `insert_bytes` in `Program::to_image`, independent of
+    /// `instruction_endian`. Defaults to `Endian::Little`.
+    pub fn data_endian(&mut self, endian: Endian) -> &mut Self {
+        self.data_endian = endian;
+        self
+    }
+
+    /// Enable the M extension's multiply and divide instructions, which is
+    /// also reflected in `isa_string`. Without it, they are an
+    /// `ExtensionDisabledError`. Defaults to `false`.
+    pub fn m_extension(&mut self, enabled: bool) -> &mut Self {
+        self.m_extension = enabled;
+        self
+    }
+
+    /// Reject every mnemonic outside base RV32I with an
+    /// `ExtensionDisabledError`, even if its extension is enabled, so that
+    /// a program is guaranteed to run on a base RV32I core. Defaults to
+    /// `false`.
+    pub fn rv32i_only(&mut self, rv32i_only: bool) -> &mut Self {
+        self.rv32i_only = rv32i_only;
+        self
+    }
+
+    /// The ISA string for the enabled extensions, like `rv32i` or `rv32im`.
+    pub fn isa_string(&self) -> String {
+        let mut isa = "rv32i".to_owned();
+        if self.m_extension {
+            isa.push('m');
+        }
+        isa
+    }
+
+    /// Append newline-separated instructions at the current cursor.
+    pub fn insert_source(&mut self, program: &str) {
+        self.chunks.push(Chunk::Source(program.to_owned()));
+    }
+
+    /// Append raw bytes at the current cursor, optionally defining `label`
+    /// at their first byte. The bytes are packed little-endian into words,
+    /// and the last word is zero-padded so that following instructions
+    /// stay aligned.
+    pub fn insert_bytes(&mut self, label: Option<&str>, data: &[u8]) {
+        self.chunks.push(Chunk::Bytes {
+            label: label.map(|l| l.to_ascii_lowercase()),
+            data: data.to_vec(),
+        });
+    }
+
+    /// Run the first pass over everything inserted so far, finding the
+    /// address of every label and the total size in bytes.
+    pub fn parse_labels(&self) -> Result<(HashMap<String, u32>, u32), AssemblerError> {
+        let mut errors = Vec::new();
+        let layout = self.layout(&self.prepare(), &mut errors);
+        match errors.into_iter().next() {
+            Some((_, err)) => Err(err),
+            None => Ok((layout.labels, layout.size)),
+        }
+    }
+
+    /// The size in bytes of the image, including any padding, computed
+    /// without assembling it.
+    pub fn size(&self) -> Result<usize, AssemblerError> {
+        self.image_size(self.parse_labels()?.1 as usize)
+    }
+
+    /// The size of the image holding a program of `size` bytes, after
+    /// padding.
+    fn image_size(&self, size: usize) -> Result<usize, AssemblerError> {
+        if size > self.pad_to && self.pad_to > 0 {
+            return Err(AssemblerError::ImageTooLargeError {
+                needed: size,
+                capacity: self.pad_to,
+            });
+        }
+        let mut padded = size.max(self.pad_to);
+        if self.pad_to_pow2 && padded > 0 {
+            padded = padded.next_power_of_two();
+        }
+        Ok(padded.div_ceil(4) * 4)
+    }
+
+    /// Check that an image of `needed` bytes is within `max_bytes`.
+    fn check_max_bytes(&self, needed: usize) -> Result<(), AssemblerError> {
+        match self.max_bytes {
+            Some(capacity) if needed > capacity => {
+                Err(AssemblerError::ImageTooLargeError { needed, capacity })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Assemble everything inserted so far.
+    pub fn assemble(&self) -> Result<Program, AssemblerError> {
+        self.assemble_located().map_err(|err| match err {
+            AssemblerError::LocatedError { error, .. } => *error,
+            err => err,
+        })
+    }
+
+    /// Like `assemble`, but an error about a line is wrapped in a
+    /// `LocatedError` with where it is in the source, which `format_error`
+    /// shows.
+    pub fn assemble_located(&self) -> Result<Program, AssemblerError> {
+        let lines = self.prepare();
+        let mut errors = Vec::new();
+        let layout = self.layout(&lines, &mut errors);
+        if let Some((line, err)) = errors.into_iter().next() {
+            return Err(self.locate_error(&lines, line, err));
+        }
+        let mut errors = Vec::new();
+        let prog = self.emit(&lines, layout, &mut errors);
+        match errors.into_iter().next() {
+            Some((line, err)) => Err(self.locate_error(&lines, line, err)),
+            None if self.warnings_as_errors
+                && prog
+                    .diagnostics
+                    .iter()
+                    .any(|d| d.severity == Severity::Warning) =>
+            {
+                Err(AssemblerError::WarningsDeniedError(
+                    prog.diagnostics
+                        .into_iter()
+                        .filter(|d| d.severity == Severity::Warning)
+                        .collect(),
+                ))
+            }
+            None => Ok(prog),
+        }
+    }
+
+    /// Wrap an error on the zero-based `line` in a `LocatedError`, at the
+    /// token it names on that line, or else at the start of its code.
+    fn locate_error(
+        &self,
+        lines: &[Vec<SourceLine>],
+        line: usize,
+        err: AssemblerError,
+    ) -> AssemblerError {
+        let source: Vec<&str> = self
+            .chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                Chunk::Source(program) => Some(&program[..]),
+                Chunk::Bytes { .. } => None,
+            })
+            .collect();
+        let source = source.join("\n");
+        let code = lines
+            .iter()
+            .flatten()
+            .nth(line)
+            .map_or(source.len()..source.len(), |l| l.span.clone());
+        let start = source[..code.start].rfind('\n').map_or(0, |i| i + 1);
+        let end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+
+        let chars: Vec<(usize, char)> = source[start..end].char_indices().collect();
+        let text: Vec<char> = chars.iter().map(|(_, c)| *c).collect();
+        let token = error_token(&err).and_then(|token| {
+            let column = find_token(&text, token)?;
+            let begin = start + chars[column].0;
+            Some((column, begin..begin + token.len()))
+        });
+        let (column, span) = token.unwrap_or_else(|| {
+            let column = source[start..code.start].chars().count();
+            (column, code.start..code.start)
+        });
+        AssemblerError::LocatedError {
+            line,
+            column,
+            span,
+            error: Box::new(err),
+        }
+    }
+
+    /// Parse everything inserted so far into instructions, each with the
+    /// byte range of source it came from, without encoding them. Labels are
+    /// resolved, so `encode_program` turns the instructions back into the
+    /// words of the program, except for data, since directives and
+    /// constant definitions give no instructions. Lines with errors are
+    /// left out, and every error is reported, in source order.
+    pub fn parse(&self) -> (Vec<(Instruction, Range<usize>)>, Vec<Diagnostic>) {
+        let lines = self.prepare();
+        let mut errors = Vec::new();
+        let layout = self.layout(&lines, &mut errors);
+        let names = layout.names();
+
+        let mut instructions = Vec::new();
+        for (line_num, line) in lines.iter().flatten().enumerate() {
+            let (_, tokens) = split_labels(&line.tokens);
+            if tokens.is_empty()
+                || tokens[0].starts_with('.')
+                || constant_definition(tokens).is_some()
+                || errors.iter().any(|(l, _)| *l == line_num)
+            {
+                continue;
+            }
+            let mut pc = layout.line_pcs[line_num];
+            for tokens in line.instructions() {
+                match resolve_instruction(&line.text, tokens, &layout, &names, pc) {
+                    Ok(parsed) => {
+                        instructions.extend(parsed.into_iter().map(|i| (i, line.span.clone())))
+                    }
+                    Err(err) => {
+                        errors.push((line_num, err));
+                        break;
+                    }
+                }
+                pc += self.line_size(tokens, &names, pc).unwrap_or(0);
+            }
+        }
+
+        let mut diagnostics: Vec<Diagnostic> = errors
+            .into_iter()
+            .map(|(line, err)| Diagnostic::error(line, err.to_string()))
+            .collect();
+        diagnostics.sort_by_key(|d| d.line);
+        (instructions, diagnostics)
+    }
+
+    /// Check everything inserted so far without keeping the output. Both
+    /// passes run to the end, so every line with an error is reported
+    /// (once), along with any warnings, in source order.
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let lines = self.prepare();
+        let mut errors = Vec::new();
+        let layout = self.layout(&lines, &mut errors);
+        let mut emit_errors = Vec::new();
+        let prog = self.emit(&lines, layout, &mut emit_errors);
+        for (line, err) in emit_errors {
+            if errors.iter().all(|(l, _)| *l != line) {
+                errors.push((line, err));
+            }
+        }
+
+        let mut diagnostics: Vec<Diagnostic> = errors
+            .into_iter()
+            .map(|(line, err)| Diagnostic::error(line, err.to_string()))
+            .chain(prog.diagnostics.into_iter().map(|mut d| {
+                if self.warnings_as_errors && d.severity == Severity::Warning {
+                    d.severity = Severity::Error;
+                }
+                d
+            }))
+            .collect();
+        diagnostics.sort_by_key(|d| d.line);
+        diagnostics
+    }
+
+    /// The first pass: find the address of every label and the size of
+    /// every line. Lines with errors are recorded in `errors` and take no
+    /// space. When relaxing, the layout is redone until every branch that
+    /// must be relaxed has been.
+    fn layout(
+        &self,
+        lines: &[Vec<SourceLine>],
+        errors: &mut Vec<(usize, AssemblerError)>,
+    ) -> Layout {
+        let mut relaxed = HashSet::new();
+        loop {
+            let mut pass_errors = Vec::new();
+            let layout = self.layout_pass(lines, relaxed, &mut pass_errors);
+            if self.relax {
+                if let Some(more) = relax_branches(lines, &layout) {
+                    relaxed = more;
+                    continue;
+                }
+            }
+            errors.extend(pass_errors);
+            return layout;
+        }
+    }
+
+    /// One pass of `layout`, with the branches on the `relaxed` lines taking
+    /// an extra `jal`. When relaxing, calls are shrunk to a `jal`, except
+    /// those on the `relaxed` lines.
+    fn layout_pass(
+        &self,
+        lines: &[Vec<SourceLine>],
+        relaxed: HashSet<usize>,
+        errors: &mut Vec<(usize, AssemblerError)>,
+    ) -> Layout {
+        let calls = if self.relax {
+            relaxable_calls(lines)
+        } else {
+            HashSet::new()
+        };
+        let (grown, relaxed): (HashSet<usize>, _) = relaxed
+            .into_iter()
+            .partition(|line_num| calls.contains(line_num));
+        let mut layout = Layout {
+            relaxed,
+            shrunk: calls.difference(&grown).copied().collect(),
+            ..Layout::default()
+        };
+        let mut pc = self.base;
+        let mut line_num = 0;
+        // Labels and constants, which share a namespace.
+        let mut names = HashMap::new();
+        // Constants that refer to names not defined yet.
+        let mut pending = Vec::new();
+
+        for (chunk, lines) in self.chunks.iter().zip(lines) {
+            match chunk {
+                Chunk::Source(_) => {
+                    for line in lines {
+                        let (line_labels, tokens) = split_labels(&line.tokens);
+                        names.insert(".".to_owned(), pc);
+                        for label in line_labels {
+                            match define_name(&mut names, &pending, label.clone(), pc) {
+                                Ok(()) => {
+                                    layout.labels.insert(label, pc);
+                                }
+                                Err(err) => errors.push((line_num, err)),
+                            }
+                        }
+                        let size = match constant_definition(tokens) {
+                            Some(Ok((name, expr))) => match match_register(&expr) {
+                                // Aliases take their name from the namespace,
+                                // but are not constants.
+                                Ok(reg) if reg < 32 => {
+                                    define_name(&mut names, &pending, name.clone(), reg as u32).map(
+                                        |()| {
+                                            layout.register_aliases.insert(name, reg);
+                                            0
+                                        },
+                                    )
+                                }
+                                Ok(_) => Err(AssemblerError::NoSuchRegisterError(expr)),
+                                Err(_) => define_constant(
+                                    &mut names,
+                                    &mut pending,
+                                    &mut layout.constants,
+                                    (line_num, name, substitute_location(&expr, pc)),
+                                )
+                                .map(|()| 0),
+                            },
+                            Some(Err(err)) => Err(err),
+                            None if layout.shrunk.contains(&line_num) => Ok(4),
+                            None if layout.relaxed.contains(&line_num) => {
+                                self.line_size(tokens, &names, pc).map(|size| size + 4)
+                            }
+                            None => line
+                                .instructions()
+                                .iter()
+                                .map(|tokens| self.line_size(tokens, &names, pc))
+                                .sum(),
+                        };
+                        let size = match size {
+                            Ok(size) => {
+                                // `.stack` and `.heap` define a symbol at the
+                                // edge of the space they reserve.
+                                if let Some((symbol, at_end)) =
+                                    tokens.first().and_then(|t| region_symbol(t))
+                                {
+                                    let addr = if at_end {
+                                        pc.checked_add(size)
+                                            .ok_or(AssemblerError::AddressOverflowError { pc })
+                                    } else {
+                                        Ok(pc)
+                                    };
+                                    match addr.and_then(|addr| {
+                                        define_name(&mut names, &pending, symbol.to_owned(), addr)
+                                            .map(|()| addr)
+                                    }) {
+                                        Ok(addr) => {
+                                            layout.labels.insert(symbol.to_owned(), addr);
+                                        }
+                                        Err(err) => errors.push((line_num, err)),
+                                    }
+                                }
+                                size
+                            }
+                            Err(err) => {
+                                errors.push((line_num, err));
+                                0
+                            }
+                        };
+                        let size = match pc.checked_add(size) {
+                            Some(_) => size,
+                            None => {
+                                errors
+                                    .push((line_num, AssemblerError::AddressOverflowError { pc }));
+                                layout.overflowed.insert(line_num);
+                                0
+                            }
+                        };
+                        if size > 0
+                            && matches!(
+                                tokens.first().map(|t| &t[..]),
+                                Some(".word" | ".half" | ".byte")
+                            )
+                        {
+                            layout.data.push(pc..pc + size);
+                        }
+                        layout.line_sizes.push(size);
+                        layout.line_pcs.push(pc);
+                        pc += size;
+                        line_num += 1;
+                    }
+                }
+                Chunk::Bytes { label, data } => {
+                    if let Some(l) = label {
+                        match define_name(&mut names, &pending, l.clone(), pc) {
+                            Ok(()) => {
+                                layout.labels.insert(l.clone(), pc);
+                            }
+                            Err(err) => errors.push((line_num, err)),
+                        }
+                    }
+                    match pc.checked_add(padded_len(data)) {
+                        Some(end) => {
+                            layout.data.push(pc..pc + data.len() as u32);
+                            pc = end;
+                        }
+                        None => {
+                            errors.push((line_num, AssemblerError::AddressOverflowError { pc }))
+                        }
+                    }
+                }
+            }
+        }
+
+        resolve_constants(&mut names, &mut layout.constants, pending, errors);
+        layout.size = (pc - self.base).div_ceil(4) * 4;
+        layout
+    }
+
+    /// The second pass: assemble every line with the labels from `layout`.
+    /// A line with an error is recorded in `errors` and filled with zeros,
+    /// so the following lines stay where the first pass put them.
+    fn emit(
+        &self,
+        lines: &[Vec<SourceLine>],
+        layout: Layout,
+        errors: &mut Vec<(usize, AssemblerError)>,
+    ) -> Program {
+        let mut names = layout.names();
+        let mut prog = Program {
+            base: self.base,
+            symbols: layout.labels.into_iter().collect(),
+            constants: layout.constants,
+            register_aliases: layout.register_aliases,
+            data: layout.data.clone(),
+            jump_style: self.jump_style,
+            immediate_format: self.immediate_format,
+            instruction_endian: self.instruction_endian,
+            data_endian: self.data_endian,
+            ..Program::default()
+        };
+        let mut pc = self.base;
+        let mut line_num = 0;
+        let mut offset = 0;
+
+        for (chunk, lines) in self.chunks.iter().zip(lines) {
+            match chunk {
+                Chunk::Source(_) => {
+                    for line in lines {
+                        if layout.overflowed.contains(&line_num) {
+                            errors.push((line_num, AssemblerError::AddressOverflowError { pc }));
+                            line_num += 1;
+                            offset = line.next_offset;
+                            continue;
+                        }
+                        let (start, len) = (pc, prog.words.len());
+                        names.insert(".".to_owned(), pc);
+                        let end = start.wrapping_sub(self.base) + layout.line_sizes[line_num];
+                        if let Err(err) = self.check_max_bytes(end as usize) {
+                            errors.push((line_num, err));
+                            return prog;
+                        }
+                        let relaxed =
+                            layout.relaxed.contains(&line_num) || layout.shrunk.contains(&line_num);
+                        if let Err(err) =
+                            self.assemble_line(line, line_num, &names, relaxed, &mut pc, &mut prog)
+                        {
+                            errors.push((line_num, err));
+                            prog.words.truncate(len);
+                            prog.line_map.truncate(len);
+                            prog.spans.truncate(len);
+                            pc = start + layout.line_sizes[line_num];
+                            while (prog.words.len() as u32) * 4 < pc - self.base {
+                                prog.words.push(0);
+                                prog.line_map.push(line_num);
+                                prog.spans.push(line.span.clone());
+                            }
+                        } else if self.strict && !layout.data.contains(&(start..pc)) {
+                            // Words of data are not checked, even if they
+                            // happen to decode as a branch.
+                            for index in len..prog.words.len() {
+                                warn_data_target(index, line_num, &layout.data, &mut prog);
+                            }
+                        }
+                        line_num += 1;
+                        offset = line.next_offset;
+                    }
+                }
+                Chunk::Bytes { data, .. } => {
+                    if pc.checked_add(padded_len(data)).is_none() {
+                        errors.push((line_num, AssemblerError::AddressOverflowError { pc }));
+                        continue;
+                    }
+                    if let Err(err) = self.check_max_bytes(4 * prog.words.len() + data.len()) {
+                        errors.push((line_num, err));
+                        return prog;
+                    }
+                    for word in data.chunks(4) {
+                        let mut bytes = [0; 4];
+                        bytes[..word.len()].copy_from_slice(word);
+                        prog.words.push(u32::from_le_bytes(bytes));
+                        prog.line_map.push(line_num);
+                        prog.spans.push(offset..offset);
+                        pc += 4;
+                    }
+                }
+            }
+        }
+
+        match self
+            .image_size(4 * prog.words.len())
+            .and_then(|size| self.check_max_bytes(size).map(|_| size))
+        {
+            Ok(size) => {
+                let fill = u32::from_le_bytes([self.pad_fill; 4]);
+                while 4 * prog.words.len() < size {
+                    prog.words.push(fill);
+                    prog.line_map.push(line_num);
+                    prog.spans.push(offset..offset);
+                }
+            }
+            Err(err) => errors.push((line_num, err)),
+        }
+
+        if let Some(symbol) = &self.entry {
+            match prog.symbols.get(symbol) {
+                Some(addr) => prog.entry = Some(*addr),
+                None => errors.push((
+                    line_num,
+                    AssemblerError::UndefinedEntryError(symbol.clone()),
+                )),
+            }
+        }
+
+        if self.warn_unused_labels {
+            warn_unused_labels(lines, &mut prog);
+        }
+        prog
+    }
+
+    /// Split every source chunk into lines and tokenize them, once for both
+    /// passes. Byte chunks get no lines.
+    fn prepare(&self) -> Vec<Vec<SourceLine>> {
+        let mut offset = 0;
+        self.chunks
+            .iter()
+            .map(|chunk| match chunk {
+                Chunk::Source(program) => {
+                    let lines = self.source_lines(program);
+                    lines
+                        .into_iter()
+                        .zip(program.split('\n'))
+                        .map(|(text, source)| {
+                            let span = code_span(source);
+                            let tokens = keep_empty_operands(&text, tokenize!(text));
+                            let line = SourceLine {
+                                expansion: self.transform_line(&text, &tokens),
+                                tokens,
+                                text,
+                                span: offset + span.start..offset + span.end,
+                                next_offset: offset + source.len() + 1,
+                            };
+                            offset = line.next_offset;
+                            line
+                        })
+                        .collect()
+                }
+                Chunk::Bytes { .. } => Vec::new(),
+            })
+            .collect()
+    }
+
+    /// Pass the instruction on a line through the `transform` hook, if any,
+    /// returning the tokens of the instructions it gave if they differ.
+    fn transform_line(&self, text: &str, tokens: &[String]) -> Option<Vec<Vec<String>>> {
+        let mut hook = self.transform.borrow_mut();
+        let hook = hook.as_mut()?;
+        let (_, tokens) = split_labels(tokens);
+        if tokens.is_empty() || tokens[0].starts_with('.') || constant_definition(tokens).is_some()
+        {
+            return None;
+        }
+        let instruction = match parse_instruction(text) {
+            Ok(Some(instruction)) => instruction,
+            _ => Instruction::Pseudo {
+                name: tokens[0].clone(),
+                operands: tokens[1..].to_vec(),
+            },
+        };
+        let replaced = hook(&instruction);
+        if replaced == [instruction] {
+            return None;
+        }
+        Some(replaced.iter().map(|i| tokenize!(i.to_string())).collect())
+    }
+
+    /// The lines of a source chunk, after optimizing if enabled. There is
+    /// always one line per line of `program`.
+    fn source_lines(&self, program: &str) -> Vec<String> {
+        if self.optimize {
+            optimize::optimize(program)
+        } else {
+            program.split('\n').map(|l| l.to_owned()).collect()
+        }
+    }
+
+    /// The number of bytes a line will assemble to, given its tokens after
+    /// any labels. Instructions are sized by the same pseudo-instruction
+    /// expansion the second pass emits, so e.g. `li` takes one or two words
+    /// depending on its immediate exactly as it will be assembled.
+    fn line_size(
+        &self,
+        tokens: &[String],
+        labels: &HashMap<String, u32>,
+        pc: u32,
+    ) -> Result<u32, AssemblerError> {
+        if tokens.is_empty() {
+            Ok(0)
+        } else if tokens[0].starts_with('.') {
+            self.directive_size(tokens, labels, pc)
+        } else {
+            Ok(4 * expand_pseudo(tokens)?.map_or(1, |e| e.len()) as u32)
+        }
+    }
+
+    /// The number of bytes a directive will emit at `pc`, given its tokens
+    /// starting with the directive name and the labels defined before it.
+    fn directive_size(
+        &self,
+        tokens: &[String],
+        labels: &HashMap<String, u32>,
+        pc: u32,
+    ) -> Result<u32, AssemblerError> {
+        match &tokens[0][..] {
+            ".globl" | ".global" | ".type" | ".size" | ".option" | ".text" | ".data" | ".file"
+            | ".line" => Ok(0),
+            ".org" | ".align" | ".p2align" => Ok(self.fill_target(tokens, labels, pc)? - pc),
+            ".insn" => Ok(4),
+            ".stack" | ".heap" => {
+                let size = region_size(tokens, labels)?;
+                match pc.checked_add(size) {
+                    Some(_) => Ok(size),
+                    None => Err(AssemblerError::AddressOverflowError { pc }),
+                }
+            }
+            ".word" | ".half" | ".byte" => data_len(tokens, labels)?
+                .checked_next_multiple_of(4)
+                .ok_or(AssemblerError::ImmediateTooLargeError),
+            name if self.strict && !is_debug_directive(name) => {
+                Err(AssemblerError::UnknownDirectiveError(name.to_owned()))
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// The address a `.org`, `.align`, or `.p2align` directive at `pc`
+    /// fills up to. The alignments take an optional fill byte and then an
+    /// optional maximum: if aligning would skip more than that many bytes,
+    /// nothing is skipped. An empty fill, like `.p2align 4,,8`, is the
+    /// default fill.
+    fn fill_target(
+        &self,
+        tokens: &[String],
+        labels: &HashMap<String, u32>,
+        pc: u32,
+    ) -> Result<u32, AssemblerError> {
+        let operands = if tokens[0] == ".org" { 1 } else { 3 };
+        match tokens.len() {
+            0 | 1 => return Err(AssemblerError::TooFewTokensError),
+            n if n > operands + 1 => return Err(AssemblerError::TooManyTokensError),
+            _ => (),
+        }
+        let value = eval_expr(&tokens[1], labels)?;
+        let target = if tokens[0] == ".org" {
+            self.base.checked_add(value)
+        } else if value < 32 {
+            let mask = (1u32 << value) - 1;
+            pc.checked_add(mask).map(|end| end & !mask)
+        } else {
+            None
+        };
+        match target {
+            Some(target) if target >= pc => match tokens.get(3).filter(|t| !t.is_empty()) {
+                Some(max) if target - pc > eval_expr(max, labels)? => Ok(pc),
+                _ => Ok(target),
+            },
+            Some(_) => Err(AssemblerError::InvalidImmediateError(tokens[1].clone())),
+            None => Err(AssemblerError::ImmediateTooLargeError),
+        }
+    }
+
+    /// Assemble one line of source into `prog`, where `names` holds every
+    /// label and constant. If `relaxed`, the line is a branch to relax or a
+    /// call to shrink.
+    fn assemble_line(
+        &self,
+        line: &SourceLine,
+        line_num: usize,
+        names: &HashMap<String, u32>,
+        relaxed: bool,
+        pc: &mut u32,
+        prog: &mut Program,
+    ) -> Result<(), AssemblerError> {
+        let (_, tokens) = split_labels(&line.tokens);
+        let span = line.span.clone();
+
+        // Constants were defined by the first pass.
+        if constant_definition(tokens).is_some() {
+            return Ok(());
+        }
+
+        if !tokens.is_empty() && tokens[0].starts_with('.') {
+            return self.assemble_directive(tokens, line_num, names, span, pc, prog);
+        }
+
+        if tokens.is_empty() {
+            return Ok(());
+        }
+
+        if self.strict && *pc & 3 != 0 {
+            return Err(AssemblerError::MisalignedInstructionError { pc: *pc });
+        }
+
+        let mut instructions = Vec::new();
+        for tokens in line.instructions() {
+            match expand_pseudo(tokens)? {
+                _ if relaxed => instructions.extend(relax_line(tokens)),
+                Some(expansion) => instructions.extend(expansion),
+                None => instructions.push(tokens.to_vec()),
+            }
+            if let Some(message) = li_sign_warning(tokens) {
+                warn!("Line {}: {}", line_num, message);
+                prog.diagnostics
+                    .push(Diagnostic::warning(line_num, message));
+            }
+        }
+        for tokens in &instructions {
+            if let Some(extension) = extension_of(&tokens[0]) {
+                if self.rv32i_only || !(extension == "M" && self.m_extension) {
+                    return Err(AssemblerError::ExtensionDisabledError {
+                        mnemonic: tokens[0].clone(),
+                        extension,
+                    });
+                }
+            }
+            let tokens = substitute_names(tokens, &prog.constants, &prog.register_aliases);
+            let ir = match assemble_tokens(&line.text, &tokens, names, *pc) {
+                Err(AssemblerError::ImmediateTooLargeError) => {
+                    if let Some(hint) = immediate_boundary_hint(&tokens, names, *pc) {
+                        warn!("Line {}: {}", line_num, hint);
+                        prog.diagnostics.push(Diagnostic::warning(line_num, hint));
+                    }
+                    return Err(AssemblerError::ImmediateTooLargeError);
+                }
+                ir => ir?,
+            };
+            for operand in tokens.iter().skip(1) {
+                if match_register(operand).is_err() && prog.symbols.contains_key(operand) {
+                    prog.relocations.push(Relocation {
+                        address: *pc,
+                        symbol: operand.clone(),
+                        kind: match_relocation_kind(ir),
+                    });
+                }
+            }
+            for operand in tokens.iter().filter(|t| t.starts_with("%lo")) {
+                let lo = parse_imm(operand, names, *pc)? as i32;
+                if lo < 0 {
+                    warn!("Line {}: `{}` is negative", line_num, operand);
+                    prog.diagnostics.push(Diagnostic::warning(
+                        line_num,
+                        format!(
+                            "`{}` is {}, since bit 11 is set and sign-extends; the matching `%hi` carries one into the upper bits",
+                            operand, lo
+                        ),
+                    ));
+                }
+            }
+            if self.warn_zero_destination && discards_result(ir) {
+                let text = format!("{} {}", tokens[0], tokens[1..].join(", "));
+                warn!("Line {}: `{}` writes to zero", line_num, text);
+                prog.diagnostics.push(Diagnostic::warning(
+                    line_num,
+                    format!("`{}` writes to zero, discarding its result", text),
+                ));
+            }
+            if self.report_targets {
+                report_target(ir, line_num, *pc, prog);
+            }
+            prog.words.push(ir);
+            prog.line_map.push(line_num);
+            prog.spans.push(span.clone());
+            *pc += 4;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a directive, given its tokens starting with the directive name.
+    fn assemble_directive(
+        &self,
+        tokens: &[String],
+        line_num: usize,
+        names: &HashMap<String, u32>,
+        span: Range<usize>,
+        pc: &mut u32,
+        prog: &mut Program,
+    ) -> Result<(), AssemblerError> {
+        match &tokens[0][..] {
+            ".word" | ".half" | ".byte" => {
+                let width = match_data_width(&tokens[0]) as usize;
+                let mut data = Vec::with_capacity(data_len(tokens, names)? as usize);
+                for (value, count) in parse_data_items(&tokens[1..])? {
+                    let value = eval_expr(value, names)?;
+                    if !fits_width(value, width) {
+                        return Err(AssemblerError::ImmediateTooLargeError);
+                    }
+                    for _ in 0..repeat_count(count, names)? {
+                        data.extend_from_slice(&value.to_le_bytes()[..width]);
+                    }
+                }
+                for word in data.chunks(4) {
+                    let mut bytes = [0; 4];
+                    bytes[..word.len()].copy_from_slice(word);
+                    prog.words.push(u32::from_le_bytes(bytes));
+                    prog.line_map.push(line_num);
+                    prog.spans.push(span.clone());
+                    *pc += 4;
+                }
+                Ok(())
+            }
+            ".insn" => {
+                if self.strict && *pc & 3 != 0 {
+                    return Err(AssemblerError::MisalignedInstructionError { pc: *pc });
+                }
+                let ir = assemble_insn(tokens, names, *pc)?;
+                if self.strict {
+                    if let Some(message) = reserved_encoding(ir) {
+                        warn!("Line {}: {}", line_num, message);
+                        prog.diagnostics
+                            .push(Diagnostic::warning(line_num, message));
+                    }
+                }
+                prog.words.push(ir);
+                prog.line_map.push(line_num);
+                prog.spans.push(span);
+                *pc += 4;
+                Ok(())
+            }
+            ".stack" | ".heap" => {
+                let size = region_size(tokens, names)?;
+                // Check before reserving, so an overflowing size is not
+                // allocated.
+                let end = pc
+                    .checked_add(size)
+                    .ok_or(AssemblerError::AddressOverflowError { pc: *pc })?;
+                for _ in 0..size / 4 {
+                    prog.words.push(0);
+                    prog.line_map.push(line_num);
+                    prog.spans.push(span.clone());
+                }
+                *pc = end;
+                Ok(())
+            }
+            ".org" | ".align" | ".p2align" => {
+                *pc = self.fill_target(tokens, names, *pc)?;
+                let fill = match tokens.get(2) {
+                    Some(fill) if !fill.is_empty() => eval_expr(fill, names)?,
+                    _ => 0,
+                };
+                if fill > 0xff {
+                    return Err(AssemblerError::ImmediateTooLargeError);
+                }
+                let start = self.base + 4 * prog.words.len() as u32;
+                while (prog.words.len() as u32) * 4 < *pc - self.base {
+                    prog.words.push(u32::from_le_bytes([fill as u8; 4]));
+                    prog.line_map.push(line_num);
+                    prog.spans.push(span.clone());
+                }
+                let end = self.base + 4 * prog.words.len() as u32;
+                if end > start {
+                    prog.gaps.push(start..end);
+                }
+                Ok(())
+            }
+            // Sections are laid out in source order in one flat image, so a
+            // section switch only marks where the next one starts.
+            ".text" | ".data" => Ok(()),
+            ".globl" | ".global" => {
+                prog.globals.extend(tokens[1..].iter().cloned());
+                Ok(())
+            }
+            ".type" => {
+                let (name, kind) = symbol_attribute(tokens)?;
+                let kind = match &kind[..] {
+                    "@function" => SymbolType::Function,
+                    "@object" => SymbolType::Object,
+                    "@notype" => SymbolType::NoType,
+                    _ => {
+                        let message = format!("unknown symbol type `{}` is ignored", kind);
+                        warn!("Line {}: {}", line_num, message);
+                        prog.diagnostics
+                            .push(Diagnostic::warning(line_num, message));
+                        return Ok(());
+                    }
+                };
+                prog.symbol_types.insert(name.clone(), kind);
+                Ok(())
+            }
+            ".size" => {
+                let (name, expr) = symbol_attribute(tokens)?;
+                // `.` is the address of the directive, so `.size f, .-f`
+                // placed after `f` measures it.
+                prog.symbol_sizes
+                    .insert(name.clone(), eval_expr(expr, names)?);
+                Ok(())
+            }
+            ".option" => {
+                for arg in &tokens[1..] {
+                    // The assembler only relaxes when asked through
+                    // `Assembler::relax`, and never emits compressed
+                    // instructions, so only the options asking for those
+                    // have any effect we can't honor.
+                    let message = match &arg[..] {
+                        "push" | "pop" | "norvc" | "norelax" => continue,
+                        "relax" if self.relax => continue,
+                        "rvc" | "relax" => {
+                            format!("`.option {}` is not supported and is ignored", arg)
+                        }
+                        _ => format!("unknown option `{}` is ignored", arg),
+                    };
+                    warn!("Line {}: {}", line_num, message);
+                    prog.diagnostics
+                        .push(Diagnostic::warning(line_num, message));
+                }
+                Ok(())
+            }
+            ".file" => {
+                // `.file 1 "name"` numbers the file for DWARF; the number
+                // is not needed without DWARF output.
+                let name = match tokens.len() {
+                    0 | 1 => return Err(AssemblerError::TooFewTokensError),
+                    2 | 3 => &tokens[tokens.len() - 1],
+                    _ => return Err(AssemblerError::TooManyTokensError),
+                };
+                match parse_string(name) {
+                    Some(name) => prog.files.push(name),
+                    None => return Err(AssemblerError::InvalidImmediateError(name.clone())),
+                }
+                Ok(())
+            }
+            ".line" => {
+                let line = match tokens.len() {
+                    0 | 1 => return Err(AssemblerError::TooFewTokensError),
+                    2 => eval_expr(&tokens[1], names)?,
+                    _ => return Err(AssemblerError::TooManyTokensError),
+                };
+                let file = prog.files.last().cloned();
+                prog.source_lines.push((*pc, SourceLocation { file, line }));
+                Ok(())
+            }
+            name if is_debug_directive(name) => {
+                let message = format!("debug directive `{}` is ignored", name);
+                warn!("Line {}: {}", line_num, message);
+                prog.diagnostics
+                    .push(Diagnostic::warning(line_num, message));
+                Ok(())
+            }
+            name if self.strict => Err(AssemblerError::UnknownDirectiveError(name.to_owned())),
+            name => {
+                let message = format!("unknown directive `{}` is ignored", name);
+                warn!("Line {}: {}", line_num, message);
+                prog.diagnostics
+                    .push(Diagnostic::warning(line_num, message));
+                Ok(())
+            }
+        }
+    }
 }
@@ -0,0 +1,51 @@
+/// How serious a diagnostic is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// A message about a line of source, collected while assembling.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The zero-based source line the message is about.
+    pub line: usize,
+    /// The file the line is in, for programs assembled from several files
+    /// with `assemble_units`.
+    pub file: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Construct an informational message about `line`.
+    pub fn info(line: usize, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Info,
+            line,
+            file: None,
+            message,
+        }
+    }
+
+    /// Construct a warning about `line`.
+    pub fn warning(line: usize, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            line,
+            file: None,
+            message,
+        }
+    }
+
+    /// Construct an error about `line`.
+    pub fn error(line: usize, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            line,
+            file: None,
+            message,
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use lib_rv32_common::{bit_slice, constants::*};
+
+use crate::{
+    assembler::{instruction_format, InstructionFormat},
+    error::DisassembleError,
+    instruction::Instruction,
+    program::Endian,
+};
+
+/// The func7 of the M extension's multiply and divide instructions.
+const FUNC7_MULDIV: u8 = 0b0000001;
+
+/// Decode the I-type immediate, sign-extended.
+fn i_imm(ir: u32) -> i32 {
+    (ir as i32) >> 20
+}
+
+/// Decode the S-type immediate, sign-extended.
+fn s_imm(ir: u32) -> i32 {
+    (((ir as i32) >> 25) << 5) | bit_slice!(ir, 11, 7) as i32
+}
+
+/// Decode the B-type immediate, sign-extended.
+fn b_imm(ir: u32) -> i32 {
+    (((ir as i32) >> 31) << 12)
+        | (bit_slice!(ir, 7) << 11) as i32
+        | (bit_slice!(ir, 30, 25) << 5) as i32
+        | (bit_slice!(ir, 11, 8) << 1) as i32
+}
+
+/// Decode the J-type immediate, sign-extended.
+fn j_imm(ir: u32) -> i32 {
+    (((ir as i32) >> 31) << 20)
+        | (bit_slice!(ir, 19, 12) << 12) as i32
+        | (bit_slice!(ir, 20) << 11) as i32
+        | (bit_slice!(ir, 30, 21) << 1) as i32
+}
+
+/// The raw fields of an instruction word, as returned by `decode_fields`.
+///
+/// Register and function fields are sliced from their fixed positions
+/// whether or not the format uses them, so e.g. `rs2` of an I-type
+/// instruction holds the low bits of its immediate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecodedFields {
+    pub opcode: u8,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub func3: u8,
+    pub func7: u8,
+    /// The sign-extended immediate of the format. U-type immediates are
+    /// the upper 20 bits shifted down, as written in assembly. R-type and
+    /// unknown formats have none and decode to `0`.
+    pub imm: i32,
+    /// The format of the opcode, or `None` if the opcode is unknown.
+    pub format: Option<InstructionFormat>,
+}
+
+/// Decode the fields of an instruction word without naming the
+/// instruction. This never fails, and is meant for emulators and analyzers
+/// that dispatch on the fields themselves.
+///
+/// Parameters:
+///     `ir: u32`: The instruction
+///
+/// Returns:
+///     `DecodedFields`: The fields and immediate of the instruction.
+pub fn decode_fields(ir: u32) -> DecodedFields {
+    let opcode = bit_slice!(ir, 6, 0) as u8;
+    let format = instruction_format(opcode);
+    DecodedFields {
+        opcode,
+        rd: bit_slice!(ir, 11, 7) as u8,
+        rs1: bit_slice!(ir, 19, 15) as u8,
+        rs2: bit_slice!(ir, 24, 20) as u8,
+        func3: bit_slice!(ir, 14, 12) as u8,
+        func7: bit_slice!(ir, 31, 25) as u8,
+        imm: match format {
+            Some(InstructionFormat::Itype) => i_imm(ir),
+            Some(InstructionFormat::Stype) => s_imm(ir),
+            Some(InstructionFormat::Btype) => b_imm(ir),
+            Some(InstructionFormat::Jtype) => j_imm(ir),
+            Some(InstructionFormat::Utype) => (ir as i32) >> 12,
+            Some(InstructionFormat::Rtype) | None => 0,
+        },
+        format,
+    }
+}
+
+/// The address computed by an `auipc` and the instruction that adds its
+/// low 12 bits, such as `addi`, `lw`, `sw`, or `jalr`. The low immediate is
+/// sign-extended, so a target whose bit 11 is set has an upper part one
+/// higher than its own upper bits. The words are not checked to be such
+/// a pair.
+///
+/// Parameters:
+///     `auipc_word: u32`: The `auipc`
+///     `lo_word: u32`: The I- or S-type instruction using its result
+///     `auipc_pc: u32`: The address of the `auipc`
+///
+/// Returns:
+///     `u32`: The target address, wrapping around the address space.
+pub fn resolve_pcrel_pair(auipc_word: u32, lo_word: u32, auipc_pc: u32) -> u32 {
+    let hi = auipc_word & 0xffff_f000;
+    let lo = decode_fields(lo_word).imm as u32;
+    auipc_pc.wrapping_add(hi).wrapping_add(lo)
+}
+
+/// Disassemble a single instruction word.
+///
+/// Parameters:
+///     `ir: u32`: The instruction
+///
+/// Returns:
+///     `Result<Instruction, DisassembleError>`: The decoded instruction, or
+///     the field that could not be decoded.
+pub fn disassemble(ir: u32) -> Result<Instruction, DisassembleError> {
+    let opcode = bit_slice!(ir, 6, 0) as u8;
+    let rd = bit_slice!(ir, 11, 7) as u8;
+    let func3 = bit_slice!(ir, 14, 12) as u8;
+    let rs1 = bit_slice!(ir, 19, 15) as u8;
+    let rs2 = bit_slice!(ir, 24, 20) as u8;
+    let func7 = bit_slice!(ir, 31, 25) as u8;
+
+    let bad_func3 = || DisassembleError::InvalidFunc3Error(ir, func3);
+    let bad_func7 = || DisassembleError::InvalidFunc7Error(ir, func7);
+
+    let instruction = match opcode {
+        OPCODE_LUI | OPCODE_AUIPC => Instruction::Utype {
+            op: if opcode == OPCODE_LUI { "lui" } else { "auipc" }.to_owned(),
+            rd,
+            imm: ir >> 12,
+        },
+        OPCODE_JAL => Instruction::Jtype {
+            op: "jal".to_owned(),
+            rd,
+            imm: j_imm(ir),
+        },
+        OPCODE_JALR => {
+            if func3 != 0 {
+                return Err(bad_func3());
+            }
+            Instruction::Itype {
+                op: "jalr".to_owned(),
+                rd,
+                rs1,
+                imm: i_imm(ir),
+            }
+        }
+        OPCODE_BRANCH => Instruction::Btype {
+            op: match func3 {
+                FUNC3_BEQ => "beq",
+                FUNC3_BNE => "bne",
+                FUNC3_BLT => "blt",
+                FUNC3_BGE => "bge",
+                FUNC3_BLTU => "bltu",
+                FUNC3_BGEU => "bgeu",
+                _ => return Err(bad_func3()),
+            }
+            .to_owned(),
+            rs1,
+            rs2,
+            imm: b_imm(ir),
+        },
+        OPCODE_LOAD => Instruction::Itype {
+            op: match func3 {
+                FUNC3_LB => "lb",
+                FUNC3_LH => "lh",
+                FUNC3_LW => "lw",
+                FUNC3_LBU => "lbu",
+                FUNC3_LHU => "lhu",
+                _ => return Err(bad_func3()),
+            }
+            .to_owned(),
+            rd,
+            rs1,
+            imm: i_imm(ir),
+        },
+        OPCODE_STORE => Instruction::Stype {
+            op: match func3 {
+                FUNC3_SB => "sb",
+                FUNC3_SH => "sh",
+                FUNC3_SW => "sw",
+                _ => return Err(bad_func3()),
+            }
+            .to_owned(),
+            rs1,
+            rs2,
+            imm: s_imm(ir),
+        },
+        OPCODE_ARITHMETIC_IMM => {
+            // Shifts keep the shift amount in rs2 and use func7 to select
+            // arithmetic vs. logical shifts, like their register counterparts.
+            let (op, imm) = match (func3, func7) {
+                (FUNC3_ADD_SUB, _) => ("addi", i_imm(ir)),
+                (FUNC3_SLT, _) => ("slti", i_imm(ir)),
+                (FUNC3_SLTU, _) => ("sltiu", i_imm(ir)),
+                (FUNC3_XOR, _) => ("xori", i_imm(ir)),
+                (FUNC3_OR, _) => ("ori", i_imm(ir)),
+                (FUNC3_AND, _) => ("andi", i_imm(ir)),
+                (FUNC3_SLL, FUNC7_ADD) => ("slli", rs2 as i32),
+                (FUNC3_SR, FUNC7_ADD) => ("srli", rs2 as i32),
+                (FUNC3_SR, FUNC7_SUB) => ("srai", rs2 as i32),
+                _ => return Err(bad_func7()),
+            };
+            Instruction::Itype {
+                op: op.to_owned(),
+                rd,
+                rs1,
+                imm,
+            }
+        }
+        OPCODE_ARITHMETIC => Instruction::Rtype {
+            op: match (func7, func3) {
+                (FUNC7_ADD, FUNC3_ADD_SUB) => "add",
+                (FUNC7_SUB, FUNC3_ADD_SUB) => "sub",
+                (FUNC7_ADD, FUNC3_SLL) => "sll",
+                (FUNC7_ADD, FUNC3_SLT) => "slt",
+                (FUNC7_ADD, FUNC3_SLTU) => "sltu",
+                (FUNC7_ADD, FUNC3_XOR) => "xor",
+                (FUNC7_ADD, FUNC3_SR) => "srl",
+                (FUNC7_SUB, FUNC3_SR) => "sra",
+                (FUNC7_ADD, FUNC3_OR) => "or",
+                (FUNC7_ADD, FUNC3_AND) => "and",
+                (FUNC7_MULDIV, 0b000) => "mul",
+                (FUNC7_MULDIV, 0b001) => "mulh",
+                (FUNC7_MULDIV, 0b010) => "mulhsu",
+                (FUNC7_MULDIV, 0b011) => "mulhu",
+                (FUNC7_MULDIV, 0b100) => "div",
+                (FUNC7_MULDIV, 0b101) => "divu",
+                (FUNC7_MULDIV, 0b110) => "rem",
+                (FUNC7_MULDIV, 0b111) => "remu",
+                _ => return Err(bad_func7()),
+            }
+            .to_owned(),
+            rd,
+            rs1,
+            rs2,
+        },
+        _ => return Err(DisassembleError::InvalidOpcodeError(ir, opcode)),
+    };
+
+    Ok(instruction)
+}
+
+/// Disassemble a region of words, one result per word. Words that cannot
+/// be decoded (e.g. data mixed in with code) yield an error without stopping
+/// the iteration, so callers can print them as raw `.word`s and keep going.
+///
+/// Parameters:
+///     `words: &[u32]`: The words to disassemble
+///     `base: u32`: Address of the first word
+///
+/// Returns:
+///     `impl Iterator<Item = (u32, Result<Instruction, DisassembleError>)>`:
+///     The address of each word and the result of disassembling it.
+pub fn disassemble_iter(
+    words: &[u32],
+    base: u32,
+) -> impl Iterator<Item = (u32, Result<Instruction, DisassembleError>)> + '_ {
+    words
+        .iter()
+        .enumerate()
+        .map(move |(i, ir)| (base.wrapping_add(4 * i as u32), disassemble(*ir)))
+}
+
+/// Pack a flat binary into words, e.g. to disassemble it.
+///
+/// Parameters:
+///     `bytes: &[u8]`: The binary
+///     `endian: Endian`: Byte order of each word
+///
+/// Returns:
+///     `Result<Vec<u32>, DisassembleError>`: The words, or an error if the
+///     length is not a multiple of four. Trailing bytes are never padded,
+///     since a partial word cannot be a valid instruction.
+pub fn words_from_bytes(bytes: &[u8], endian: Endian) -> Result<Vec<u32>, DisassembleError> {
+    if bytes.len() & 3 != 0 {
+        return Err(DisassembleError::PartialWordError(bytes.len()));
+    }
+    Ok(bytes
+        .chunks(4)
+        .map(|w| {
+            let w = [w[0], w[1], w[2], w[3]];
+            match endian {
+                Endian::Little => u32::from_le_bytes(w),
+                Endian::Big => u32::from_be_bytes(w),
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,216 @@
+use crate::program::{Endian, Program, SymbolType};
+
+const EHDR_SIZE: u32 = 52;
+const PHDR_SIZE: u32 = 32;
+const SHDR_SIZE: u32 = 40;
+const SYM_SIZE: u32 = 16;
+
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_R: u32 = 4;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const STB_LOCAL: u8 = 0;
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+/// Section header indices.
+const TEXT_IDX: u16 = 1;
+const STRTAB_IDX: u32 = 3;
+const SHSTRTAB_IDX: u16 = 4;
+const SECTION_COUNT: u16 = 5;
+
+/// Helper for writing little-endian fields.
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.0.push(n);
+    }
+
+    fn u16(&mut self, n: u16) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    /// Pad with zeros to a multiple of `n`, which must be a power of two.
+    fn align(&mut self, n: usize) {
+        while self.0.len() & (n - 1) != 0 {
+            self.0.push(0);
+        }
+    }
+
+    fn pos(&self) -> u32 {
+        self.0.len() as u32
+    }
+}
+
+/// Append a null-terminated string to a string table, returning its offset.
+fn add_str(table: &mut Vec<u8>, s: &str) -> u32 {
+    let offset = table.len() as u32;
+    table.extend_from_slice(s.as_bytes());
+    table.push(0);
+    offset
+}
+
+/// Serialize a program as an ELF32 executable. The layout is the ELF
+/// header, one loadable segment for `.text`, then `.symtab`, `.strtab`,
+/// `.shstrtab`, and the section headers.
+pub(crate) fn write_elf(program: &Program) -> Vec<u8> {
+    let text = program.to_bytes(Endian::Little);
+
+    // Locals must come before globals in the symbol table. Sort by address
+    // so the output is the same on every run.
+    let mut symbols: Vec<(&String, &u32)> = program.symbols.iter().collect();
+    symbols.sort_by_key(|(name, addr)| (program.globals.contains(*name), **addr, *name));
+    let first_global = symbols
+        .iter()
+        .position(|(name, _)| program.globals.contains(*name))
+        .unwrap_or(symbols.len())
+        + 1;
+
+    let mut strtab = vec![0];
+    let mut symtab = Writer(Vec::new());
+    symtab.0.extend_from_slice(&[0; SYM_SIZE as usize]);
+    for (name, addr) in &symbols {
+        let bind = if program.globals.contains(*name) {
+            STB_GLOBAL
+        } else {
+            STB_LOCAL
+        };
+        let kind = match program.symbol_types.get(*name) {
+            Some(SymbolType::Function) => STT_FUNC,
+            Some(SymbolType::Object) => STT_OBJECT,
+            Some(SymbolType::NoType) | None => STT_NOTYPE,
+        };
+        symtab.u32(add_str(&mut strtab, name));
+        symtab.u32(**addr);
+        symtab.u32(program.symbol_sizes.get(*name).copied().unwrap_or(0));
+        symtab.u8(bind << 4 | kind);
+        symtab.u8(0);
+        symtab.u16(TEXT_IDX);
+    }
+
+    let mut shstrtab = vec![0];
+    let text_name = add_str(&mut shstrtab, ".text");
+    let symtab_name = add_str(&mut shstrtab, ".symtab");
+    let strtab_name = add_str(&mut shstrtab, ".strtab");
+    let shstrtab_name = add_str(&mut shstrtab, ".shstrtab");
+
+    // Lay out the body after the headers.
+    let mut body = Writer(Vec::new());
+    let text_off = EHDR_SIZE + PHDR_SIZE;
+    body.0.extend_from_slice(&text);
+    body.align(4);
+    let symtab_off = text_off + body.pos();
+    body.0.extend_from_slice(&symtab.0);
+    let strtab_off = text_off + body.pos();
+    body.0.extend_from_slice(&strtab);
+    let shstrtab_off = text_off + body.pos();
+    body.0.extend_from_slice(&shstrtab);
+    body.align(4);
+    let shoff = text_off + body.pos();
+
+    let mut elf = Writer(Vec::new());
+
+    // ELF header.
+    elf.0
+        .extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0]);
+    elf.0.extend_from_slice(&[0; 8]);
+    elf.u16(ET_EXEC);
+    elf.u16(EM_RISCV);
+    elf.u32(1);
+    elf.u32(program.entry.unwrap_or(program.base));
+    elf.u32(EHDR_SIZE);
+    elf.u32(shoff);
+    elf.u32(0);
+    elf.u16(EHDR_SIZE as u16);
+    elf.u16(PHDR_SIZE as u16);
+    elf.u16(1);
+    elf.u16(SHDR_SIZE as u16);
+    elf.u16(SECTION_COUNT);
+    elf.u16(SHSTRTAB_IDX);
+
+    // Program header.
+    elf.u32(PT_LOAD);
+    elf.u32(text_off);
+    elf.u32(program.base);
+    elf.u32(program.base);
+    elf.u32(text.len() as u32);
+    elf.u32(text.len() as u32);
+    elf.u32(PF_R | PF_X);
+    elf.u32(4);
+
+    elf.0.extend_from_slice(&body.0);
+
+    // Section headers: name, type, flags, addr, offset, size, link, info,
+    // addralign, entsize.
+    let sections: [[u32; 10]; 5] = [
+        [0; 10],
+        [
+            text_name,
+            SHT_PROGBITS,
+            SHF_ALLOC | SHF_EXECINSTR,
+            program.base,
+            text_off,
+            text.len() as u32,
+            0,
+            0,
+            4,
+            0,
+        ],
+        [
+            symtab_name,
+            SHT_SYMTAB,
+            0,
+            0,
+            symtab_off,
+            symtab.pos(),
+            STRTAB_IDX,
+            first_global as u32,
+            4,
+            SYM_SIZE,
+        ],
+        [
+            strtab_name,
+            SHT_STRTAB,
+            0,
+            0,
+            strtab_off,
+            strtab.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ],
+        [
+            shstrtab_name,
+            SHT_STRTAB,
+            0,
+            0,
+            shstrtab_off,
+            shstrtab.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ],
+    ];
+    for section in sections.iter() {
+        for field in section.iter() {
+            elf.u32(*field);
+        }
+    }
+
+    elf.0
+}
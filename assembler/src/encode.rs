@@ -1,3 +1,9 @@
+use crate::{
+    assembler::InstructionFormat,
+    disassembler::{decode_fields, DecodedFields},
+    error::AssemblerError,
+};
+
 /// Encode an integer as a bitmask for the opcode.
 #[macro_export]
 macro_rules! encode_opcode {
@@ -9,47 +15,62 @@ macro_rules! encode_opcode {
 /// Encode a register number as a bitmask for rd.
 #[macro_export]
 macro_rules! encode_rd {
-    ($n:expr) => {
-        (($n as u32) << 7)
-    };
+    ($n:expr) => {{
+        let n = $n as u32;
+        debug_assert!(n < 32, "rd out of range: {}", n);
+        (n << 7)
+    }};
 }
 
 /// Encode a register number as a bitmask for rs1.
 #[macro_export]
 macro_rules! encode_rs1 {
-    ($n:expr) => {
-        (($n as u32) << 15)
-    };
+    ($n:expr) => {{
+        let n = $n as u32;
+        debug_assert!(n < 32, "rs1 out of range: {}", n);
+        (n << 15)
+    }};
 }
 
 /// Encode a register number as a bitmask for rs2.
 #[macro_export]
 macro_rules! encode_rs2 {
-    ($n:expr) => {
-        (($n as u32) << 20)
-    };
+    ($n:expr) => {{
+        let n = $n as u32;
+        debug_assert!(n < 32, "rs2 out of range: {}", n);
+        (n << 20)
+    }};
 }
 
 /// Encode an integer as a bitmask for func3.
 #[macro_export]
 macro_rules! encode_func3 {
-    ($n: expr) => {
-        (($n as u32) << 12)
-    };
+    ($n: expr) => {{
+        let n = $n as u32;
+        debug_assert!(n < 8, "func3 out of range: {}", n);
+        (n << 12)
+    }};
 }
 
 /// Encode an integer as a bitmask for func7.
 #[macro_export]
 macro_rules! encode_func7 {
-    ($n: expr) => {
-        (($n as u32) << 25)
-    };
+    ($n: expr) => {{
+        let n = $n as u32;
+        debug_assert!(n < 128, "func7 out of range: {}", n);
+        (n << 25)
+    }};
 }
 
 /// Encode and integer as a bitmask for an I-type immediate.
 #[macro_export]
 macro_rules! encode_i_imm {
     ($n:expr) => {{
+        debug_assert!(
+            (-2048..2048).contains(&($n as i32)),
+            "I-type immediate out of range: {}",
+            $n as i32
+        );
         let n_bits = ($n & 0xFFF) as u32;
         let mut res: u32 = 0;
         res |= (n_bits as u32) << 20;
@@ -66,38 +87,187 @@ macro_rules! encode_i_imm {
 /// Encode and integer as a bitmask for a J-type immediate.
 #[macro_export]
 macro_rules! encode_j_imm {
-    ($n:expr) => {
+    ($n:expr) => {{
+        debug_assert!(
+            (-(1 << 20)..(1 << 20)).contains(&($n as i32)) && ($n as u32) & 1 == 0,
+            "J-type immediate out of range or misaligned: {}",
+            $n as i32
+        );
         (((($n as u32) & 0b10000000_00000000_00000000_00000000) << (31 - 31))
             | ((($n as u32) & 0b00000000_00001111_11110000_00000000) << (12 - 12))
             | ((($n as u32) & 0b00000000_00000000_00001000_00000000) << (20 - 11))
             | ((($n as u32) & 0b00000000_00000000_00000111_11100000) << (25 - 5))
             | ((($n as u32) & 0b00000000_00000000_00000000_00011110) << (21 - 1)))
-    };
+    }};
 }
 
 /// Encode and integer as a bitmask for a U-type immediate.
 #[macro_export]
 macro_rules! encode_u_imm {
-    ($n:expr) => {
-        (($n as u32) << 12)
-    };
+    ($n:expr) => {{
+        let n = $n as u32;
+        debug_assert!(n < (1 << 20), "U-type immediate out of range: {}", n);
+        (n << 12)
+    }};
 }
 
 /// Encode and integer as a bitmask for an S-type immediate.
 #[macro_export]
 macro_rules! encode_s_imm {
-    ($n:expr) => {
-        (((($n as u32) & 0b111111100000) << 25) | ((($n as u32) & 0b000000011111) << 7))
-    };
+    ($n:expr) => {{
+        debug_assert!(
+            (-2048..2048).contains(&($n as i32)),
+            "S-type immediate out of range: {}",
+            $n as i32
+        );
+        (((($n as u32) & 0b111111100000) << (25 - 5)) | ((($n as u32) & 0b000000011111) << 7))
+    }};
 }
 
 /// Encode and integer as a bitmask for a B-type immediate.
 #[macro_export]
 macro_rules! encode_b_imm {
-    ($n:expr) => {
+    ($n:expr) => {{
+        debug_assert!(
+            (-(1 << 12)..(1 << 12)).contains(&($n as i32)) && ($n as u32) & 1 == 0,
+            "B-type immediate out of range or misaligned: {}",
+            $n as i32
+        );
         (((($n as u32) & 0b10000000_00000000_00000000_00000000) << (31 - 31))
             | ((($n as u32) & 0b00000000_00000000_00000111_11100000) << (25 - 5))
             | ((($n as u32) & 0b00000000_00000000_00000000_00011110) << (8 - 1))
             | ((($n as u32) & 0b00000000_00000000_00001000_00000000) >> (11 - 7)))
+    }};
+}
+
+/// Returns true if the two's-complement value `n` fits in a signed field
+/// of `bits` bits.
+pub(crate) fn fits_signed(n: u32, bits: u32) -> bool {
+    let n = n as i32;
+    n >= -(1 << (bits - 1)) && n < (1 << (bits - 1))
+}
+
+/// Check that a register number fits in a 5-bit register field.
+fn check_register(n: u8) -> Result<u32, AssemblerError> {
+    if n < 32 {
+        Ok(n as u32)
+    } else {
+        Err(AssemblerError::NoSuchRegisterError(format!("x{}", n)))
+    }
+}
+
+/// Checked version of `encode_rd!`. Errors if `n` is not a register number.
+pub fn encode_rd(n: u8) -> Result<u32, AssemblerError> {
+    Ok(encode_rd!(check_register(n)?))
+}
+
+/// Checked version of `encode_rs1!`. Errors if `n` is not a register number.
+pub fn encode_rs1(n: u8) -> Result<u32, AssemblerError> {
+    Ok(encode_rs1!(check_register(n)?))
+}
+
+/// Checked version of `encode_rs2!`. Errors if `n` is not a register number.
+pub fn encode_rs2(n: u8) -> Result<u32, AssemblerError> {
+    Ok(encode_rs2!(check_register(n)?))
+}
+
+/// Checked version of `encode_i_imm!`. Errors if `n` does not fit in a
+/// signed 12-bit immediate.
+pub fn encode_i_imm(n: u32) -> Result<u32, AssemblerError> {
+    if !fits_signed(n, 12) {
+        return Err(AssemblerError::ImmediateTooLargeError);
+    }
+    Ok(encode_i_imm!(n))
+}
+
+/// Checked version of `encode_s_imm!`. Errors if `n` does not fit in a
+/// signed 12-bit immediate.
+pub fn encode_s_imm(n: u32) -> Result<u32, AssemblerError> {
+    if !fits_signed(n, 12) {
+        return Err(AssemblerError::ImmediateTooLargeError);
+    }
+    Ok(encode_s_imm!(n))
+}
+
+/// Checked version of `encode_b_imm!`. Errors if `n` does not fit in a
+/// signed 13-bit offset or is not a multiple of two.
+pub fn encode_b_imm(n: u32) -> Result<u32, AssemblerError> {
+    if !fits_signed(n, 13) {
+        return Err(AssemblerError::ImmediateTooLargeError);
+    }
+    if n & 1 != 0 {
+        return Err(AssemblerError::ImmediateMisalignedError);
+    }
+    Ok(encode_b_imm!(n))
+}
+
+/// Checked version of `encode_u_imm!`. Errors if `n` does not fit in an
+/// unsigned 20-bit immediate.
+pub fn encode_u_imm(n: u32) -> Result<u32, AssemblerError> {
+    if n >= (1 << 20) {
+        return Err(AssemblerError::ImmediateTooLargeError);
+    }
+    Ok(encode_u_imm!(n))
+}
+
+/// Checked version of `encode_j_imm!`. Errors if `n` does not fit in a
+/// signed 21-bit offset or is not a multiple of two.
+pub fn encode_j_imm(n: u32) -> Result<u32, AssemblerError> {
+    if !fits_signed(n, 21) {
+        return Err(AssemblerError::ImmediateTooLargeError);
+    }
+    if n & 1 != 0 {
+        return Err(AssemblerError::ImmediateMisalignedError);
+    }
+    Ok(encode_j_imm!(n))
+}
+
+/// Decode `ir` and check that it holds the `expected` fields, catching an
+/// encoder that put a field in the wrong bits. Only the fields used by the
+/// format of `expected` are compared, and `expected.imm` is the immediate
+/// as `decode_fields` reconstructs it.
+///
+/// Parameters:
+///     `ir: u32`: The encoded instruction
+///     `expected: &DecodedFields`: The fields it was encoded from
+///
+/// Returns:
+///     `Result<(), AssemblerError>`: `EncodingMismatchError` with the first
+///     field that does not match.
+pub fn verify_encoding(ir: u32, expected: &DecodedFields) -> Result<(), AssemblerError> {
+    let actual = decode_fields(ir);
+    // Every format with rs1 also has func3, and every one but R-type an
+    // immediate.
+    let (rd, rs1, rs2, func7) = match expected.format {
+        Some(InstructionFormat::Rtype) => (true, true, true, true),
+        Some(InstructionFormat::Itype) => (true, true, false, false),
+        Some(InstructionFormat::Stype) | Some(InstructionFormat::Btype) => {
+            (false, true, true, false)
+        }
+        Some(InstructionFormat::Utype) | Some(InstructionFormat::Jtype) => {
+            (true, false, false, false)
+        }
+        None => (false, false, false, false),
     };
+    let fields = [
+        ("opcode", true, expected.opcode as i32, actual.opcode as i32),
+        ("rd", rd, expected.rd as i32, actual.rd as i32),
+        ("rs1", rs1, expected.rs1 as i32, actual.rs1 as i32),
+        ("func3", rs1, expected.func3 as i32, actual.func3 as i32),
+        ("rs2", rs2, expected.rs2 as i32, actual.rs2 as i32),
+        ("func7", func7, expected.func7 as i32, actual.func7 as i32),
+        ("imm", !func7, expected.imm, actual.imm),
+    ];
+    match fields
+        .iter()
+        .find(|(_, used, expected, actual)| *used && expected != actual)
+    {
+        Some((field, _, expected, actual)) => Err(AssemblerError::EncodingMismatchError {
+            ir,
+            field,
+            expected: *expected,
+            actual: *actual,
+        }),
+        None => Ok(()),
+    }
 }
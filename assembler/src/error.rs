@@ -1,13 +1,195 @@
+use std::{fmt, ops::Range};
+
+use crate::diagnostic::Diagnostic;
+
 /// Enumeration of possible errors when assembling a program.
+///
+/// Errors about a token contain the token as written: the operation, the
+/// label, the register, or the immediate. Directive errors contain
+/// `(directive: String)`, ordering errors the
+/// unknown suffix `(suffix: String)`, and label and constant
+/// errors `(name: String)`, including labels, constants, and register
+/// aliases named like a register. Image size errors
+/// contain the bytes needed and the bytes available, and alignment errors
+/// the address of the misaligned instruction. Float errors contain the
+/// immediate that looks like a floating-point number, which must be written
+/// as an integer instead, since RV32I has no floating-point immediates.
+/// Value errors contain a literal too large for any 32-bit value.
+/// Denied warnings contain every warning, when warnings are errors.
+/// Operand count errors contain the mnemonic and the operands it expects,
+/// like `rd, rs1, imm`. Entry errors contain the entry point symbol that
+/// is not defined. Encoding mismatches contain the encoded word, the field
+/// that did not decode to the value it was encoded from, and both values;
+/// they mean the assembler itself has a bug. Unit errors contain the file
+/// and zero-based line in it of an error assembling several files.
+/// Extension errors contain a mnemonic from an extension that is not
+/// enabled and the name of the extension, like `M`. Address overflow
+/// errors contain the address of a line that would reach the end of the
+/// 32-bit address space, leaving no address for the pc after it. Located
+/// errors wrap an error from `Assembler::assemble_located` with the
+/// zero-based line and column it is on, counting through every inserted
+/// source, and the byte range in the source of the token it is about,
+/// which is empty at the start of the line's code if it names none.
 #[derive(Debug, PartialEq)]
 pub enum AssemblerError {
-    InvalidOperationError,
-    NoSuchLabelError,
-    NoSuchRegisterError,
+    InvalidOperationError(String),
+    NoSuchLabelError(String),
+    NoSuchRegisterError(String),
     WrongOperandTypeError,
     TooManyTokensError,
     TooFewTokensError,
     ImmediateTooLargeError,
-    InvalidImmediateError,
+    ImmediateMisalignedError,
+    InvalidImmediateError(String),
     IOError,
+    MultipleInstructionsError,
+    UnknownDirectiveError(String),
+    DuplicateLabelError(String),
+    CircularConstantError(String),
+    ReservedNameError(String),
+    InvalidOrderingError(String),
+    ImageTooLargeError {
+        needed: usize,
+        capacity: usize,
+    },
+    MisalignedInstructionError {
+        pc: u32,
+    },
+    FloatNotSupportedError {
+        token: String,
+    },
+    ValueTooLargeError {
+        token: String,
+    },
+    WarningsDeniedError(Vec<Diagnostic>),
+    OperandCountError {
+        op: String,
+        expected: &'static str,
+    },
+    UndefinedEntryError(String),
+    EncodingMismatchError {
+        ir: u32,
+        field: &'static str,
+        expected: i32,
+        actual: i32,
+    },
+    UnitError {
+        file: String,
+        line: usize,
+        error: Box<AssemblerError>,
+    },
+    ExtensionDisabledError {
+        mnemonic: String,
+        extension: &'static str,
+    },
+    AddressOverflowError {
+        pc: u32,
+    },
+    LocatedError {
+        line: usize,
+        column: usize,
+        span: Range<usize>,
+        error: Box<AssemblerError>,
+    },
+}
+
+impl fmt::Display for AssemblerError {
+    /// A message for people, in the style of rustc: lowercase and without
+    /// a trailing period.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use AssemblerError::*;
+        match self {
+            InvalidOperationError(op) => write!(f, "unknown operation `{}`", op),
+            NoSuchLabelError(name) => write!(f, "no label or constant named `{}`", name),
+            NoSuchRegisterError(reg) => write!(f, "no register named `{}`", reg),
+            WrongOperandTypeError => write!(f, "operand is the wrong kind for this instruction"),
+            TooManyTokensError => write!(f, "too many operands"),
+            TooFewTokensError => write!(f, "too few operands"),
+            ImmediateTooLargeError => write!(f, "immediate is out of range"),
+            ImmediateMisalignedError => write!(f, "offset is not a multiple of 2"),
+            InvalidImmediateError(imm) => write!(f, "invalid immediate `{}`", imm),
+            IOError => write!(f, "failed to read or write"),
+            MultipleInstructionsError => {
+                write!(f, "expands to more than one instruction")
+            }
+            UnknownDirectiveError(directive) => write!(f, "unknown directive `{}`", directive),
+            DuplicateLabelError(name) => write!(f, "`{}` is defined more than once", name),
+            CircularConstantError(name) => {
+                write!(f, "constant `{}` is defined in terms of itself", name)
+            }
+            ReservedNameError(name) => {
+                write!(
+                    f,
+                    "`{}` is a register, so it cannot name a label or constant",
+                    name
+                )
+            }
+            InvalidOrderingError(suffix) => write!(f, "unknown ordering suffix `{}`", suffix),
+            ImageTooLargeError { needed, capacity } => write!(
+                f,
+                "image needs {} bytes, but only {} are available",
+                needed, capacity
+            ),
+            MisalignedInstructionError { pc } => {
+                write!(f, "instruction at 0x{:08x} is not word-aligned", pc)
+            }
+            FloatNotSupportedError { token } => write!(
+                f,
+                "`{}` is a floating-point number, but immediates must be integers",
+                token
+            ),
+            ValueTooLargeError { token } => write!(f, "`{}` does not fit in 32 bits", token),
+            WarningsDeniedError(warnings) => {
+                write!(f, "warnings are denied, and there are {}", warnings.len())
+            }
+            OperandCountError { op, expected: "" } => write!(f, "`{}` takes no operands", op),
+            OperandCountError { op, expected } => {
+                write!(f, "`{}` takes operands `{}`", op, expected)
+            }
+            UndefinedEntryError(name) => write!(f, "entry point `{}` is not defined", name),
+            EncodingMismatchError {
+                ir,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "0x{:08x} decodes `{}` as {} instead of {}, which is an assembler bug",
+                ir, field, actual, expected
+            ),
+            UnitError { file, line, error } => write!(f, "{}:{}: {}", file, line + 1, error),
+            ExtensionDisabledError {
+                mnemonic,
+                extension,
+            } => write!(
+                f,
+                "`{}` needs the {} extension, which is not enabled",
+                mnemonic, extension
+            ),
+            AddressOverflowError { pc } => write!(
+                f,
+                "line at 0x{:08x} reaches the end of the address space",
+                pc
+            ),
+            LocatedError {
+                line,
+                column,
+                error,
+                ..
+            } => write!(f, "line {}:{}: {}", line + 1, column + 1, error),
+        }
+    }
+}
+
+/// Enumeration of possible errors when disassembling a word.
+///
+/// Field errors contain `(instruction: u32, bad_field: u8)`. Length errors
+/// contain `(len: usize)`, the length of input that is not a whole number
+/// of words.
+#[derive(Debug, PartialEq)]
+pub enum DisassembleError {
+    InvalidOpcodeError(u32, u8),
+    InvalidFunc3Error(u32, u8),
+    InvalidFunc7Error(u32, u8),
+    PartialWordError(usize),
 }
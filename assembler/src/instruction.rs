@@ -0,0 +1,178 @@
+use std::{collections::HashMap, fmt};
+
+use lib_rv32_common::constants::REG_NAMES;
+
+use crate::{
+    assembler::assemble_line,
+    error::AssemblerError,
+    program::{ImmediateFormat, JumpStyle, Radix},
+};
+
+/// A single RV32I instruction broken into its operands. Immediates are
+/// stored sign-extended, except for U-type immediates, which hold the
+/// upper 20 bits as they would be written in assembly. Pseudo-instructions
+/// from `parse_instruction` keep their name and operands as written, and
+/// are only expanded when encoded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Rtype {
+        op: String,
+        rd: u8,
+        rs1: u8,
+        rs2: u8,
+    },
+    Itype {
+        op: String,
+        rd: u8,
+        rs1: u8,
+        imm: i32,
+    },
+    Stype {
+        op: String,
+        rs1: u8,
+        rs2: u8,
+        imm: i32,
+    },
+    Btype {
+        op: String,
+        rs1: u8,
+        rs2: u8,
+        imm: i32,
+    },
+    Utype {
+        op: String,
+        rd: u8,
+        imm: u32,
+    },
+    Jtype {
+        op: String,
+        rd: u8,
+        imm: i32,
+    },
+    Pseudo {
+        name: String,
+        operands: Vec<String>,
+    },
+}
+
+impl Instruction {
+    /// The mnemonic of the instruction.
+    pub fn op(&self) -> &str {
+        match self {
+            Instruction::Rtype { op, .. }
+            | Instruction::Itype { op, .. }
+            | Instruction::Stype { op, .. }
+            | Instruction::Btype { op, .. }
+            | Instruction::Utype { op, .. }
+            | Instruction::Jtype { op, .. } => op,
+            Instruction::Pseudo { name, .. } => name,
+        }
+    }
+
+    /// The address the instruction refers to relative to its own address
+    /// `pc`: where a branch or `jal` goes, or what an `auipc` computes.
+    /// `lui` gives an absolute value, not an address relative to `pc`, and
+    /// `jalr` depends on a register, so neither has a target.
+    pub fn target(&self, pc: u32) -> Option<u32> {
+        match self {
+            Instruction::Btype { imm, .. } | Instruction::Jtype { imm, .. } => {
+                Some(pc.wrapping_add(*imm as u32))
+            }
+            Instruction::Utype { op, imm, .. } if op == "auipc" => Some(pc.wrapping_add(imm << 12)),
+            _ => None,
+        }
+    }
+
+    /// Format the instruction in the same syntax accepted by the assembler,
+    /// printing `jal zero, offset` as `j offset` if `jumps` is
+    /// `JumpStyle::J`, and immediates in the radixes of `immediates`.
+    /// `to_string` uses `JumpStyle::Jal` and the default `ImmediateFormat`.
+    pub fn to_string_with(&self, jumps: JumpStyle, immediates: ImmediateFormat) -> String {
+        let reg = |n: &u8| REG_NAMES[*n as usize];
+        let imm = |imm: &i32| format_radix(*imm as i64, immediates.signed);
+        match self {
+            Instruction::Rtype { op, rd, rs1, rs2 } => {
+                format!("{} {}, {}, {}", op, reg(rd), reg(rs1), reg(rs2))
+            }
+            Instruction::Itype {
+                op,
+                rd,
+                rs1,
+                imm: i,
+            } if is_load(op) => {
+                format!("{} {}, {}({})", op, reg(rd), imm(i), reg(rs1))
+            }
+            Instruction::Itype {
+                op,
+                rd,
+                rs1,
+                imm: i,
+            } => {
+                format!("{} {}, {}, {}", op, reg(rd), reg(rs1), imm(i))
+            }
+            Instruction::Stype {
+                op,
+                rs1,
+                rs2,
+                imm: i,
+            } => {
+                format!("{} {}, {}({})", op, reg(rs2), imm(i), reg(rs1))
+            }
+            Instruction::Btype {
+                op,
+                rs1,
+                rs2,
+                imm: i,
+            } => {
+                format!("{} {}, {}, {}", op, reg(rs1), reg(rs2), imm(i))
+            }
+            Instruction::Utype { op, rd, imm: i } => {
+                format!(
+                    "{} {}, {}",
+                    op,
+                    reg(rd),
+                    format_radix(*i as i64, immediates.upper)
+                )
+            }
+            Instruction::Jtype { op, rd: 0, imm: i } if op == "jal" && jumps == JumpStyle::J => {
+                format!("j {}", imm(i))
+            }
+            Instruction::Jtype { op, rd, imm: i } => format!("{} {}, {}", op, reg(rd), imm(i)),
+            Instruction::Pseudo { name, operands } if operands.is_empty() => name.clone(),
+            Instruction::Pseudo { name, operands } => format!("{} {}", name, operands.join(", ")),
+        }
+    }
+
+    /// Encode the instruction at `pc`, expanding a pseudo-instruction into
+    /// every word it stands for.
+    pub fn encode(
+        &self,
+        labels: &HashMap<String, u32>,
+        pc: u32,
+    ) -> Result<Vec<u32>, AssemblerError> {
+        assemble_line(&self.to_string(), labels, pc).map(|(words, _)| words)
+    }
+}
+
+/// Returns true if the mnemonic is a load, which uses `offset(reg)` addressing.
+fn is_load(op: &str) -> bool {
+    matches!(op, "lb" | "lh" | "lw" | "lbu" | "lhu")
+}
+
+/// Format an immediate in `radix`, with a sign rather than as two's
+/// complement, so that it assembles back to the same value.
+fn format_radix(imm: i64, radix: Radix) -> String {
+    match radix {
+        Radix::Decimal => imm.to_string(),
+        Radix::Hex if imm < 0 => format!("-0x{:x}", -imm),
+        Radix::Hex => format!("0x{:x}", imm),
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Format the instruction in the same syntax accepted by the assembler.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = self.to_string_with(JumpStyle::Jal, ImmediateFormat::default());
+        write!(f, "{}", text)
+    }
+}
@@ -1,15 +1,40 @@
 /// Functions for assembling instructions and buffers.
 mod assembler;
 
+/// Warnings and errors reported while assembling.
+mod diagnostic;
+
+/// Functions for disassembling instruction words.
+mod disassembler;
+
 /// Functions for encoding integers as instruction fields.
 pub mod encode;
 
+/// ELF serialization of programs.
+mod elf;
+
 /// Errors that may arise when assembling.
 pub mod error;
 
+/// Structured representation of a single instruction.
+mod instruction;
+
+/// Peephole optimization of source before it is laid out.
+mod optimize;
+
 /// Functions for parsing an instruction string.
 pub mod parse;
 
+/// Expansion of pseudo-instructions into base instructions.
+pub mod pseudo;
+
+/// Assembled programs and their export formats.
+mod program;
+
+/// Assertions for tests of assembled programs.
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 /// Unit-tests.
 #[cfg(test)]
 mod test;
@@ -19,3 +44,12 @@ pub use lib_rv32_common as common;
 
 // Re-export assembler functions.
 pub use assembler::*;
+
+pub use diagnostic::*;
+
+// Re-export disassembler functions.
+pub use disassembler::*;
+
+pub use instruction::Instruction;
+
+pub use program::*;
@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+
+use lib_rv32_common::constants::*;
+
+use crate::{
+    assembler::assemble_ir, disassembler::disassemble, instruction::Instruction, parse::*,
+    pseudo::expand_pseudo, tokenize,
+};
+
+/// A source line split into its label tokens and the instruction they
+/// precede, if the instruction is one the optimizer understands.
+struct Line {
+    labels: Vec<String>,
+    /// True if the line has anything other than labels.
+    code: bool,
+    /// The tokens after the labels.
+    tokens: Vec<String>,
+    instruction: Option<Instruction>,
+}
+
+/// Decode the instruction on a line. Only instructions whose effect is
+/// fully described by their registers are decoded: ALU operations, upper
+/// immediates, and loads, with no label operands.
+fn decode(tokens: &[String], line: &str) -> Option<Instruction> {
+    let op = match expand_pseudo(tokens.first().map(|_| tokens)?) {
+        Ok(Some(expansion)) if expansion.len() == 1 => expansion[0][0].clone(),
+        _ => tokens[0].clone(),
+    };
+    match match_opcode(&op).ok()? {
+        OPCODE_ARITHMETIC | OPCODE_ARITHMETIC_IMM | OPCODE_LUI | OPCODE_AUIPC | OPCODE_LOAD => {
+            let ir = assemble_ir(line, &mut HashMap::new(), 0).ok()??;
+            disassemble(ir).ok()
+        }
+        _ => None,
+    }
+}
+
+fn parse_line(line: &str) -> Line {
+    let tokens: Vec<String> = tokenize!(line);
+    let labels_end = tokens
+        .iter()
+        .position(|t| !t.ends_with(':'))
+        .unwrap_or(tokens.len());
+    Line {
+        labels: tokens[..labels_end].to_vec(),
+        code: labels_end < tokens.len(),
+        tokens: tokens[labels_end..].to_vec(),
+        instruction: decode(&tokens[labels_end..], line),
+    }
+}
+
+/// The register an instruction writes, if any.
+fn writes(instruction: &Instruction) -> Option<u8> {
+    match instruction {
+        Instruction::Rtype { rd, .. }
+        | Instruction::Itype { rd, .. }
+        | Instruction::Utype { rd, .. }
+        | Instruction::Jtype { rd, .. } => Some(*rd),
+        _ => None,
+    }
+}
+
+/// Returns true if the instruction reads register `reg`.
+fn reads(instruction: &Instruction, reg: u8) -> bool {
+    match instruction {
+        Instruction::Rtype { rs1, rs2, .. }
+        | Instruction::Stype { rs1, rs2, .. }
+        | Instruction::Btype { rs1, rs2, .. } => *rs1 == reg || *rs2 == reg,
+        Instruction::Itype { rs1, .. } => *rs1 == reg,
+        _ => false,
+    }
+}
+
+/// Returns true if the instruction only computes a register from other
+/// registers, so removing it has no effect beyond that register.
+fn is_pure(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Itype { op, .. } => match_opcode(op) == Ok(OPCODE_ARITHMETIC_IMM),
+        Instruction::Rtype { .. } | Instruction::Utype { .. } => true,
+        _ => false,
+    }
+}
+
+/// Returns true if the instruction has no effect at all. The canonical
+/// `addi zero, zero, 0` nop is kept, since it is usually there on purpose.
+fn is_dead(instruction: &Instruction) -> bool {
+    match instruction {
+        Instruction::Itype { op, rd, rs1, imm } if op == "addi" && *imm == 0 => {
+            *rd == *rs1 && *rd != 0
+        }
+        _ => is_pure(instruction) && writes(instruction) == Some(0),
+    }
+}
+
+/// Returns true if `next` overwrites the register written by the pure
+/// instruction `prev` without reading it first, so `prev` is dead.
+fn is_overwritten(prev: &Instruction, next: &Instruction) -> bool {
+    match writes(prev) {
+        Some(rd) if is_pure(prev) => writes(next) == Some(rd) && !reads(next, rd),
+        _ => false,
+    }
+}
+
+/// If `li` and `mv` are `addi a, zero, imm` and `addi b, a, 0`, return
+/// `addi b, zero, imm`.
+fn fold_li_mv(li: &Instruction, mv: &Instruction) -> Option<Instruction> {
+    match (li, mv) {
+        (
+            Instruction::Itype {
+                op: li_op,
+                rd: a,
+                rs1: 0,
+                imm,
+            },
+            Instruction::Itype {
+                op: mv_op,
+                rd: b,
+                rs1,
+                imm: 0,
+            },
+        ) if li_op == "addi" && mv_op == "addi" && rs1 == a => Some(Instruction::Itype {
+            op: "addi".to_owned(),
+            rd: *b,
+            rs1: 0,
+            imm: *imm,
+        }),
+        _ => None,
+    }
+}
+
+/// Expand the tokens of a line if they are a single-instruction
+/// pseudo-instruction.
+fn expand(tokens: &[String]) -> Vec<String> {
+    match expand_pseudo(tokens) {
+        Ok(Some(expansion)) if expansion.len() == 1 => expansion[0].clone(),
+        _ => tokens.to_vec(),
+    }
+}
+
+/// If `set` is `seqz r, a` or `snez r, a` and `branch` is `beqz r` or
+/// `bnez r` to some target, return the tokens of the branch testing `a`
+/// directly, and `r`.
+fn fold_set_branch(set: &[String], branch: &[String]) -> Option<(Vec<String>, u8)> {
+    let set = expand(set);
+    let branch = expand(branch);
+    // `seqz` is `sltiu r, a, 1`, true when `a` is zero; `snez` is
+    // `sltu r, zero, a`, true when it is not.
+    let (r, a, equal) = match &set.iter().map(|t| &t[..]).collect::<Vec<_>>()[..] {
+        ["sltiu", r, a, imm] if parse_literal(imm) == Some(1) => (*r, *a, true),
+        ["sltu", r, zero, a] if match_register(zero) == Ok(0) => (*r, *a, false),
+        _ => return None,
+    };
+    let reg = match_register(r).ok()?;
+    // A literal offset is relative to the branch, which moves up a word.
+    if reg == 0
+        || match_register(a).ok()? == reg
+        || branch.len() != 4
+        || parse_literal(&branch[3]).is_some()
+    {
+        return None;
+    }
+    let tested = match (match_register(&branch[1]), match_register(&branch[2])) {
+        (Ok(rs1), Ok(0)) | (Ok(0), Ok(rs1)) => rs1,
+        _ => return None,
+    };
+    // Branching when the flag is set is branching on the condition it
+    // holds, and branching when it is clear on the opposite one.
+    let taken_if_set = match &branch[0][..] {
+        "bne" => true,
+        "beq" => false,
+        _ => return None,
+    };
+    if tested != reg {
+        return None;
+    }
+    let op = if equal == taken_if_set { "beq" } else { "bne" };
+    Some((
+        vec![
+            op.to_owned(),
+            a.to_owned(),
+            "zero".to_owned(),
+            branch[3].clone(),
+        ],
+        reg,
+    ))
+}
+
+/// Returns true if `reg` appears as an operand of any line with code other
+/// than lines `i` and `j`. This is a stand-in for proving the register is
+/// dead, which would need control flow.
+fn mentioned_elsewhere(lines: &[Line], reg: u8, i: usize, j: usize) -> bool {
+    lines.iter().enumerate().any(|(k, line)| {
+        k != i
+            && k != j
+            && line.code
+            && line.tokens[1..]
+                .iter()
+                .any(|t| match_register(t) == Ok(reg))
+    })
+}
+
+/// Format a line as its labels followed by an instruction, if any.
+fn render(labels: &[String], instruction: Option<&Instruction>) -> String {
+    let mut line = labels.join(" ");
+    if let Some(instruction) = instruction {
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line += &instruction.to_string();
+    }
+    line
+}
+
+/// Remove provably dead instructions from a chunk of source, as described
+/// on `Assembler::optimize`. Only instructions that write a register and
+/// nothing else are considered, and anything using a label is left alone.
+///
+/// Removed instructions leave their labels behind, so every returned line
+/// corresponds to the same source line.
+pub(crate) fn optimize(program: &str) -> Vec<String> {
+    let mut lines: Vec<Line> = program.split('\n').map(parse_line).collect();
+    let mut out: Vec<String> = program.split('\n').map(|l| l.to_owned()).collect();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        // Indices of the lines that still hold an instruction, in order.
+        // Lines with only labels do not break up a sequence.
+        let code: Vec<usize> = (0..lines.len()).filter(|i| lines[*i].code).collect();
+
+        for (n, &i) in code.iter().enumerate() {
+            let next = code
+                .get(n + 1)
+                .and_then(|j| lines[*j].instruction.as_ref().map(|ins| (*j, ins)));
+            let after = code.get(n + 2).and_then(|k| lines[*k].instruction.as_ref());
+            let current = match &lines[i].instruction {
+                Some(ins) => ins,
+                None => continue,
+            };
+
+            if is_dead(current) || next.is_some_and(|(_, ins)| is_overwritten(current, ins)) {
+                out[i] = render(&lines[i].labels, None);
+                lines[i].code = false;
+                lines[i].instruction = None;
+                changed = true;
+                break;
+            }
+
+            let j = match code.get(n + 1) {
+                Some(j) => *j,
+                None => continue,
+            };
+            if lines[j].labels.is_empty() {
+                if let Some((branch, reg)) = fold_set_branch(&lines[i].tokens, &lines[j].tokens) {
+                    if !mentioned_elsewhere(&lines, reg, i, j) {
+                        let mut line = lines[i].labels.clone();
+                        line.push(format!("{} {}", branch[0], branch[1..].join(", ")));
+                        out[i] = line.join(" ");
+                        out[j] = String::new();
+                        lines[i].tokens = branch;
+                        lines[i].instruction = None;
+                        lines[j].code = false;
+                        lines[j].tokens.clear();
+                        changed = true;
+                        break;
+                    }
+                }
+            }
+
+            if let (Some((j, mv)), Some(after)) = (next, after) {
+                if let Some(folded) = fold_li_mv(current, mv) {
+                    if lines[j].labels.is_empty() && is_overwritten(current, after) {
+                        out[i] = render(&lines[i].labels, Some(&folded));
+                        out[j] = String::new();
+                        lines[i].tokens = tokenize!(folded.to_string());
+                        lines[i].instruction = Some(folded);
+                        lines[j].code = false;
+                        lines[j].instruction = None;
+                        changed = true;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    out
+}
@@ -1,70 +1,727 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range, sync::OnceLock};
 
-use lib_rv32_common::{constants::*, parse_int};
+use lib_rv32_common::constants::*;
 
-use crate::error::AssemblerError;
+use crate::{encode::fits_signed, error::AssemblerError};
 
-/// Convert an instruction to it's tokens, stripping out whitespace,
-/// parenthesis, and commas.
+/// Convert an instruction to it's tokens with `parse::tokenize`.
 #[macro_export]
 macro_rules! tokenize {
     ($s:expr) => {
-        $s.replace(",", " ")
-            .replace("\n", " ")
-            .replace("(", " ")
-            .replace(")", " ")
-            .to_ascii_lowercase()
-            .split_whitespace()
-            .map(|s| s.to_owned())
-            .collect();
+        $crate::parse::tokenize(&$s)
     };
 }
 
+/// Split a line into tokens. This is exactly the tokenization used by the
+/// assembler:
+///
+/// - Tokens are separated by any run of commas and whitespace, so empty
+///   operands are never produced. Whitespace is any Unicode whitespace,
+///   like the non-breaking spaces that copying from a web page leaves, or
+///   a zero-width space or byte order mark.
+/// - `#`, `//`, or `;` starts a comment that runs to the end of the line.
+/// - Tokens are lowercased, except inside double-quoted strings.
+/// - A double-quoted string is one token, including its quotes. Separators
+///   and comment markers inside it are kept, and `\"` does not end it.
+///   `parse_string` decodes it.
+/// - Anything inside parentheses stays in one token, so expressions can
+///   contain spaces.
+/// - A trailing `(reg)` group is split off its offset, so `(a - b)(sp)`
+///   becomes `(a - b)` and `sp`, and `8(sp)` becomes `8` and `sp`. A token
+///   that is only a group, like `(sp)`, becomes its contents, so spacing
+///   around the group does not matter: `8 ( sp )` is the same as `8(sp)`.
+///   The group after a `%hi` or `%lo` modifier is not split.
+pub fn tokenize(line: &str) -> Vec<String> {
+    let line = &line[..comment_start(line).unwrap_or(line.len())];
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut depth = 0;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                token.push(c);
+                while let Some(c) = chars.next() {
+                    token.push(c);
+                    match c {
+                        '\\' => token.extend(chars.next()),
+                        '"' => break,
+                        _ => (),
+                    }
+                }
+                continue;
+            }
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth <= 0 => {
+                tokens.push(std::mem::take(&mut token));
+                continue;
+            }
+            c if is_space(c) && depth <= 0 => {
+                tokens.push(std::mem::take(&mut token));
+                continue;
+            }
+            _ => (),
+        }
+        token.push(c.to_ascii_lowercase());
+    }
+    tokens.push(token);
+
+    let mut split = Vec::new();
+    for token in tokens.into_iter().filter(|t| !t.is_empty()) {
+        match split_offset(&token) {
+            Some((offset, reg)) => {
+                if !offset.is_empty() {
+                    split.push(offset.to_owned());
+                }
+                split.push(reg.trim_matches(is_space).to_owned());
+            }
+            None => split.push(token),
+        }
+    }
+    split
+}
+
+/// Returns true if a character separates tokens: any Unicode whitespace,
+/// and the zero-width spaces and byte order mark that are invisible but
+/// not whitespace to Unicode.
+pub(crate) fn is_space(c: char) -> bool {
+    c.is_whitespace()
+        || matches!(
+            c,
+            '\u{200b}' | '\u{200c}' | '\u{200d}' | '\u{2060}' | '\u{feff}'
+        )
+}
+
+/// Decode a double-quoted string token from `tokenize` into its contents,
+/// for directives that take a string argument. The escapes `\\`, `\"`,
+/// `\'`, `\n`, `\t`, `\r`, and `\0` are supported. Returns `None` if the
+/// token is not exactly one quoted string or has an unknown escape.
+pub fn parse_string(token: &str) -> Option<String> {
+    let inner = token.strip_prefix('"')?.strip_suffix('"')?;
+    let mut decoded = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        decoded.push(match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c @ ('\\' | '"' | '\'') => c,
+                _ => return None,
+            },
+            // An unescaped quote ends the string early.
+            '"' => return None,
+            c => c,
+        });
+    }
+    Some(decoded)
+}
+
+/// The byte index where a comment starts on a line, if it has one.
+fn comment_start(line: &str) -> Option<usize> {
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '#' | ';' => return Some(i),
+            '/' if chars.peek().map(|(_, c)| *c) == Some('/') => return Some(i),
+            _ => (),
+        }
+    }
+    None
+}
+
+/// The byte range of the code on a line, without leading labels, a
+/// trailing comment, or surrounding whitespace. A line with no code gives
+/// an empty range.
+pub fn code_span(line: &str) -> Range<usize> {
+    let code = &line[..comment_start(line).unwrap_or(line.len())];
+    let mut start = 0;
+    loop {
+        let rest = &code[start..];
+        let trimmed = rest.trim_start_matches(is_space);
+        start += rest.len() - trimmed.len();
+        let word = trimmed.split(is_space).next().unwrap_or("");
+        if word.is_empty() || !word.ends_with(':') {
+            break;
+        }
+        start += word.len();
+    }
+    start..start + code[start..].trim_end_matches(is_space).len()
+}
+
+/// Split an `offset(reg)` token into its offset and the contents of the
+/// trailing parenthesized group.
+fn split_offset(token: &str) -> Option<(&str, &str)> {
+    if !token.ends_with(')') {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, c) in token.char_indices().rev() {
+        match c {
+            ')' => depth += 1,
+            '(' => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            let offset = &token[..i];
+            // `%lo(sym)` is a modifier applied to its group, not an offset.
+            if offset.starts_with('%') && !offset.contains('(') {
+                return None;
+            }
+            return Some((offset, &token[i + 1..token.len() - 1]));
+        }
+    }
+    None
+}
+
+/// Every mnemonic the assembler accepts, with the operands it expects:
+/// base instructions, then pseudo-instructions.
+static SIGNATURES: &[(&str, &str)] = &[
+    ("add", "rd, rs1, rs2"),
+    ("sub", "rd, rs1, rs2"),
+    ("sll", "rd, rs1, rs2"),
+    ("slt", "rd, rs1, rs2"),
+    ("sltu", "rd, rs1, rs2"),
+    ("xor", "rd, rs1, rs2"),
+    ("srl", "rd, rs1, rs2"),
+    ("sra", "rd, rs1, rs2"),
+    ("or", "rd, rs1, rs2"),
+    ("and", "rd, rs1, rs2"),
+    ("mul", "rd, rs1, rs2"),
+    ("mulh", "rd, rs1, rs2"),
+    ("mulhsu", "rd, rs1, rs2"),
+    ("mulhu", "rd, rs1, rs2"),
+    ("div", "rd, rs1, rs2"),
+    ("divu", "rd, rs1, rs2"),
+    ("rem", "rd, rs1, rs2"),
+    ("remu", "rd, rs1, rs2"),
+    ("addi", "rd, rs1, imm"),
+    ("slli", "rd, rs1, shamt"),
+    ("slti", "rd, rs1, imm"),
+    ("sltiu", "rd, rs1, imm"),
+    ("xori", "rd, rs1, imm"),
+    ("srli", "rd, rs1, shamt"),
+    ("srai", "rd, rs1, shamt"),
+    ("ori", "rd, rs1, imm"),
+    ("andi", "rd, rs1, imm"),
+    ("lui", "rd, imm"),
+    ("auipc", "rd, imm"),
+    ("jal", "rd, offset"),
+    ("jalr", "rd, rs1, offset"),
+    ("beq", "rs1, rs2, offset"),
+    ("bne", "rs1, rs2, offset"),
+    ("blt", "rs1, rs2, offset"),
+    ("bge", "rs1, rs2, offset"),
+    ("bltu", "rs1, rs2, offset"),
+    ("bgeu", "rs1, rs2, offset"),
+    ("lb", "rd, offset(rs1)"),
+    ("lbu", "rd, offset(rs1)"),
+    ("lh", "rd, offset(rs1)"),
+    ("lhu", "rd, offset(rs1)"),
+    ("lw", "rd, offset(rs1)"),
+    ("sb", "rs2, offset(rs1)"),
+    ("sh", "rs2, offset(rs1)"),
+    ("sw", "rs2, offset(rs1)"),
+    ("nop", ""),
+    ("mv", "rd, rs"),
+    ("not", "rd, rs"),
+    ("neg", "rd, rs"),
+    ("seqz", "rd, rs"),
+    ("snez", "rd, rs"),
+    ("sltz", "rd, rs"),
+    ("sgtz", "rd, rs"),
+    ("beqz", "rs, offset"),
+    ("bnez", "rs, offset"),
+    ("blez", "rs, offset"),
+    ("bgez", "rs, offset"),
+    ("bltz", "rs, offset"),
+    ("bgtz", "rs, offset"),
+    ("bgt", "rs, rt, offset"),
+    ("ble", "rs, rt, offset"),
+    ("bleu", "rs, rt, offset"),
+    ("j", "offset"),
+    ("ret", ""),
+    ("auipcz", "rd"),
+    ("li", "rd, imm"),
+    ("la", "rd, symbol"),
+    ("call", "symbol"),
+    ("call_abs", "addr"),
+    ("jump_abs", "addr"),
+];
+
+/// Every mnemonic the assembler accepts, including pseudo-instructions.
+pub fn supported_mnemonics() -> &'static [&'static str] {
+    static MNEMONICS: OnceLock<Vec<&str>> = OnceLock::new();
+    MNEMONICS.get_or_init(|| SIGNATURES.iter().map(|(op, _)| *op).collect())
+}
+
+/// The operands a mnemonic expects, like `rd, rs1, imm` for `addi`, or
+/// `None` if the mnemonic is not supported. Instructions with no operands
+/// expect the empty string.
+pub fn operand_signature(op: &str) -> Option<&'static str> {
+    SIGNATURES
+        .iter()
+        .find(|(name, _)| *name == op)
+        .map(|(_, signature)| *signature)
+}
+
+/// The error for an instruction with the wrong number of operands.
+pub(crate) fn operand_count_error(op: &str) -> AssemblerError {
+    AssemblerError::OperandCountError {
+        op: op.to_owned(),
+        expected: operand_signature(op).unwrap_or_default(),
+    }
+}
+
 /// Match an operation to the correct opcode.
 pub fn match_opcode(op: &str) -> Result<u8, AssemblerError> {
     let opcode = match op {
-        "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "sra" | "or" | "and" => OPCODE_ARITHMETIC,
-        "addi" | "slli" | "slti" | "xori" | "srai" | "ori" | "andi" => OPCODE_ARITHMETIC_IMM,
+        "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and" => {
+            OPCODE_ARITHMETIC
+        }
+        "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" => OPCODE_ARITHMETIC,
+        "addi" | "slli" | "slti" | "sltiu" | "xori" | "srli" | "srai" | "ori" | "andi" => {
+            OPCODE_ARITHMETIC_IMM
+        }
         "lui" => OPCODE_LUI,
         "auipc" => OPCODE_AUIPC,
         "jal" => OPCODE_JAL,
         "jalr" => OPCODE_JALR,
-        "beq" | "bne" | "blt" | "bge" | "bgeu" => OPCODE_BRANCH,
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => OPCODE_BRANCH,
         "lb" | "lbu" | "lh" | "lhu" | "lw" => OPCODE_LOAD,
         "sb" | "sh" | "sw" => OPCODE_STORE,
-        _ => return Err(AssemblerError::InvalidOperationError),
+        _ => return Err(AssemblerError::InvalidOperationError(op.to_owned())),
     };
     Ok(opcode)
 }
 
+/// RV32A mnemonics, which may take a memory ordering suffix.
+static ATOMICS: &[&str] = &[
+    "lr.w",
+    "sc.w",
+    "amoswap.w",
+    "amoadd.w",
+    "amoxor.w",
+    "amoand.w",
+    "amoor.w",
+    "amomin.w",
+    "amomax.w",
+    "amominu.w",
+    "amomaxu.w",
+];
+
+/// Zicsr mnemonics, including the pseudo-instructions for them.
+static CSR_MNEMONICS: &[&str] = &[
+    "csrrw",
+    "csrrs",
+    "csrrc",
+    "csrrwi",
+    "csrrsi",
+    "csrrci",
+    "csrr",
+    "csrw",
+    "csrs",
+    "csrc",
+    "csrwi",
+    "csrsi",
+    "csrci",
+    "rdcycle",
+    "rdtime",
+    "rdinstret",
+];
+
+/// The extension a mnemonic belongs to, like `M` for `mul`, or `None` for
+/// base RV32I mnemonics and mnemonics that are not known. Only the M
+/// extension is assembled; `A` and `Zicsr` mnemonics are recognized so
+/// that they can be reported as such.
+pub fn extension_of(op: &str) -> Option<&'static str> {
+    match op {
+        "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" => Some("M"),
+        _ if CSR_MNEMONICS.contains(&op) => Some("Zicsr"),
+        _ => match split_ordering(op) {
+            Ok((base, _)) if ATOMICS.contains(&base) => Some("A"),
+            Err(_) => Some("A"),
+            _ => None,
+        },
+    }
+}
+
+/// Split the memory ordering suffix, `.aq`, `.rl`, or `.aqrl`, off an
+/// atomic mnemonic, returning the base mnemonic and its `aq` (bit 26) and
+/// `rl` (bit 25) bits. Other mnemonics are returned unchanged, with no
+/// bits set.
+pub fn split_ordering(op: &str) -> Result<(&str, u32), AssemblerError> {
+    for base in ATOMICS {
+        if let Some(suffix) = op.strip_prefix(base) {
+            let bits = match suffix {
+                "" => 0,
+                ".aq" => 1 << 26,
+                ".rl" => 1 << 25,
+                ".aqrl" => (1 << 26) | (1 << 25),
+                // Another mnemonic that shares the prefix.
+                _ if !suffix.starts_with('.') => continue,
+                _ => return Err(AssemblerError::InvalidOrderingError(suffix.to_owned())),
+            };
+            return Ok((base, bits));
+        }
+    }
+    Ok((op, 0))
+}
+
 /// Match a register number or name to its integer number.
 pub fn match_register(reg: &str) -> Result<u8, AssemblerError> {
     if reg.starts_with('x') {
         match reg.strip_prefix('x').unwrap().parse() {
             Ok(n) => Ok(n),
-            Err(_) => Err(AssemblerError::NoSuchRegisterError),
+            Err(_) => Err(AssemblerError::NoSuchRegisterError(reg.to_owned())),
         }
     } else {
         match REG_NAMES.iter().position(|e| *e == reg) {
             Some(n) => Ok(n as u8),
-            None => Err(AssemblerError::NoSuchRegisterError),
+            None => Err(AssemblerError::NoSuchRegisterError(reg.to_owned())),
         }
     }
 }
 
-/// Parse a label or an immediate literal into an integer.
+/// How an instruction interprets its immediate operand.
+///
+/// - `addi`, `slti`, `sltiu`, `xori`, `ori`, `andi`, `jalr`, loads, and
+///   stores take a 12-bit immediate that the hardware always sign-extends,
+///   even for `sltiu` (which then compares unsigned) and the logical
+///   operations. A value is accepted if it is the sign-extension of its low
+///   12 bits, so `-1` and `0xffffffff` both encode as `0xfff`, but `0xfff`
+///   itself is rejected since it would not mean 4095.
+/// - `slli`, `srli`, and `srai` take an unsigned 5-bit shift amount.
+/// - `lui` and `auipc` take an unsigned 20-bit upper immediate.
+/// - Branches take a signed 13-bit offset and `jal` a signed 21-bit
+///   offset, both of which must be even.
+#[derive(Debug, PartialEq)]
+pub enum ImmediateKind {
+    /// A two's-complement immediate with this many bits.
+    Signed(u32),
+    /// An unsigned immediate with this many bits.
+    Unsigned(u32),
+}
+
+/// Match an operation to the interpretation of its immediate, if it has one.
+pub fn match_imm_kind(op: &str) -> Option<ImmediateKind> {
+    match op {
+        "slli" | "srli" | "srai" => Some(ImmediateKind::Unsigned(5)),
+        "lui" | "auipc" => Some(ImmediateKind::Unsigned(20)),
+        "jal" => Some(ImmediateKind::Signed(21)),
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => Some(ImmediateKind::Signed(13)),
+        _ => match match_opcode(op) {
+            Ok(OPCODE_ARITHMETIC_IMM) | Ok(OPCODE_JALR) | Ok(OPCODE_LOAD) | Ok(OPCODE_STORE) => {
+                Some(ImmediateKind::Signed(12))
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Parse an integer literal: decimal, `0x` hexadecimal, or `0b` binary,
+/// with an optional leading `+` or `-`. Returns `None` if `s` is not a
+/// literal.
+pub fn parse_literal(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.chars().next() {
+        Some('-') => (true, &s[1..]),
+        Some('+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let prefix = digits.get(..2).map(|p| p.to_ascii_lowercase());
+    let (radix, digits) = match prefix.as_deref() {
+        Some("0x") => (16, &digits[2..]),
+        Some("0b") => (2, &digits[2..]),
+        _ => (10, digits),
+    };
+    // `from_str_radix` would accept a second sign, even after the prefix.
+    if !digits.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    let value = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+/// The 32-bit value of a literal parsed from `token`. Negative values are
+/// two's complement, so anything from `-2^31` to `2^32 - 1` fits. Larger
+/// values are rejected with `ValueTooLargeError` rather than truncated.
+fn literal_word(value: i64, token: &str) -> Result<u32, AssemblerError> {
+    if value < i32::MIN as i64 || value > u32::MAX as i64 {
+        return Err(AssemblerError::ValueTooLargeError {
+            token: token.to_owned(),
+        });
+    }
+    Ok(value as u32)
+}
+
+/// Parse a label or an immediate literal into an integer. A bare label
+/// evaluates to its offset from `pc`. Anything else is evaluated as an
+/// expression of absolute label addresses and literals. A floating-point
+/// number like `1.0` is rejected with `FloatNotSupportedError`, and a
+/// literal that does not fit in 32 bits with `ValueTooLargeError`.
+/// `%pcrel_hi(expr)` and `%pcrel_lo(expr)` split the offset of `expr`
+/// from an `auipc` into its parts; see `eval_pcrel`.
 pub fn parse_imm(s: &str, labels: &HashMap<String, u32>, pc: u32) -> Result<u32, AssemblerError> {
-    let num = parse_int!(i64, s);
+    if let Some(value) = eval_pcrel(s, labels, pc) {
+        return value;
+    }
+    let num = parse_literal(s);
     match num {
-        Err(_) => {
+        None => {
             let label = labels.get(s);
             if let Some(v) = label {
                 Ok((*v).wrapping_sub(pc))
+            } else if s.contains('.') && s.parse::<f64>().is_ok() {
+                Err(AssemblerError::FloatNotSupportedError {
+                    token: s.to_owned(),
+                })
             } else {
-                Err(AssemblerError::InvalidImmediateError)
+                eval_expr(s, labels)
+            }
+        }
+        Some(d) => literal_word(d, s),
+    }
+}
+
+/// Evaluate a `%pcrel_hi(expr)` or `%pcrel_lo(expr)` operand, or `None`
+/// if `s` is neither. `%pcrel_hi` is the upper part of the offset of `expr`
+/// from `pc`, for an `auipc`. `%pcrel_lo` is the lower part of its offset
+/// from the instruction before `pc`, for the `addi`, load, or store that
+/// follows the `auipc` it pairs with. Either must be the whole operand.
+fn eval_pcrel(
+    s: &str,
+    labels: &HashMap<String, u32>,
+    pc: u32,
+) -> Option<Result<u32, AssemblerError>> {
+    let (group, auipc_pc, split): (_, _, fn(u32) -> u32) =
+        if let Some(group) = s.strip_prefix("%pcrel_hi") {
+            (group, pc, split_hi)
+        } else {
+            (s.strip_prefix("%pcrel_lo")?, pc.wrapping_sub(4), split_lo)
+        };
+    let group = group.trim_start_matches(is_space);
+    if !group.starts_with('(') || !group.ends_with(')') {
+        return Some(Err(AssemblerError::InvalidImmediateError(s.to_owned())));
+    }
+    let target = eval_expr(&group[1..group.len() - 1], labels);
+    Some(target.map(|target| split(target.wrapping_sub(auipc_pc))))
+}
+
+/// Check a register operand as the assembler does, for validating input
+/// as it is typed. Operands are lowercased by `tokenize` first, so `T0` is
+/// as valid as `t0`.
+///
+/// Parameters:
+///     `token: &str`: The operand, like `t0` or `x5`
+///
+/// Returns:
+///     `bool`: True if `token` is an ABI name, or `x0` to `x31`.
+pub fn is_valid_register(token: &str) -> bool {
+    matches!(match_register(&token.to_ascii_lowercase()), Ok(reg) if reg < 32)
+}
+
+/// Check an immediate operand as the assembler does, for validating input
+/// as it is typed. The operand is parsed by `parse_imm` with no labels, so
+/// literals and expressions of them are accepted with the same errors.
+///
+/// Parameters:
+///     `token: &str`: The operand, like `-4` or `0x12345`
+///     `bits: u32`: The width of the immediate field, from 1 to 32
+///     `signed: bool`: True if the field is two's complement, like the
+///     12-bit immediate of `addi`; false if it is unsigned, like the 20-bit
+///     immediate of `lui`
+///
+/// Returns:
+///     `Result<i64, AssemblerError>`: The value, or `ImmediateTooLargeError`
+///     if it does not fit the field or the field has no valid width.
+pub fn validate_immediate(token: &str, bits: u32, signed: bool) -> Result<i64, AssemblerError> {
+    if bits == 0 || bits > 32 {
+        return Err(AssemblerError::ImmediateTooLargeError);
+    }
+    let value = parse_imm(&token.to_ascii_lowercase(), &HashMap::new(), 0)?;
+    let fits = match (signed, bits) {
+        // Every 32-bit value fits a 32-bit field, either way it is read.
+        (_, 32) => true,
+        (true, _) => fits_signed(value, bits),
+        (false, _) => value < (1 << bits),
+    };
+    match (fits, signed) {
+        (true, true) => Ok(value as i32 as i64),
+        (true, false) => Ok(value as i64),
+        (false, _) => Err(AssemblerError::ImmediateTooLargeError),
+    }
+}
+
+/// Evaluate an expression of literals and labels, with binary and unary
+/// `+` and `-` and parentheses. Labels evaluate to their absolute address
+/// and arithmetic wraps. `%hi(expr)` and `%lo(expr)` split a value into
+/// parts for `lui` and a following I- or S-type immediate, so that
+/// `(%hi(x) << 12) + %lo(x) == x`.
+pub fn eval_expr(expr: &str, labels: &HashMap<String, u32>) -> Result<u32, AssemblerError> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut pos = 0;
+    let value = eval_sum(&chars, &mut pos, labels)?;
+    skip_whitespace(&chars, &mut pos);
+    if pos != chars.len() {
+        return Err(invalid_immediate(&chars));
+    }
+    Ok(value)
+}
+
+/// The error for an expression that can't be evaluated, with its text.
+fn invalid_immediate(chars: &[char]) -> AssemblerError {
+    AssemblerError::InvalidImmediateError(chars.iter().collect())
+}
+
+/// Advance `pos` past any whitespace.
+fn skip_whitespace(chars: &[char], pos: &mut usize) {
+    while *pos < chars.len() && is_space(chars[*pos]) {
+        *pos += 1;
+    }
+}
+
+/// Evaluate terms joined by `+` and `-`.
+fn eval_sum(
+    chars: &[char],
+    pos: &mut usize,
+    labels: &HashMap<String, u32>,
+) -> Result<u32, AssemblerError> {
+    let mut value = eval_term(chars, pos, labels)?;
+    skip_whitespace(chars, pos);
+    while *pos < chars.len() {
+        match chars[*pos] {
+            '+' => {
+                *pos += 1;
+                value = value.wrapping_add(eval_term(chars, pos, labels)?);
+            }
+            '-' => {
+                *pos += 1;
+                value = value.wrapping_sub(eval_term(chars, pos, labels)?);
+            }
+            _ => break,
+        }
+        skip_whitespace(chars, pos);
+    }
+    Ok(value)
+}
+
+/// Evaluate a parenthesized expression starting at the `(`.
+fn eval_group(
+    chars: &[char],
+    pos: &mut usize,
+    labels: &HashMap<String, u32>,
+) -> Result<u32, AssemblerError> {
+    *pos += 1;
+    let value = eval_sum(chars, pos, labels)?;
+    if chars.get(*pos) != Some(&')') {
+        return Err(invalid_immediate(chars));
+    }
+    *pos += 1;
+    Ok(value)
+}
+
+/// Split a value into the parts a `lui`/`auipc` and an `addi` (or load,
+/// store, or `jalr`) build it from, exactly as the `li`, `la`, `call`,
+/// `call_abs`, and `jump_abs` pseudo-instructions and the `%hi`/`%lo` and
+/// `%pcrel_hi`/`%pcrel_lo` modifiers do. The lower 12 bits are
+/// sign-extended when added, so when bit 11 is set the upper part is
+/// rounded up by one to carry it back.
+///
+/// Parameters:
+///     `value: u32`: The value to build
+///
+/// Returns:
+///     `(u32, i32)`: The upper 20 bits, and the signed lower 12 bits, such
+///     that `(hi << 12).wrapping_add(lo as u32) == value`.
+pub fn split_hi_lo(value: u32) -> (u32, i32) {
+    let lo = ((value << 20) as i32) >> 20;
+    let hi = value.wrapping_sub(lo as u32) >> 12;
+    (hi, lo)
+}
+
+/// The upper 20 bits of a value for `lui`/`auipc`. See `split_hi_lo`.
+pub(crate) fn split_hi(value: u32) -> u32 {
+    split_hi_lo(value).0
+}
+
+/// The lower 12 bits of a value, sign-extended, to add to `split_hi`.
+pub(crate) fn split_lo(value: u32) -> u32 {
+    split_hi_lo(value).1 as u32
+}
+
+/// Evaluate a signed term, parenthesized expression, `%hi`/`%lo`
+/// modifier, literal, or label.
+fn eval_term(
+    chars: &[char],
+    pos: &mut usize,
+    labels: &HashMap<String, u32>,
+) -> Result<u32, AssemblerError> {
+    skip_whitespace(chars, pos);
+    match chars.get(*pos) {
+        Some('+') => {
+            *pos += 1;
+            eval_term(chars, pos, labels)
+        }
+        Some('-') => {
+            *pos += 1;
+            Ok(eval_term(chars, pos, labels)?.wrapping_neg())
+        }
+        Some('(') => eval_group(chars, pos, labels),
+        Some('%') => {
+            *pos += 1;
+            let start = *pos;
+            while *pos < chars.len() && chars[*pos].is_alphanumeric() {
+                *pos += 1;
+            }
+            let modifier: String = chars[start..*pos].iter().collect();
+            skip_whitespace(chars, pos);
+            if chars.get(*pos) != Some(&'(') {
+                return Err(invalid_immediate(chars));
+            }
+            let value = eval_group(chars, pos, labels)?;
+            match &modifier[..] {
+                "hi" => Ok(split_hi(value)),
+                "lo" => Ok(split_lo(value)),
+                _ => Err(invalid_immediate(chars)),
+            }
+        }
+        _ => {
+            let start = *pos;
+            while *pos < chars.len()
+                && (chars[*pos].is_alphanumeric() || "_.$".contains(chars[*pos]))
+            {
+                *pos += 1;
+            }
+            let atom: String = chars[start..*pos].iter().collect();
+            if atom.is_empty() {
+                return Err(invalid_immediate(chars));
+            }
+            if let Some(d) = parse_literal(&atom) {
+                return literal_word(d, &atom);
+            }
+            match labels.get(&atom) {
+                Some(v) => Ok(*v),
+                None if atom.starts_with(|c: char| c.is_ascii_digit()) => {
+                    Err(invalid_immediate(chars))
+                }
+                None => Err(AssemblerError::NoSuchLabelError(atom)),
             }
         }
-        Ok(d) => Ok(d as u32),
     }
 }
 
@@ -73,6 +730,7 @@ pub fn parse_imm(s: &str, labels: &HashMap<String, u32>, pc: u32) -> Result<u32,
 macro_rules! match_func3 {
     ($t:expr) => {
         match $t {
+            "jalr" => 0b000,
             "beq" => FUNC3_BEQ,
             "bne" => FUNC3_BNE,
             "blt" => FUNC3_BLT,
@@ -90,11 +748,19 @@ macro_rules! match_func3 {
             "add" | "addi" | "sub" => FUNC3_ADD_SUB,
             "sll" | "slli" => FUNC3_SLL,
             "slt" | "slti" => FUNC3_SLT,
-            "sltu" => FUNC3_SLTU,
+            "sltu" | "sltiu" => FUNC3_SLTU,
             "xor" | "xori" => FUNC3_XOR,
             "sra" | "srai" | "srl" | "srli" => FUNC3_SR,
             "or" | "ori" => FUNC3_OR,
             "and" | "andi" => FUNC3_AND,
+            "mul" => 0b000,
+            "mulh" => 0b001,
+            "mulhsu" => 0b010,
+            "mulhu" => 0b011,
+            "div" => 0b100,
+            "divu" => 0b101,
+            "rem" => 0b110,
+            "remu" => 0b111,
             _ => unreachable!(),
         }
     };
@@ -105,10 +771,13 @@ macro_rules! match_func3 {
 macro_rules! match_func7 {
     ($t:expr) => {
         match $t {
-            "add" | "addi" => FUNC7_ADD,
-            "sub" => FUNC7_SUB,
-            "sra" | "srai" => FUNC7_SRA,
-            "srl" | "srli" => FUNC7_SRL,
+            // The common crate's `FUNC7_SRA` and `FUNC7_SRL` are swapped
+            // relative to the spec, so shifts share the add/sub values.
+            "add" | "addi" | "sll" | "slt" | "sltu" | "xor" | "srl" | "srli" | "or" | "and" => {
+                FUNC7_ADD
+            }
+            "sub" | "sra" | "srai" => FUNC7_SUB,
+            "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" => 0b0000001,
             _ => unreachable!(),
         }
     };
@@ -0,0 +1,750 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, Write},
+    ops::Range,
+};
+
+use crate::{
+    assembler::{instruction_format, InstructionFormat},
+    diagnostic::Diagnostic,
+    disassembler::disassemble,
+    elf::write_elf,
+};
+
+/// The tab width `Program::to_source_listing` is usually given, matching
+/// most terminals and editors.
+pub const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// Replace each tab in `line` with spaces up to the next multiple of
+/// `width` columns, so the line looks the same wherever it starts.
+fn expand_tabs(line: &str, width: usize) -> String {
+    let width = width.max(1);
+    let mut out = String::new();
+    let mut column = 0;
+    for c in line.chars() {
+        if c == '\t' {
+            let spaces = width - column % width;
+            out += &" ".repeat(spaces);
+            column += spaces;
+        } else {
+            out.push(c);
+            column += 1;
+        }
+    }
+    out
+}
+
+/// Format words as text, one unsigned decimal value per line, for tools
+/// that read numbers rather than hex.
+pub fn to_decimal_lines(words: &[u32]) -> String {
+    words.iter().map(|w| format!("{}\n", w)).collect()
+}
+
+/// The length of the header `to_loader_image` puts before the image.
+pub const LOADER_HEADER_LEN: usize = 12;
+
+/// Format a program for a minimal loader: a header of `magic`, the entry
+/// point, and the length of the image in bytes, each a little-endian
+/// `u32`, followed by the image as little-endian bytes.
+///
+/// Parameters:
+///     `program: &Program`: The program
+///     `magic: u32`: The number the loader checks for
+///
+/// Returns:
+///     `Vec<u8>`: The header and the image. The entry point is the one
+///     given with `Assembler::entry`, or `base` if there is none.
+pub fn to_loader_image(program: &Program, magic: u32) -> Vec<u8> {
+    let image = program.to_bytes(Endian::Little);
+    let entry = program.entry.unwrap_or(program.base);
+    let mut out = Vec::with_capacity(LOADER_HEADER_LEN + image.len());
+    out.extend_from_slice(&magic.to_le_bytes());
+    out.extend_from_slice(&entry.to_le_bytes());
+    out.extend_from_slice(&(image.len() as u32).to_le_bytes());
+    out.extend(image);
+    out
+}
+
+/// How a relocated word refers to its symbol. Label operands are always
+/// encoded as an offset from the address of the instruction using them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocationKind {
+    /// PC-relative offset in a B-type immediate.
+    Branch,
+    /// PC-relative offset in a J-type immediate.
+    Jal,
+    /// PC-relative offset in a U-type immediate.
+    PcrelHi20,
+    /// PC-relative offset in an I-type immediate.
+    PcrelLo12I,
+    /// PC-relative offset in an S-type immediate.
+    PcrelLo12S,
+}
+
+/// Byte order of words in a binary. RISC-V instructions are always
+/// little-endian in memory, but data buses and tools sometimes want words
+/// byte-swapped.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endian {
+    /// The bytes of `word` in this order.
+    pub fn word_bytes(self, word: u32) -> [u8; 4] {
+        match self {
+            Endian::Little => word.to_le_bytes(),
+            Endian::Big => word.to_be_bytes(),
+        }
+    }
+}
+
+/// The width of one memory location in a memory initialization file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemWidth {
+    Byte,
+    Half,
+    Word,
+}
+
+impl MemWidth {
+    /// The number of bytes in one memory location.
+    pub fn bytes(self) -> usize {
+        match self {
+            MemWidth::Byte => 1,
+            MemWidth::Half => 2,
+            MemWidth::Word => 4,
+        }
+    }
+}
+
+/// How listings print an unconditional jump, `jal zero, offset`. Both
+/// encode the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JumpStyle {
+    /// `jal zero, offset`, as it is encoded.
+    #[default]
+    Jal,
+    /// The `j offset` pseudo-instruction.
+    J,
+}
+
+/// The base listings print an immediate in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Radix {
+    /// Decimal, like `-4`.
+    Decimal,
+    /// Hex, like `0x12345`, or `-0x4` for a negative immediate.
+    Hex,
+}
+
+/// How listings print immediates, by kind. The default matches objdump:
+/// signed decimal for the 12-bit immediates and offsets of loads, stores,
+/// arithmetic, branches, and jumps, and hex for the upper immediates of
+/// `lui` and `auipc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImmediateFormat {
+    /// The radix of sign-extended immediates and offsets.
+    pub signed: Radix,
+    /// The radix of `lui` and `auipc` upper immediates.
+    pub upper: Radix,
+}
+
+impl Default for ImmediateFormat {
+    fn default() -> Self {
+        ImmediateFormat {
+            signed: Radix::Decimal,
+            upper: Radix::Hex,
+        }
+    }
+}
+
+/// An output format that `Program::write` can stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Raw bytes, each word little-endian.
+    LeBytes,
+    /// Raw bytes, each word big-endian.
+    BeBytes,
+    /// Intel HEX, with 16 data bytes per record.
+    IntelHex,
+    /// Verilog `$readmemh`, one memory location of the given width per line.
+    Readmemh(MemWidth),
+    /// Motorola S-records, with 32-bit addresses and 16 data bytes per
+    /// record.
+    Srec,
+    /// A listing, one word per line with its address and disassembly, and
+    /// the target of each branch, jump, or `auipc`.
+    /// Words that are not instructions are listed as `.word`.
+    Listing,
+}
+
+/// Write chunks of bytes, each starting at an address, as Intel HEX data
+/// records with an extended linear address record wherever the upper 16
+/// address bits change, and an end of file record.
+fn write_ihex<'a, W: Write>(
+    out: &mut W,
+    chunks: impl IntoIterator<Item = (u32, &'a [u8])>,
+) -> io::Result<()> {
+    // The upper address bits start at zero.
+    let mut upper = 0;
+    for (base, bytes) in chunks {
+        let mut i = 0;
+        while i < bytes.len() {
+            let addr = base.wrapping_add(i as u32);
+            if addr >> 16 != upper {
+                upper = addr >> 16;
+                write_ihex_record(out, 4, 0, &(upper as u16).to_be_bytes())?;
+            }
+            // Records may not cross into the next 64 KiB.
+            let len = (bytes.len() - i)
+                .min(16)
+                .min(0x10000 - (addr & 0xffff) as usize);
+            write_ihex_record(out, 0, addr as u16, &bytes[i..i + len])?;
+            i += len;
+        }
+    }
+    write_ihex_record(out, 1, 0, &[])
+}
+
+/// Write chunks of bytes, each starting at an address, as S-records: an
+/// empty header, data records, and a termination record giving `entry`.
+fn write_srec<'a, W: Write>(
+    out: &mut W,
+    chunks: impl IntoIterator<Item = (u32, &'a [u8])>,
+    entry: u32,
+) -> io::Result<()> {
+    write_srec_record(out, 0, 0, 2, &[])?;
+    for (base, bytes) in chunks {
+        for (i, chunk) in bytes.chunks(16).enumerate() {
+            let addr = base.wrapping_add(16 * i as u32);
+            write_srec_record(out, 3, addr, 4, chunk)?;
+        }
+    }
+    write_srec_record(out, 7, entry, 4, &[])
+}
+
+/// The fields of an instruction's encoding, from bit 31 down, as their
+/// name and the bits they span, or `None` if the opcode is unknown.
+fn encoding_fields(word: u32) -> Option<&'static [(&'static str, u32, u32)]> {
+    const RS2: (&str, u32, u32) = ("rs2", 24, 20);
+    const RS1: (&str, u32, u32) = ("rs1", 19, 15);
+    const FUNC3: (&str, u32, u32) = ("func3", 14, 12);
+    const RD: (&str, u32, u32) = ("rd", 11, 7);
+    const OPCODE: (&str, u32, u32) = ("opcode", 6, 0);
+    Some(match instruction_format((word & 0x7f) as u8)? {
+        InstructionFormat::Rtype => &[("func7", 31, 25), RS2, RS1, FUNC3, RD, OPCODE],
+        InstructionFormat::Itype => &[("imm[11:0]", 31, 20), RS1, FUNC3, RD, OPCODE],
+        InstructionFormat::Stype => &[
+            ("imm[11:5]", 31, 25),
+            RS2,
+            RS1,
+            FUNC3,
+            ("imm[4:0]", 11, 7),
+            OPCODE,
+        ],
+        InstructionFormat::Btype => &[
+            ("imm[12|10:5]", 31, 25),
+            RS2,
+            RS1,
+            FUNC3,
+            ("imm[4:1|11]", 11, 7),
+            OPCODE,
+        ],
+        InstructionFormat::Utype => &[("imm[31:12]", 31, 12), RD, OPCODE],
+        InstructionFormat::Jtype => &[("imm[20|10:1|11|19:12]", 31, 12), RD, OPCODE],
+    })
+}
+
+/// Write an Intel HEX record.
+fn write_ihex_record<W: Write>(out: &mut W, kind: u8, addr: u16, data: &[u8]) -> io::Result<()> {
+    let mut sum = (data.len() as u8)
+        .wrapping_add((addr >> 8) as u8)
+        .wrapping_add(addr as u8)
+        .wrapping_add(kind);
+    write!(out, ":{:02X}{:04X}{:02X}", data.len(), addr, kind)?;
+    for b in data {
+        sum = sum.wrapping_add(*b);
+        write!(out, "{:02X}", b)?;
+    }
+    writeln!(out, "{:02X}", sum.wrapping_neg())
+}
+
+/// Write an S-record of type `kind` with a 2- or 4-byte address.
+fn write_srec_record<W: Write>(
+    out: &mut W,
+    kind: u8,
+    addr: u32,
+    addr_len: usize,
+    data: &[u8],
+) -> io::Result<()> {
+    let addr_bytes = &addr.to_be_bytes()[4 - addr_len..];
+    let count = (addr_len + data.len() + 1) as u8;
+    let mut sum = count;
+    write!(out, "S{}{:02X}", kind, count)?;
+    for b in addr_bytes.iter().chain(data) {
+        sum = sum.wrapping_add(*b);
+        write!(out, "{:02X}", b)?;
+    }
+    writeln!(out, "{:02X}", !sum)
+}
+
+/// A reference from an assembled word to a symbol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    /// Address of the word containing the reference.
+    pub address: u32,
+    /// Name of the referenced symbol.
+    pub symbol: String,
+    /// How the word encodes the reference.
+    pub kind: RelocationKind,
+}
+
+/// The kind of thing a symbol names, as set with `.type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolType {
+    /// `@notype`.
+    NoType,
+    /// `@object`, a data object.
+    Object,
+    /// `@function`, code.
+    Function,
+}
+
+/// A place in the original source of generated assembly, given with `.file`
+/// and `.line`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLocation {
+    /// The file named by the last `.file` before the `.line`, if any.
+    pub file: Option<String>,
+    pub line: u32,
+}
+
+/// An assembled program and everything learned about it while assembling.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    /// Assembled words, starting at `base`.
+    pub words: Vec<u32>,
+    /// The address of the first word.
+    pub base: u32,
+    /// Address of every label, ordered by name so that iterating it gives
+    /// the same order on every run.
+    pub symbols: BTreeMap<String, u32>,
+    /// Value of every constant defined with `.equ`, `.set`, or `=`. These
+    /// are not labels, so they are not in `symbols`.
+    pub constants: HashMap<String, u32>,
+    /// Register number of every alias defined like a constant whose value
+    /// is a register, like `.equ counter, t0`.
+    pub register_aliases: HashMap<String, u8>,
+    /// The address of the entry point given with `Assembler::entry`.
+    pub entry: Option<u32>,
+    /// Labels declared with `.globl` or `.global`.
+    pub globals: HashSet<String>,
+    /// The type of every symbol given one with `.type`.
+    pub symbol_types: HashMap<String, SymbolType>,
+    /// The size in bytes of every symbol given one with `.size`.
+    pub symbol_sizes: HashMap<String, u32>,
+    /// Address ranges laid out by `.word`, `.half`, `.byte`, and
+    /// `Assembler::insert_bytes`, in order.
+    pub data: Vec<Range<u32>>,
+    /// Address ranges of the words filled in by `.org`, `.align`, and
+    /// `.p2align` to skip ahead, in order.
+    pub gaps: Vec<Range<u32>>,
+    /// Every word that refers to a label.
+    pub relocations: Vec<Relocation>,
+    /// File names given with `.file`, in order.
+    pub files: Vec<String>,
+    /// The address of every `.line`, in order, with the location it gives
+    /// for the words from there on. See `Program::source_location`.
+    pub source_lines: Vec<(u32, SourceLocation)>,
+    /// The zero-based source line that produced each word. Words from
+    /// `Assembler::insert_bytes` map to the line they were inserted before.
+    pub line_map: Vec<usize>,
+    /// The byte range of source that produced each word, without labels
+    /// or comments. Offsets count through every inserted source as if they
+    /// were joined by newlines, and words from `Assembler::insert_bytes`
+    /// get an empty range where they were inserted.
+    pub spans: Vec<Range<usize>>,
+    /// Warnings and info messages reported while assembling, in source
+    /// order.
+    pub diagnostics: Vec<Diagnostic>,
+    /// How listings print unconditional jumps, from `Assembler::jump_style`.
+    pub jump_style: JumpStyle,
+    /// How listings print immediates, from `Assembler::immediate_format`.
+    pub immediate_format: ImmediateFormat,
+    /// Byte order of instruction words in `Program::to_image`, from
+    /// `Assembler::instruction_endian`.
+    pub instruction_endian: Endian,
+    /// Byte order of data words in `Program::to_image`, from
+    /// `Assembler::data_endian`.
+    pub data_endian: Endian,
+}
+
+impl Program {
+    /// Consume the program, keeping only its words.
+    pub fn into_words(self) -> Vec<u32> {
+        self.words
+    }
+
+    /// The program as bytes, each word in `endian` order. The words are
+    /// the same on every host, so this is byte-exact regardless of the
+    /// host's own endianness.
+    pub fn to_bytes(&self, endian: Endian) -> Vec<u8> {
+        self.words
+            .iter()
+            .flat_map(|w| endian.word_bytes(*w))
+            .collect()
+    }
+
+    /// The program as bytes, with instruction words in
+    /// `instruction_endian` order and data words in `data_endian` order. A
+    /// word is data if its address is in one of the `data` ranges; padding
+    /// from `.org` and `.align` counts as instructions.
+    ///
+    /// | Instructions     | Data             | Image                                            |
+    /// |------------------|------------------|--------------------------------------------------|
+    /// | `Endian::Little` | `Endian::Little` | Standard RISC-V, the same as `to_bytes(Little)`  |
+    /// | `Endian::Big`    | `Endian::Big`    | Every word swapped, the same as `to_bytes(Big)`  |
+    /// | `Endian::Big`    | `Endian::Little` | For cores that fetch big-endian but load little  |
+    /// | `Endian::Little` | `Endian::Big`    | For big-endian data buses with standard fetch    |
+    ///
+    /// Data words are swapped whole, so bytes from `.byte` and `.half`
+    /// move within their word like the bytes of a `.word` do.
+    pub fn to_image(&self) -> Vec<u8> {
+        self.words
+            .iter()
+            .enumerate()
+            .flat_map(|(i, word)| {
+                let addr = self.base.wrapping_add(4 * i as u32);
+                let endian = if self.data.iter().any(|r| r.contains(&addr)) {
+                    self.data_endian
+                } else {
+                    self.instruction_endian
+                };
+                endian.word_bytes(*word)
+            })
+            .collect()
+    }
+
+    /// The program as text, one word per line as hex bytes in `endian`
+    /// order. `Endian::Big` prints each word as its value.
+    pub fn to_hex(&self, endian: Endian) -> String {
+        self.words
+            .iter()
+            .map(|w| {
+                let hex: String = endian
+                    .word_bytes(*w)
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                hex + "\n"
+            })
+            .collect()
+    }
+
+    /// Split each word into little-endian memory locations of `width`,
+    /// zero-extended to `u32`.
+    fn locations(&self, width: MemWidth) -> Vec<u32> {
+        self.to_bytes(Endian::Little)
+            .chunks(width.bytes())
+            .map(|c| c.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u32))
+            .collect()
+    }
+
+    /// The program for Verilog's `$readmemh`, one memory location of
+    /// `width` per line. Each word is preceded by a comment with its
+    /// address in units of `width`.
+    pub fn to_readmemh(&self, width: MemWidth) -> String {
+        self.to_text(OutputFormat::Readmemh(width))
+    }
+
+    /// The program as Intel HEX records, starting at `base`. Extended
+    /// linear address records give the upper 16 bits of addresses.
+    pub fn to_ihex(&self) -> String {
+        self.to_text(OutputFormat::IntelHex)
+    }
+
+    /// The program as Motorola S-records, starting at `base`, with an
+    /// empty header and a termination record giving the entry point: the
+    /// one given with `Assembler::entry`, or `base` if there is none.
+    pub fn to_srec(&self) -> String {
+        self.to_text(OutputFormat::Srec)
+    }
+
+    /// The program as contiguous chunks of little-endian bytes, keyed by
+    /// their start address, leaving out the `gaps` skipped by `.org` and
+    /// `.align` instead of filling them, so only memory the program uses
+    /// needs to be written.
+    pub fn to_sparse(&self) -> BTreeMap<u32, Vec<u8>> {
+        let mut chunks = BTreeMap::new();
+        let mut chunk: Option<(u32, Vec<u8>)> = None;
+        for (i, word) in self.words.iter().enumerate() {
+            let addr = self.base.wrapping_add(4 * i as u32);
+            if self.gaps.iter().any(|gap| gap.contains(&addr)) {
+                chunks.extend(chunk.take());
+                continue;
+            }
+            chunk
+                .get_or_insert_with(|| (addr, Vec::new()))
+                .1
+                .extend_from_slice(&word.to_le_bytes());
+        }
+        chunks.extend(chunk);
+        chunks
+    }
+
+    /// Like `to_ihex`, but with records only for the chunks of `to_sparse`.
+    pub fn to_ihex_sparse(&self) -> String {
+        let mut out = Vec::new();
+        let chunks = self.to_sparse();
+        // Writing to a `Vec` cannot fail, and Intel HEX is ASCII.
+        write_ihex(&mut out, chunks.iter().map(|(addr, b)| (*addr, &b[..]))).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Like `to_srec`, but with records only for the chunks of `to_sparse`.
+    pub fn to_srec_sparse(&self) -> String {
+        let mut out = Vec::new();
+        let chunks = self.to_sparse();
+        // Writing to a `Vec` cannot fail, and S-records are ASCII.
+        write_srec(
+            &mut out,
+            chunks.iter().map(|(addr, b)| (*addr, &b[..])),
+            self.entry.unwrap_or(self.base),
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// The location in the original source, from `.file` and `.line`, of
+    /// the word at `addr`: the last `.line` at or before it.
+    pub fn source_location(&self, addr: u32) -> Option<&SourceLocation> {
+        self.source_lines
+            .iter()
+            .rev()
+            .find(|(at, _)| *at <= addr)
+            .map(|(_, location)| location)
+    }
+
+    /// The first label, in order, at `addr`, if any.
+    pub(crate) fn label_at(&self, addr: u32) -> Option<&str> {
+        self.symbols
+            .iter()
+            .filter(|(_, a)| **a == addr)
+            .map(|(label, _)| &label[..])
+            .min()
+    }
+
+    /// The program as a listing, one word per line with its address, its
+    /// value, and what it disassembles to. Addresses start at `base`, and
+    /// branches and jumps are followed by the address they go to, and
+    /// `auipc` by the address it computes, with the label there, if any.
+    pub fn to_listing(&self) -> String {
+        self.to_text(OutputFormat::Listing)
+    }
+
+    /// A listing for seeing how instructions encode: `to_listing`, with
+    /// each instruction followed by a line breaking the word into the
+    /// fields of its format, from bit 31 down, each labeled and in binary,
+    /// like `func7=0000000 rs2=00111 ...`. Split immediates are labeled
+    /// with the immediate bits they hold, like `imm[4:1|11]`.
+    pub fn to_field_listing(&self) -> String {
+        let mut out = String::new();
+        for (line, word) in self.to_listing().lines().zip(&self.words) {
+            out += line;
+            out.push('\n');
+            if disassemble(*word).is_err() {
+                continue;
+            }
+            if let Some(fields) = encoding_fields(*word) {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, hi, lo)| {
+                        let bits = (word >> lo) & ((1u64 << (hi - lo + 1)) - 1) as u32;
+                        format!("{}={:0w$b}", name, bits, w = (hi - lo + 1) as usize)
+                    })
+                    .collect();
+                out += &format!("{:10}{}\n", "", fields.join(" "));
+            }
+        }
+        out
+    }
+
+    /// A listing beside the source the program was assembled from, one word
+    /// per line with its address and value. The first word from each source
+    /// line is followed by that line, with tabs expanded to stops every
+    /// `tab_width` columns, usually `DEFAULT_TAB_WIDTH`, so the source does
+    /// not push the columns out of line. `source` is the text given to the
+    /// assembler, with several inserted sources joined by newlines.
+    pub fn to_source_listing(&self, source: &str, tab_width: usize) -> String {
+        let lines: Vec<&str> = source.split('\n').collect();
+        let mut out = String::new();
+        let mut last_line = None;
+        for (i, word) in self.words.iter().enumerate() {
+            let addr = self.base.wrapping_add(4 * i as u32);
+            out += &format!("{:08x}: {:08x}", addr, word);
+            // Inserted bytes have an empty span and no source of their own.
+            let line = self.line_map.get(i).filter(|_| !self.spans[i].is_empty());
+            if let Some(text) = line
+                .filter(|l| Some(**l) != last_line)
+                .and_then(|l| lines.get(*l))
+            {
+                let text = expand_tabs(text, tab_width);
+                out += "  ";
+                out += text.trim_end();
+                last_line = line.copied();
+            }
+            out += "\n";
+        }
+        out
+    }
+
+    /// Write a text format to a string.
+    fn to_text(&self, format: OutputFormat) -> String {
+        let mut out = Vec::new();
+        // Writing to a `Vec` cannot fail, and every text format is ASCII.
+        self.write(&mut out, format).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    /// Stream the program to `out` in `format`, one word or record at a
+    /// time. Each format writes exactly what its `to_*` method returns.
+    pub fn write<W: Write>(&self, out: &mut W, format: OutputFormat) -> io::Result<()> {
+        match format {
+            OutputFormat::LeBytes | OutputFormat::BeBytes => {
+                let endian = match format {
+                    OutputFormat::LeBytes => Endian::Little,
+                    _ => Endian::Big,
+                };
+                for word in &self.words {
+                    out.write_all(&endian.word_bytes(*word))?;
+                }
+            }
+            OutputFormat::Readmemh(width) => {
+                let per_word = 4 / width.bytes();
+                let digits = width.bytes() * 2;
+                for (i, location) in self.locations(width).iter().enumerate() {
+                    if i % per_word == 0 {
+                        writeln!(out, "// 0x{:08x}", i)?;
+                    }
+                    writeln!(out, "{:0digits$x}", location, digits = digits)?;
+                }
+            }
+            OutputFormat::IntelHex => {
+                write_ihex(out, [(self.base, &self.to_bytes(Endian::Little)[..])])?;
+            }
+            OutputFormat::Srec => {
+                let bytes = self.to_bytes(Endian::Little);
+                let entry = self.entry.unwrap_or(self.base);
+                write_srec(out, [(self.base, &bytes[..])], entry)?;
+            }
+            OutputFormat::Listing => {
+                for (i, word) in self.words.iter().enumerate() {
+                    let addr = self.base.wrapping_add(4 * i as u32);
+                    match disassemble(*word) {
+                        Ok(instruction) => {
+                            write!(
+                                out,
+                                "{:08x}: {:08x}  {}",
+                                addr,
+                                word,
+                                instruction.to_string_with(self.jump_style, self.immediate_format)
+                            )?;
+                            if let Some(target) = instruction.target(addr) {
+                                write!(out, "  # {:#x}", target)?;
+                                if let Some(label) = self.label_at(target) {
+                                    write!(out, " <{}>", label)?;
+                                }
+                            }
+                            writeln!(out)?
+                        }
+                        Err(_) => {
+                            writeln!(out, "{:08x}: {:08x}  .word 0x{:08x}", addr, word, word)?
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The program as a Xilinx COE file, one memory location of `width`
+    /// per entry.
+    pub fn to_coe(&self, width: MemWidth) -> String {
+        let digits = width.bytes() * 2;
+        let entries: Vec<String> = self
+            .locations(width)
+            .iter()
+            .map(|l| format!("{:0digits$x}", l, digits = digits))
+            .collect();
+        format!(
+            "memory_initialization_radix=16;\nmemory_initialization_vector=\n{};\n",
+            entries.join(",\n")
+        )
+    }
+
+    /// The program as an Intel MIF file, one memory location of `width`
+    /// per address.
+    pub fn to_mif(&self, width: MemWidth) -> String {
+        let digits = width.bytes() * 2;
+        let locations = self.locations(width);
+        let mut out = format!(
+            "WIDTH={};\nDEPTH={};\nADDRESS_RADIX=HEX;\nDATA_RADIX=HEX;\nCONTENT BEGIN\n",
+            width.bytes() * 8,
+            locations.len()
+        );
+        for (i, location) in locations.iter().enumerate() {
+            out += &format!("    {:x} : {:0digits$x};\n", i, location, digits = digits);
+        }
+        out + "END;\n"
+    }
+
+    /// A symbol map, one `address name` line per label with the address in
+    /// hex, in order of address and then name. Labels that are not global
+    /// are only included if `locals` is true.
+    pub fn to_symbol_map(&self, locals: bool) -> String {
+        let mut symbols: Vec<(&u32, &String)> = self
+            .symbols
+            .iter()
+            .filter(|(name, _)| locals || self.globals.contains(*name))
+            .map(|(name, addr)| (addr, name))
+            .collect();
+        symbols.sort();
+        symbols
+            .iter()
+            .map(|(addr, name)| format!("{:08x} {}\n", addr, name))
+            .collect()
+    }
+
+    /// The program as CSV, with a header row and then one row per word:
+    /// its index, its address, its value in hex, and its mnemonic and
+    /// operands as disassembled. Words of data, and words that are not
+    /// instructions, have the mnemonic `.word` and their value as the
+    /// operand.
+    pub fn to_csv(&self) -> String {
+        let mut out = "index,address,hex,mnemonic,operands\n".to_owned();
+        for (i, word) in self.words.iter().enumerate() {
+            let addr = self.base.wrapping_add(4 * i as u32);
+            let is_data = self.data.iter().any(|r| r.contains(&addr));
+            let text = match disassemble(*word) {
+                Ok(instruction) if !is_data => instruction.to_string(),
+                _ => format!(".word 0x{:08x}", word),
+            };
+            let (mnemonic, operands) = text.split_once(' ').unwrap_or((&text, ""));
+            // Operands are separated by commas, so they are quoted.
+            out += &format!(
+                "{},0x{:08x},{:08x},{},\"{}\"\n",
+                i, addr, word, mnemonic, operands
+            );
+        }
+        out
+    }
+
+    /// The program as a 32-bit little-endian RISC-V ELF executable with a
+    /// `.text` section and a symbol table, loaded at `base`.
+    pub fn to_elf(&self) -> Vec<u8> {
+        write_elf(self)
+    }
+}
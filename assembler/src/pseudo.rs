@@ -0,0 +1,183 @@
+use crate::{
+    error::AssemblerError,
+    parse::{operand_count_error, parse_literal, split_hi, split_lo},
+};
+
+/// Take exactly `n` operands from a tokenized instruction.
+fn operands(tokens: &[String], n: usize) -> Result<&[String], AssemblerError> {
+    let operands = &tokens[1..];
+    if operands.len() != n {
+        Err(operand_count_error(&tokens[0]))
+    } else {
+        Ok(operands)
+    }
+}
+
+/// Build a token vector from string slices.
+fn line(tokens: &[&str]) -> Vec<String> {
+    tokens.iter().map(|t| (*t).to_owned()).collect()
+}
+
+/// Expand `li rd, imm`. A literal that fits in 12 bits becomes one `addi`,
+/// one with no lower bits a `lui`, and anything else a `lui` and `addi`.
+/// Symbolic values always take both, so a program is sized the same
+/// whether or not its labels are known yet.
+fn expand_li(rd: &str, imm: &str) -> Result<Vec<Vec<String>>, AssemblerError> {
+    let value = match parse_literal(imm) {
+        Some(v) if v < i32::MIN as i64 || v > u32::MAX as i64 => {
+            return Err(AssemblerError::ImmediateTooLargeError)
+        }
+        Some(v) => v as u32,
+        None => {
+            return Ok(vec![
+                line(&["lui", rd, &format!("%hi({})", imm)]),
+                line(&["addi", rd, rd, &format!("%lo({})", imm)]),
+            ])
+        }
+    };
+    let (hi, lo) = (split_hi(value).to_string(), split_lo(value));
+    let addi = |rs: &str| line(&["addi", rd, rs, &(lo as i32).to_string()]);
+    Ok(if lo == value {
+        vec![addi("zero")]
+    } else if lo == 0 {
+        vec![line(&["lui", rd, &hi])]
+    } else {
+        vec![line(&["lui", rd, &hi]), addi(rd)]
+    })
+}
+
+/// Expand a tokenized pseudo-instruction into the tokens of the base
+/// instructions it stands for.
+///
+/// | Pseudo-instruction | Expansion                                            |
+/// |--------------------|------------------------------------------------------|
+/// | `nop`              | `addi zero, zero, 0`                                 |
+/// | `mv rd, rs`        | `addi rd, rs, 0`                                     |
+/// | `not rd, rs`       | `xori rd, rs, -1`                                    |
+/// | `neg rd, rs`       | `sub rd, zero, rs`                                   |
+/// | `seqz rd, rs`      | `sltiu rd, rs, 1`                                    |
+/// | `snez rd, rs`      | `sltu rd, zero, rs`                                  |
+/// | `sltz rd, rs`      | `slt rd, rs, zero`                                   |
+/// | `sgtz rd, rs`      | `slt rd, zero, rs`                                   |
+/// | `beqz rs, off`     | `beq rs, zero, off`                                  |
+/// | `bnez rs, off`     | `bne rs, zero, off`                                  |
+/// | `blez rs, off`     | `bge zero, rs, off`                                  |
+/// | `bgez rs, off`     | `bge rs, zero, off`                                  |
+/// | `bltz rs, off`     | `blt rs, zero, off`                                  |
+/// | `bgtz rs, off`     | `blt zero, rs, off`                                  |
+/// | `bgt rs, rt, off`  | `blt rt, rs, off`                                    |
+/// | `ble rs, rt, off`  | `bge rt, rs, off`                                    |
+/// | `bleu rs, rt, off` | `bgeu rt, rs, off`                                   |
+/// | `j off`            | `jal zero, off`                                      |
+/// | `ret`              | `jalr zero, ra, 0`                                   |
+/// | `auipcz rd`        | `auipc rd, 0`                                        |
+/// | `li rd, imm`       | `lui`, then `addi`                                   |
+/// | `la rd, symbol`    | `auipc`, then `addi`                                 |
+/// | `call symbol`      | `auipc ra`, then `jalr ra, ra`                       |
+/// | `call_abs addr`    | `lui t0, %hi(addr)`, then `jalr ra, t0, %lo(addr)`   |
+/// | `jump_abs addr`    | `lui t0, %hi(addr)`, then `jalr zero, t0, %lo(addr)` |
+///
+/// `li` takes one or two instructions depending on its value; see
+/// `expand_li`. `call_abs` and `jump_abs` go to an absolute address
+/// wherever they are, like `li t0, addr` and a `jalr`, but always take
+/// two instructions, since the low part of the address is the offset of
+/// the `jalr`. They clobber `t0`. `la` always takes two instructions too,
+/// `auipc rd, %pcrel_hi(symbol)` and `addi rd, rd, %pcrel_lo(symbol)`, so
+/// it loads the address of `symbol` relative to the code it is in.
+/// `call` is the same pair with a `jalr`, using `ra` for the upper part;
+/// when relaxing, `Assembler::relax` shrinks one in range to a `jal`.
+/// `auipcz` loads the address of the `auipcz` itself, for pc-relative
+/// bookkeeping.
+///
+/// Returns:
+///     `Result<Option<Vec<Vec<String>>>, AssemblerError>`: The tokens of
+///     each instruction in the expansion, `None`
+///     if the tokens are not a pseudo-instruction, or an error if they
+///     have the wrong number of operands.
+pub fn expand_pseudo(tokens: &[String]) -> Result<Option<Vec<Vec<String>>>, AssemblerError> {
+    let expansion = match &tokens[0][..] {
+        "nop" => {
+            operands(tokens, 0)?;
+            line(&["addi", "zero", "zero", "0"])
+        }
+        "mv" | "not" | "neg" | "seqz" | "snez" | "sltz" | "sgtz" => {
+            let a = operands(tokens, 2)?;
+            let (rd, rs) = (&a[0][..], &a[1][..]);
+            match &tokens[0][..] {
+                "mv" => line(&["addi", rd, rs, "0"]),
+                "not" => line(&["xori", rd, rs, "-1"]),
+                "neg" => line(&["sub", rd, "zero", rs]),
+                "seqz" => line(&["sltiu", rd, rs, "1"]),
+                "snez" => line(&["sltu", rd, "zero", rs]),
+                "sltz" => line(&["slt", rd, rs, "zero"]),
+                _ => line(&["slt", rd, "zero", rs]),
+            }
+        }
+        "beqz" | "bnez" | "blez" | "bgez" | "bltz" | "bgtz" => {
+            let a = operands(tokens, 2)?;
+            let (rs, off) = (&a[0][..], &a[1][..]);
+            match &tokens[0][..] {
+                "beqz" => line(&["beq", rs, "zero", off]),
+                "bnez" => line(&["bne", rs, "zero", off]),
+                "blez" => line(&["bge", "zero", rs, off]),
+                "bgez" => line(&["bge", rs, "zero", off]),
+                "bltz" => line(&["blt", rs, "zero", off]),
+                _ => line(&["blt", "zero", rs, off]),
+            }
+        }
+        "bgt" | "ble" | "bleu" => {
+            let a = operands(tokens, 3)?;
+            let (rs, rt, off) = (&a[0][..], &a[1][..], &a[2][..]);
+            match &tokens[0][..] {
+                "bgt" => line(&["blt", rt, rs, off]),
+                "ble" => line(&["bge", rt, rs, off]),
+                _ => line(&["bgeu", rt, rs, off]),
+            }
+        }
+        "j" => {
+            let a = operands(tokens, 1)?;
+            line(&["jal", "zero", &a[0]])
+        }
+        "ret" => {
+            operands(tokens, 0)?;
+            line(&["jalr", "zero", "ra", "0"])
+        }
+        "auipcz" => {
+            let a = operands(tokens, 1)?;
+            line(&["auipc", &a[0], "0"])
+        }
+        "li" => {
+            let a = operands(tokens, 2)?;
+            return expand_li(&a[0], &a[1]).map(Some);
+        }
+        "la" => {
+            let a = operands(tokens, 2)?;
+            let (rd, symbol) = (&a[0][..], &a[1][..]);
+            return Ok(Some(vec![
+                line(&["auipc", rd, &format!("%pcrel_hi({})", symbol)]),
+                line(&["addi", rd, rd, &format!("%pcrel_lo({})", symbol)]),
+            ]));
+        }
+        "call" => {
+            let a = operands(tokens, 1)?;
+            return Ok(Some(vec![
+                line(&["auipc", "ra", &format!("%pcrel_hi({})", a[0])]),
+                line(&["jalr", "ra", "ra", &format!("%pcrel_lo({})", a[0])]),
+            ]));
+        }
+        "call_abs" | "jump_abs" => {
+            let a = operands(tokens, 1)?;
+            let rd = if tokens[0] == "call_abs" {
+                "ra"
+            } else {
+                "zero"
+            };
+            return Ok(Some(vec![
+                line(&["lui", "t0", &format!("%hi({})", a[0])]),
+                line(&["jalr", rd, "t0", &format!("%lo({})", a[0])]),
+            ]));
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(vec![expansion]))
+}
@@ -2,7 +2,11 @@ use std::collections::HashMap;
 
 use lib_rv32_common::{constants::*, instructions};
 
-use crate::{parse::*, *};
+use crate::{
+    error::{AssemblerError, DisassembleError},
+    parse::*,
+    *,
+};
 
 #[test]
 fn test_tokenize() {
@@ -96,6 +100,39 @@ fn test_parse_imm() {
     assert_eq!(16, parse_imm("16", &labels, pc).unwrap());
 }
 
+#[test]
+fn test_parse_imm_overflow() {
+    let labels = HashMap::new();
+    std::assert_eq!(Ok(0xffff_ffff), parse_imm("0xFFFFFFFF", &labels, 0));
+    std::assert_eq!(Ok(0x8000_0000), parse_imm("-2147483648", &labels, 0));
+    std::assert_eq!(
+        Err(AssemblerError::ValueTooLargeError {
+            token: "0x100000000".to_owned()
+        }),
+        parse_imm("0x100000000", &labels, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ValueTooLargeError {
+            token: "-2147483649".to_owned()
+        }),
+        parse_imm("-2147483649", &labels, 0)
+    );
+    // Literals inside expressions are checked too.
+    std::assert_eq!(Ok(0), parse_imm("0xffffffff+1", &labels, 0));
+    std::assert_eq!(
+        Err(AssemblerError::ValueTooLargeError {
+            token: "0x100000000".to_owned()
+        }),
+        parse_imm("0x100000000-1", &labels, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ValueTooLargeError {
+            token: "0x100000000".to_owned()
+        }),
+        assemble_program(".word 0x100000000").map(|prog| prog.words)
+    );
+}
+
 macro_rules! assert_eq {
     ($a:expr, $b:expr) => {
         std::assert_eq!($a, $b, "\n{:032b}\n{:032b}", $a, $b)
@@ -291,3 +328,3648 @@ fn test_encode_func3() {
     test_field!(encode_func3!(FUNC3_BEQ), instructions::BEQ_X5_X5_12);
     test_field!(encode_func3!(FUNC3_BNE), instructions::BNE_X5_X5_76);
 }
+
+#[test]
+fn test_disassemble() {
+    std::assert_eq!(
+        "addi t0, t1, -12",
+        disassemble(instructions::ADDI_X5_X6_NEG_12)
+            .unwrap()
+            .to_string()
+    );
+    std::assert_eq!(
+        "lw t0, 0(t0)",
+        disassemble(instructions::LW_X5_0_X5).unwrap().to_string()
+    );
+    std::assert_eq!(
+        "sw a0, -36(s0)",
+        disassemble(instructions::SW_A0_NEG_36_SP)
+            .unwrap()
+            .to_string()
+    );
+    std::assert_eq!(
+        "bne zero, t0, -4",
+        disassemble(instructions::BNE_X0_X5_NEG_4)
+            .unwrap()
+            .to_string()
+    );
+    std::assert_eq!(
+        "jal zero, -8",
+        disassemble(instructions::JAL_X0_NEG_8).unwrap().to_string()
+    );
+    std::assert_eq!(
+        "lui t0, 0x4",
+        disassemble(instructions::LUI_X5_4).unwrap().to_string()
+    );
+    std::assert_eq!(
+        "sub t0, t0, t0",
+        disassemble(instructions::SUB_X5_X5_X5).unwrap().to_string()
+    );
+    std::assert_eq!(
+        "srai t0, t0, 1",
+        disassemble(instructions::SRAI_X5_X5_1).unwrap().to_string()
+    );
+}
+
+#[test]
+fn test_disassemble_round_trip() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    for ir in [
+        instructions::ADDI_X5_X6_NEG_2048,
+        instructions::BEQ_X5_X5_80,
+        instructions::AUIPC_X5_4,
+    ]
+    .iter()
+    {
+        let text = disassemble(*ir).unwrap().to_string();
+        assert_eq!(
+            *ir,
+            assemble_ir(&text, &mut empty_hash, 0).unwrap().unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_disassemble_iter_recovers() {
+    let words = [
+        instructions::ADDI_X5_X6_0,
+        0xffffffff,
+        instructions::LUI_X5_4,
+    ];
+    let results: Vec<(u32, Result<Instruction, error::DisassembleError>)> =
+        disassemble_iter(&words, 0x100).collect();
+
+    std::assert_eq!(3, results.len());
+    std::assert_eq!(0x100, results[0].0);
+    std::assert_eq!("addi t0, t1, 0", results[0].1.as_ref().unwrap().to_string());
+    std::assert_eq!(0x104, results[1].0);
+    std::assert_eq!(
+        Err(error::DisassembleError::InvalidOpcodeError(
+            0xffffffff, 0b1111111
+        )),
+        results[1].1
+    );
+    std::assert_eq!(0x108, results[2].0);
+    std::assert_eq!("lui t0, 0x4", results[2].1.as_ref().unwrap().to_string());
+}
+
+#[test]
+fn test_checked_encode_registers() {
+    std::assert_eq!(Ok(encode_rd!(31)), encode::encode_rd(31));
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("x32".to_owned())),
+        encode::encode_rd(32)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("x32".to_owned())),
+        encode::encode_rs1(32)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("x255".to_owned())),
+        encode::encode_rs2(255)
+    );
+}
+
+#[test]
+fn test_checked_encode_imms() {
+    std::assert_eq!(Ok(encode_i_imm!(2047)), encode::encode_i_imm(2047));
+    std::assert_eq!(
+        Ok(encode_i_imm!(-2048i32 as u32)),
+        encode::encode_i_imm(-2048i32 as u32)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        encode::encode_i_imm(2048)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        encode::encode_s_imm(-2049i32 as u32)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        encode::encode_b_imm(4096)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateMisalignedError),
+        encode::encode_b_imm(6 + 1)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        encode::encode_u_imm(1 << 20)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        encode::encode_j_imm(1 << 20)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateMisalignedError),
+        encode::encode_j_imm(-3i32 as u32)
+    );
+}
+
+#[test]
+fn test_assemble_out_of_range_fields() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble_ir("addi t0, t1, 2048", &mut empty_hash, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("x32".to_owned())),
+        assemble_ir("addi x32, t1, 0", &mut empty_hash, 0)
+    );
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic]
+fn test_encode_rd_overflow_panics_in_debug() {
+    let _ = encode_rd!(32);
+}
+
+#[test]
+fn test_insert_bytes() {
+    let mut assembler = Assembler::new();
+    assembler.insert_source("addi t0, t1, 0");
+    assembler.insert_bytes(Some("blob"), &[0x11, 0x22, 0x33, 0x44, 0x55]);
+    assembler.insert_source("after: lui t0, 4\njal zero, blob");
+
+    let prog = assembler.assemble().unwrap().words;
+    std::assert_eq!(5, prog.len());
+    std::assert_eq!(instructions::ADDI_X5_X6_0, prog[0]);
+    std::assert_eq!(0x44332211, prog[1]);
+    std::assert_eq!(0x00000055, prog[2]);
+    std::assert_eq!(instructions::LUI_X5_4, prog[3]);
+    // `blob` is at 0x4, so the jump from 0x10 is -12.
+    std::assert_eq!(
+        assemble_ir("jal zero, -12", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap(),
+        prog[4]
+    );
+}
+
+#[test]
+fn test_insert_bytes_no_label() {
+    let mut assembler = Assembler::new();
+    assembler.insert_bytes(None, &[0xef, 0xbe, 0xad, 0xde]);
+    assembler.insert_source("addi t0, t1, 0");
+    std::assert_eq!(
+        vec![0xdeadbeef, instructions::ADDI_X5_X6_0],
+        assembler.assemble().unwrap().words
+    );
+}
+
+#[test]
+fn test_match_imm_kind() {
+    std::assert_eq!(Some(ImmediateKind::Signed(12)), match_imm_kind("sltiu"));
+    std::assert_eq!(Some(ImmediateKind::Signed(12)), match_imm_kind("andi"));
+    std::assert_eq!(Some(ImmediateKind::Signed(12)), match_imm_kind("lw"));
+    std::assert_eq!(Some(ImmediateKind::Unsigned(5)), match_imm_kind("srai"));
+    std::assert_eq!(Some(ImmediateKind::Unsigned(20)), match_imm_kind("lui"));
+    std::assert_eq!(Some(ImmediateKind::Signed(13)), match_imm_kind("bne"));
+    std::assert_eq!(None, match_imm_kind("add"));
+}
+
+#[test]
+fn test_assemble_sltiu_neg_1() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    // sltiu x1, x2, -1 (imm = 0xfff, func3 = 0b011)
+    assert_eq!(
+        0xfff13093u32,
+        assemble_ir("sltiu x1, x2, -1", &mut empty_hash, 0)
+            .unwrap()
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_assemble_logical_imm_sign_extension() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    for op in ["andi", "ori", "xori"].iter() {
+        // A value that is a sign-extended 12-bit immediate is accepted in
+        // either form.
+        assert_eq!(
+            assemble_ir(&format!("{} t0, t0, -16", op), &mut empty_hash, 0)
+                .unwrap()
+                .unwrap(),
+            assemble_ir(&format!("{} t0, t0, 0xfffffff0", op), &mut empty_hash, 0)
+                .unwrap()
+                .unwrap()
+        );
+        // One that would change meaning when sign-extended is not.
+        std::assert_eq!(
+            Err(AssemblerError::ImmediateTooLargeError),
+            assemble_ir(&format!("{} t0, t0, 0xfff", op), &mut empty_hash, 0)
+        );
+    }
+    assert_eq!(
+        0x7ff2e293u32,
+        assemble_ir("ori t0, t0, 0x7ff", &mut empty_hash, 0)
+            .unwrap()
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_assemble_shift_imm() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    assert_eq!(
+        instructions::SLLI_X5_X5_1,
+        assemble_ir("slli t0, t0, 1", &mut empty_hash, 0)
+            .unwrap()
+            .unwrap()
+    );
+    assert_eq!(
+        instructions::SRAI_X5_X5_1,
+        assemble_ir("srai t0, t0, 1", &mut empty_hash, 0)
+            .unwrap()
+            .unwrap()
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble_ir("slli t0, t0, 32", &mut empty_hash, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble_ir("srli t0, t0, -1", &mut empty_hash, 0)
+    );
+}
+
+#[test]
+fn test_assemble_program_result() {
+    let prog = assemble_program(
+        ".globl start
+start: addi t0, t1, 0
+loop: lui t0, 4
+
+bne zero, t0, loop",
+    )
+    .unwrap();
+
+    std::assert_eq!(
+        vec![
+            instructions::ADDI_X5_X6_0,
+            instructions::LUI_X5_4,
+            instructions::BNE_X0_X5_NEG_4
+        ],
+        prog.words
+    );
+    std::assert_eq!(Some(&0), prog.symbols.get("start"));
+    std::assert_eq!(Some(&4), prog.symbols.get("loop"));
+    assert!(prog.globals.contains("start"));
+    assert!(!prog.globals.contains("loop"));
+    std::assert_eq!(
+        vec![Relocation {
+            address: 8,
+            symbol: "loop".to_owned(),
+            kind: RelocationKind::Branch,
+        }],
+        prog.relocations
+    );
+    std::assert_eq!(vec![1, 2, 4], prog.line_map);
+}
+
+#[test]
+fn test_program_exports() {
+    let prog = assemble_program("addi t0, t1, 0\nlui t0, 4").unwrap();
+    std::assert_eq!(
+        vec![0x93, 0x02, 0x03, 0x00, 0xb7, 0x42, 0x00, 0x00],
+        prog.to_bytes(Endian::Little)
+    );
+    std::assert_eq!("00030293\n000042b7\n", prog.to_hex(Endian::Big));
+    std::assert_eq!("93020300\nb7420000\n", prog.to_hex(Endian::Little));
+    std::assert_eq!(
+        vec![instructions::ADDI_X5_X6_0, instructions::LUI_X5_4],
+        prog.into_words()
+    );
+}
+
+#[test]
+fn test_program_to_elf() {
+    let prog = assemble_program(".globl start\nstart: addi t0, t1, 0\nend: lui t0, 4").unwrap();
+    let elf = prog.to_elf();
+    let u16_at = |i: usize| u16::from_le_bytes([elf[i], elf[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([elf[i], elf[i + 1], elf[i + 2], elf[i + 3]]);
+
+    std::assert_eq!(&[0x7f, b'E', b'L', b'F', 1, 1, 1], &elf[..7]);
+    // e_machine is RISC-V.
+    std::assert_eq!(243, u16_at(18));
+
+    // The loadable segment holds the program.
+    let text_off = u32_at(52 + 4) as usize;
+    std::assert_eq!(
+        prog.to_bytes(Endian::Little),
+        elf[text_off..text_off + 8].to_vec()
+    );
+
+    // The symbol table has the null symbol, then `end` (local), then
+    // `start` (global).
+    let shoff = u32_at(32) as usize;
+    let symtab = shoff + 2 * 40;
+    let sym_off = u32_at(symtab + 16) as usize;
+    std::assert_eq!(3 * 16, u32_at(symtab + 20));
+    std::assert_eq!(2, u32_at(symtab + 28));
+    std::assert_eq!(4, u32_at(sym_off + 16 + 4));
+    std::assert_eq!(0x00, elf[sym_off + 16 + 12]);
+    std::assert_eq!(0, u32_at(sym_off + 32 + 4));
+    std::assert_eq!(0x10, elf[sym_off + 32 + 12]);
+    // The segment and `.text` are loaded at the base.
+    std::assert_eq!((0, 0), (u32_at(52 + 8), u32_at(52 + 12)));
+    let mut assembler = Assembler::new();
+    assembler
+        .base(0x8000_0000)
+        .insert_source(".globl _start\n_start: addi t0, t1, 0");
+    let elf = assembler.assemble().unwrap().to_elf();
+    let u32_at = |i: usize| u32::from_le_bytes([elf[i], elf[i + 1], elf[i + 2], elf[i + 3]]);
+    std::assert_eq!(0x8000_0000, u32_at(24));
+    std::assert_eq!(0x8000_0000, u32_at(52 + 8));
+    std::assert_eq!(0x8000_0000, u32_at(52 + 12));
+    std::assert_eq!(0x8000_0000, u32_at(u32_at(32) as usize + 40 + 12));
+}
+
+#[test]
+fn test_tokenize_offset_expression() {
+    let tokens: Vec<String> = tokenize!("lw a0, (gp_base - entry)(gp)");
+    std::assert_eq!(vec!["lw", "a0", "(gp_base - entry)", "gp"], tokens);
+    let tokens: Vec<String> = tokenize!("lw a0, 8 ( sp )");
+    std::assert_eq!(vec!["lw", "a0", "8", "sp"], tokens);
+}
+
+#[test]
+fn test_eval_expr() {
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    labels.insert("a".to_string(), 0x10);
+    labels.insert("b".to_string(), 0x4);
+
+    std::assert_eq!(Ok(0xc), eval_expr("a - b", &labels));
+    std::assert_eq!(Ok(-0xc_i32 as u32), eval_expr("b-a", &labels));
+    std::assert_eq!(Ok(0x18), eval_expr("-(b - a) + 0xc", &labels));
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchLabelError("c".to_owned())),
+        eval_expr("a - c", &labels)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidImmediateError("(a".to_owned())),
+        eval_expr("(a", &labels)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidImmediateError("a b".to_owned())),
+        eval_expr("a b", &labels)
+    );
+}
+
+#[test]
+fn test_assemble_negative_offset() {
+    let mut empty_hash: HashMap<String, u32> = HashMap::new();
+    let ir = assemble_ir("lw t0, -4(sp)", &mut empty_hash, 0)
+        .unwrap()
+        .unwrap();
+    std::assert_eq!("lw t0, -4(sp)", disassemble(ir).unwrap().to_string());
+}
+
+#[test]
+fn test_assemble_label_difference_offset() {
+    let prog = assemble_program(
+        "entry: addi t0, t1, 0
+addi t0, t1, 0
+gp_base: lw a0, (entry - gp_base)(gp)
+lw a0, (gp_base - entry)(gp)",
+    )
+    .unwrap();
+    std::assert_eq!(
+        "lw a0, -8(gp)",
+        disassemble(prog.words[2]).unwrap().to_string()
+    );
+    std::assert_eq!(
+        "lw a0, 8(gp)",
+        disassemble(prog.words[3]).unwrap().to_string()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.insert_source("near: addi t0, t1, 0");
+    assembler.insert_bytes(None, &[0; 2044]);
+    assembler.insert_source("far: lw a0, (near - far)(gp)");
+    assembler.assemble().unwrap();
+    assembler.insert_source("lw a0, (near - far - 4)(gp)");
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assembler.assemble()
+    );
+}
+
+#[test]
+fn test_unknown_directive() {
+    let program = "addi t0, t1, 0\n.foo 1, 2\nlui t0, 4";
+
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    std::assert_eq!(
+        vec![instructions::ADDI_X5_X6_0, instructions::LUI_X5_4],
+        assembler.assemble().unwrap().words
+    );
+
+    assembler.strict(true);
+    std::assert_eq!(
+        Err(AssemblerError::UnknownDirectiveError(".foo".to_owned())),
+        assembler.assemble()
+    );
+
+    // Known directives are still accepted in strict mode.
+    let mut assembler = Assembler::new();
+    assembler
+        .strict(true)
+        .insert_source(".globl main\nmain: lui t0, 4");
+    assert!(assembler.assemble().is_ok());
+}
+
+#[test]
+fn test_forward_label() {
+    let prog = assemble_program("beq t0, t0, end\naddi t0, t1, 0\nend: lui t0, 4").unwrap();
+    assert_eq!(
+        assemble_ir("beq t0, t0, 8", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap(),
+        prog.words[0]
+    );
+}
+
+#[test]
+fn test_parse_labels() {
+    let labels = parse_labels("start:\naddi t0, t1, 0\n.globl end\n\nend: lui t0, 4").unwrap();
+    std::assert_eq!(Some(&0), labels.get("start"));
+    std::assert_eq!(Some(&4), labels.get("end"));
+}
+
+#[test]
+fn test_program_size() {
+    let program = "start: addi t0, t1, 0\n.globl start\n\nlui t0, 4\nbne zero, t0, start";
+    std::assert_eq!(Ok(12), program_size(program));
+    std::assert_eq!(
+        program_size(program).unwrap(),
+        assemble_program(program)
+            .unwrap()
+            .to_bytes(Endian::Little)
+            .len()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    assembler.insert_bytes(None, &[1, 2, 3, 4, 5]);
+    std::assert_eq!(Ok(20), assembler.size());
+    std::assert_eq!(
+        assembler.size().unwrap(),
+        assembler.assemble().unwrap().to_bytes(Endian::Little).len()
+    );
+}
+
+#[test]
+fn test_optimize_li_mv() {
+    let mut assembler = Assembler::new();
+    assembler.optimize(true);
+    assembler.insert_source("addi t0, zero, 5\naddi t1, t0, 0\naddi t0, zero, 1");
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        vec![
+            assemble_ir("addi t1, zero, 5", &mut HashMap::new(), 0)
+                .unwrap()
+                .unwrap(),
+            assemble_ir("addi t0, zero, 1", &mut HashMap::new(), 0)
+                .unwrap()
+                .unwrap(),
+        ],
+        prog.words
+    );
+    std::assert_eq!(vec![0, 2], prog.line_map);
+}
+
+#[test]
+fn test_optimize_dead_writes() {
+    let program = "addi t0, t0, 0\nadd zero, t0, t1\naddi zero, zero, 0\nend: addi t1, zero, 1\nbeq t1, t1, end";
+
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    std::assert_eq!(5, assembler.assemble().unwrap().words.len());
+
+    assembler.optimize(true);
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(Ok(12), assembler.size());
+    std::assert_eq!(Some(&4), prog.symbols.get("end"));
+    std::assert_eq!(
+        assemble_ir("addi zero, zero, 0", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap(),
+        prog.words[0]
+    );
+    std::assert_eq!(
+        assemble_ir("beq t1, t1, -4", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap(),
+        prog.words[2]
+    );
+}
+
+#[test]
+fn test_optimize_keeps_live_writes() {
+    let program = "addi t0, zero, 5\naddi t1, t0, 0\naddi t0, t0, 1";
+    let mut assembler = Assembler::new();
+    assembler.optimize(true);
+    assembler.insert_source(program);
+    std::assert_eq!(
+        assemble_program(program).unwrap().words,
+        assembler.assemble().unwrap().words
+    );
+}
+
+#[test]
+fn test_program_mem_widths() {
+    let prog = assemble_program("addi t0, t1, 0\nlui t0, 4").unwrap();
+
+    std::assert_eq!(
+        "// 0x00000000\n00030293\n// 0x00000001\n000042b7\n",
+        prog.to_readmemh(MemWidth::Word)
+    );
+    std::assert_eq!(
+        "// 0x00000000\n0293\n0003\n// 0x00000002\n42b7\n0000\n",
+        prog.to_readmemh(MemWidth::Half)
+    );
+    std::assert_eq!(
+        "// 0x00000000\n93\n02\n03\n00\n// 0x00000004\nb7\n42\n00\n00\n",
+        prog.to_readmemh(MemWidth::Byte)
+    );
+
+    std::assert_eq!(
+        "memory_initialization_radix=16;\nmemory_initialization_vector=\n00030293,\n000042b7;\n",
+        prog.to_coe(MemWidth::Word)
+    );
+    std::assert_eq!(
+        "memory_initialization_radix=16;\nmemory_initialization_vector=\n0293,\n0003,\n42b7,\n0000;\n",
+        prog.to_coe(MemWidth::Half)
+    );
+    std::assert!(prog
+        .to_coe(MemWidth::Byte)
+        .ends_with("\n93,\n02,\n03,\n00,\nb7,\n42,\n00,\n00;\n"));
+
+    std::assert_eq!(
+        "WIDTH=32;\nDEPTH=2;\nADDRESS_RADIX=HEX;\nDATA_RADIX=HEX;\nCONTENT BEGIN\n    0 : 00030293;\n    1 : 000042b7;\nEND;\n",
+        prog.to_mif(MemWidth::Word)
+    );
+    std::assert!(prog
+        .to_mif(MemWidth::Half)
+        .contains("WIDTH=16;\nDEPTH=4;\n"));
+    std::assert!(prog.to_mif(MemWidth::Byte).contains("WIDTH=8;\nDEPTH=8;\n"));
+    std::assert!(prog.to_mif(MemWidth::Byte).contains("    4 : b7;\n"));
+}
+
+#[test]
+fn test_warn_zero_destination() {
+    let program = "addi zero, zero, 0\nadd zero, t0, t1\njal zero, 0\nlw zero, 0(sp)\nlui x0, 4";
+
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    std::assert!(assembler.assemble().unwrap().diagnostics.is_empty());
+
+    assembler.warn_zero_destination(true);
+    let diagnostics = assembler.assemble().unwrap().diagnostics;
+    std::assert_eq!(
+        vec![1, 4],
+        diagnostics.iter().map(|d| d.line).collect::<Vec<_>>()
+    );
+    std::assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+    std::assert_eq!(
+        "`add zero, t0, t1` writes to zero, discarding its result",
+        diagnostics[0].message
+    );
+}
+
+#[test]
+fn test_words_from_bytes() {
+    let prog = assemble_program("addi t0, t1, 0\nlui t0, 4").unwrap();
+    std::assert_eq!(
+        Ok(prog.words.clone()),
+        words_from_bytes(&prog.to_bytes(Endian::Little), Endian::Little)
+    );
+    std::assert_eq!(
+        Ok(vec![0x93020300, 0xb7420000]),
+        words_from_bytes(&prog.to_bytes(Endian::Little), Endian::Big)
+    );
+    std::assert_eq!(
+        Err(DisassembleError::PartialWordError(6)),
+        words_from_bytes(&[0x93, 0x02, 0x03, 0x00, 0xb7, 0x42], Endian::Little)
+    );
+}
+
+#[test]
+fn test_eval_hi_lo() {
+    let mut labels = HashMap::new();
+    labels.insert("sym".to_owned(), 0x1234_5800);
+    std::assert_eq!(Ok(0x12346), eval_expr("%hi(sym)", &labels));
+    std::assert_eq!(Ok(-2048i32 as u32), eval_expr("%lo(sym)", &labels));
+    std::assert_eq!(Ok(0x123), eval_expr("%lo(sym + 0x923)", &labels));
+    std::assert_eq!(
+        Err(AssemblerError::InvalidImmediateError(
+            "%mid(sym)".to_owned()
+        )),
+        eval_expr("%mid(sym)", &labels)
+    );
+    std::assert_eq!(
+        vec!["lw", "t0", "%lo(sym)", "t0"],
+        tokenize("lw t0, %lo(sym)(t0)")
+    );
+}
+
+#[test]
+fn test_lo_sign_warning() {
+    let mut assembler = Assembler::new();
+    assembler.insert_bytes(Some("pad"), &[0; 0x7fc]);
+    assembler.insert_source("sym: lui t0, %hi(sym)\naddi t0, t0, %lo(sym)\naddi t1, t1, %lo(pad)");
+    let prog = assembler.assemble().unwrap();
+
+    // `sym` is at 0x7fc, so neither part carries.
+    std::assert_eq!(
+        assemble_ir("addi t0, t0, 0x7fc", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap(),
+        prog.words[0x7fc / 4 + 1]
+    );
+    std::assert!(prog.diagnostics.is_empty());
+
+    let mut assembler = Assembler::new();
+    assembler.insert_bytes(Some("pad"), &[0; 0x800]);
+    assembler.insert_source("sym: lui t0, %hi(sym)\nlw t1, %lo(sym)(t0)");
+    let prog = assembler.assemble().unwrap();
+
+    std::assert_eq!(
+        assemble_ir("lui t0, 1", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap(),
+        prog.words[0x800 / 4]
+    );
+    std::assert_eq!(1, prog.diagnostics.len());
+    std::assert_eq!(1, prog.diagnostics[0].line);
+    std::assert!(prog.diagnostics[0].message.contains("-2048"));
+
+    // A literal `li` split into `lui` and `addi` warns the same way, but
+    // not when it is a lone `addi`.
+    let prog =
+        assemble_program("li t0, 0x800\nli t1, 0x7ff\nli t2, -5\nli a0, 0x12345678").unwrap();
+    std::assert_eq!(
+        assemble_program("lui t0, 1\naddi t0, t0, -2048")
+            .unwrap()
+            .words,
+        prog.words[..2]
+    );
+    std::assert_eq!(1, prog.diagnostics.len());
+    std::assert_eq!(0, prog.diagnostics[0].line);
+    std::assert_eq!(
+        "the lower part of `li t0, 0x800` is -2048, since bit 11 is set and sign-extends; the `lui` carries one into the upper bits",
+        prog.diagnostics[0].message
+    );
+}
+
+#[test]
+fn test_assemble_into() {
+    let program = "addi t0, t1, 0\nend: addi t0, zero, end+0";
+    let mut buf = [0xff; 8];
+    std::assert_eq!(Ok(8), assemble_into(program, &mut buf, 0x100));
+    std::assert_eq!(
+        assemble_program("addi t0, t1, 0\naddi t0, zero, 0x104")
+            .unwrap()
+            .to_bytes(Endian::Little),
+        buf.to_vec()
+    );
+
+    let mut buf = [0xff; 12];
+    std::assert_eq!(Ok(8), assemble_into(program, &mut buf, 0));
+    std::assert_eq!([0xff; 4], buf[8..]);
+}
+
+#[test]
+fn test_assemble_into_overflow() {
+    let mut buf = [0xff; 7];
+    std::assert_eq!(
+        Err(AssemblerError::ImageTooLargeError {
+            needed: 8,
+            capacity: 7
+        }),
+        assemble_into("addi t0, t1, 0\nlui t0, 4", &mut buf, 0)
+    );
+    std::assert_eq!([0xff; 7], buf);
+}
+
+#[test]
+fn test_assemble_into_at() {
+    let program = "start: jal zero, start\naddi t0, zero, start+0";
+    let mut buf = [0xff; 12];
+    std::assert_eq!(Ok(8), assemble_into_at(program, &mut buf, 4, 0x100));
+    std::assert_eq!([0xff; 4], buf[..4]);
+    std::assert_eq!(
+        assemble_program("jal zero, 0\naddi t0, zero, 0x100")
+            .unwrap()
+            .to_bytes(Endian::Little),
+        buf[4..].to_vec()
+    );
+
+    std::assert_eq!(
+        Err(AssemblerError::ImageTooLargeError {
+            needed: 13,
+            capacity: 12
+        }),
+        assemble_into_at(program, &mut buf, 5, 0x100)
+    );
+}
+
+#[test]
+fn test_option_directive() {
+    let program = ".option push\n.option norelax\naddi t0, t1, 0\n.option pop\nlui t0, 4";
+    let mut assembler = Assembler::new();
+    assembler.strict(true);
+    assembler.insert_source(program);
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        vec![instructions::ADDI_X5_X6_0, instructions::LUI_X5_4],
+        prog.words
+    );
+    std::assert!(prog.diagnostics.is_empty());
+
+    let prog = assemble_program(".option rvc\n.option bogus\naddi t0, t1, 0").unwrap();
+    std::assert_eq!(vec![instructions::ADDI_X5_X6_0], prog.words);
+    std::assert_eq!(
+        vec![0, 1],
+        prog.diagnostics.iter().map(|d| d.line).collect::<Vec<_>>()
+    );
+    std::assert_eq!(
+        "unknown option `bogus` is ignored",
+        prog.diagnostics[1].message
+    );
+}
+
+#[test]
+fn test_stacked_labels() {
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    assert_eq!(
+        instructions::ADDI_X5_X6_0,
+        assemble_ir("done: exit: addi t0, t1, 0", &mut labels, 8)
+            .unwrap()
+            .unwrap()
+    );
+    std::assert_eq!(Some(&8), labels.get("done"));
+    std::assert_eq!(Some(&8), labels.get("exit"));
+
+    let prog = assemble_program(
+        "beq t0, t0, exit\ndone:\nexit: addi t0, t1, 0\nagain: end: bne t0, t0, done",
+    )
+    .unwrap();
+    std::assert_eq!(Some(&4), prog.symbols.get("done"));
+    std::assert_eq!(Some(&4), prog.symbols.get("exit"));
+    std::assert_eq!(prog.symbols.get("again"), prog.symbols.get("end"));
+    assert_eq!(
+        assemble_ir("beq t0, t0, 4", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap(),
+        prog.words[0]
+    );
+}
+
+#[test]
+fn test_tokenize_contract() {
+    std::assert_eq!(
+        vec!["addi", "t0", "t1", "-1"],
+        tokenize("ADDI T0,,t1 ,\t-1,")
+    );
+    std::assert_eq!(
+        vec!["addi", "t0", "t1", "1"],
+        tokenize("addi t0, t1, 1 # Increment, then loop")
+    );
+    std::assert_eq!(vec!["lui", "t0", "4"], tokenize("lui t0, 4// upper"));
+    std::assert!(tokenize("# comment only").is_empty());
+    std::assert_eq!(
+        vec![".ascii", "\"Hi, # \\\"you\\\"\"", "x"],
+        tokenize(".ascii \"Hi, # \\\"you\\\"\" X # trailing")
+    );
+    std::assert_eq!(
+        vec!["addi", "t0", "t1", "a - b"],
+        tokenize("addi t0, t1, (A - B)")
+    );
+}
+
+#[test]
+fn test_assemble_with_comments() {
+    std::assert_eq!(
+        vec![instructions::ADDI_X5_X6_0, instructions::LUI_X5_4],
+        assemble_program("# Setup\naddi t0, t1, 0 # Copy\n\nlui t0, 4 // Upper\n")
+            .unwrap()
+            .words
+    );
+}
+
+#[test]
+fn test_assemble_not_neg() {
+    assert_eq!(
+        0xfff5c513u32,
+        assemble_ir("not a0, a1", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap()
+    );
+    assert_eq!(
+        0x40b00533u32,
+        assemble_ir("neg a0, a1", &mut HashMap::new(), 0)
+            .unwrap()
+            .unwrap()
+    );
+    std::assert_eq!(
+        Ok(Instruction::Itype {
+            op: "xori".to_owned(),
+            rd: 10,
+            rs1: 11,
+            imm: -1
+        }),
+        disassemble(0xfff5c513)
+    );
+}
+
+#[test]
+fn test_assemble_pseudo() {
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    labels.insert("loop".to_string(), 0);
+    let pairs = [
+        ("nop", "addi zero, zero, 0"),
+        ("mv t0, t1", "addi t0, t1, 0"),
+        ("seqz t0, t1", "sltiu t0, t1, 1"),
+        ("snez t0, t1", "sltu t0, zero, t1"),
+        ("sgtz t0, t1", "slt t0, zero, t1"),
+        ("bnez t0, loop", "bne t0, zero, loop"),
+        ("blez t0, loop", "bge zero, t0, loop"),
+        ("bgt t0, t1, loop", "blt t1, t0, loop"),
+        ("bleu t0, t1, loop", "bgeu t1, t0, loop"),
+        ("j loop", "jal zero, loop"),
+    ];
+    for (pseudo, base) in pairs.iter() {
+        std::assert_eq!(
+            assemble_ir(base, &mut labels, 8),
+            assemble_ir(pseudo, &mut labels, 8),
+            "{}",
+            pseudo
+        );
+    }
+    std::assert_eq!(
+        Err(AssemblerError::OperandCountError {
+            op: "mv".to_owned(),
+            expected: "rd, rs"
+        }),
+        assemble_ir("mv t0", &mut labels, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::OperandCountError {
+            op: "nop".to_owned(),
+            expected: ""
+        }),
+        assemble_ir("nop t0", &mut labels, 0)
+    );
+}
+
+#[test]
+fn test_program_bytes_endian() {
+    // Words are computed arithmetically, so these bytes are the same
+    // whatever `cfg(target_endian)` is.
+    let prog = assemble_program("addi t0, t1, 0\nlui t0, 4").unwrap();
+    std::assert_eq!(vec![0x00030293, 0x000042b7], prog.words);
+    std::assert_eq!(
+        vec![0x93, 0x02, 0x03, 0x00, 0xb7, 0x42, 0x00, 0x00],
+        prog.to_bytes(Endian::Little)
+    );
+    std::assert_eq!(
+        vec![0x00, 0x03, 0x02, 0x93, 0x00, 0x00, 0x42, 0xb7],
+        prog.to_bytes(Endian::Big)
+    );
+}
+
+#[test]
+fn test_assemble_jal_rd() {
+    let mut labels = HashMap::new();
+    std::assert_eq!(
+        Ok(Some(0x0080_00ef)),
+        assemble_ir("jal ra, 8", &mut labels, 0)
+    );
+    std::assert_eq!(
+        Ok(Some(0xff9f_f06f)),
+        assemble_ir("jal zero, -8", &mut labels, 0)
+    );
+}
+
+#[test]
+fn test_assemble_li() {
+    let words = |program: &str| assemble_program(program).unwrap().words;
+    std::assert_eq!(words("addi t0, zero, -5"), words("li t0, -5"));
+    std::assert_eq!(words("lui t0, 0x12"), words("li t0, 0x12000"));
+    std::assert_eq!(
+        words("lui t0, 0x12\naddi t0, t0, 0x345"),
+        words("li t0, 0x12345")
+    );
+    std::assert_eq!(
+        words("lui t0, 0x12346\naddi t0, t0, -0x800"),
+        words("li t0, 0x12345800")
+    );
+    std::assert_eq!(
+        words("lui t0, 0\naddi t0, t0, 12\nlui t1, 4"),
+        words("li t0, end\nlui t1, 4\nend:")
+    );
+    std::assert_eq!(Ok(12), program_size("li t0, 1\nli t0, 0x12345"));
+
+    std::assert_eq!(
+        Ok(Some(instructions::ADDI_X5_X6_0 & !0xf8000)),
+        assemble_ir("li t0, 0", &mut HashMap::new(), 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::MultipleInstructionsError),
+        assemble_ir("li t0, 0x12345", &mut HashMap::new(), 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble_ir("li t0, 0x100000000", &mut HashMap::new(), 0)
+    );
+}
+
+#[test]
+fn test_code_span() {
+    std::assert_eq!(2..16, code_span("  addi t0, t1, 0 # Copy"));
+    std::assert_eq!(12..21, code_span("done: exit:\tlui t0, 4"));
+    std::assert_eq!(6..6, code_span("loop: // Nothing"));
+}
+
+#[test]
+fn test_assemble_program_spans() {
+    let program = "start: addi t0, t1, 0\n  li t0, 0x12345 # Load\nlui t0, 4";
+    let spans = assemble_program_spans(program).unwrap();
+    std::assert_eq!(4, spans.len());
+    std::assert_eq!(7..21, spans[0].1);
+    std::assert_eq!("li t0, 0x12345", &program[spans[1].1.clone()]);
+    std::assert_eq!(spans[1].1, spans[2].1);
+    std::assert_eq!("lui t0, 4", &program[spans[3].1.clone()]);
+    std::assert_eq!(instructions::LUI_X5_4, spans[3].0);
+}
+
+#[test]
+fn test_data_directives() {
+    let prog = assemble_program(
+        "table: .word 0xaa : 4\nafter: .half 1, -2, 3\n.byte 1, 2 : 2, 0xff:2\n.word after, end\nend: lui t0, 4",
+    )
+    .unwrap();
+    std::assert_eq!(Some(&16), prog.symbols.get("after"));
+    std::assert_eq!(Some(&40), prog.symbols.get("end"));
+    std::assert_eq!(
+        vec![
+            0xaa,
+            0xaa,
+            0xaa,
+            0xaa,
+            0xfffe_0001,
+            0x0003,
+            0xff02_0201,
+            0xff,
+            16,
+            40,
+            instructions::LUI_X5_4
+        ],
+        prog.words
+    );
+    std::assert_eq!(vec![0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4], prog.line_map);
+    std::assert_eq!(Ok(40), program_size(".word 0xaa : 4\n.half 0:12"));
+}
+
+#[test]
+fn test_data_directive_errors() {
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble_program(".byte 0x100")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooFewTokensError),
+        assemble_program(".word 1 :")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooFewTokensError),
+        assemble_program(".half")
+    );
+    std::assert!(assemble_program(".half -32768, 0xffff").is_ok());
+
+    // Repeat counts whose total size overflows are an error, not a panic.
+    for program in [".word 0 : 0x40000000", ".byte 0 : 0xffffffff, 0 : 1"] {
+        std::assert_eq!(
+            Err(AssemblerError::ImmediateTooLargeError),
+            assemble_program(program)
+        );
+        std::assert_eq!(
+            vec![(0, Severity::Error, "immediate is out of range".to_owned())],
+            validate(program)
+                .into_iter()
+                .map(|d| (d.line, d.severity, d.message))
+                .collect::<Vec<_>>()
+        );
+        let (_, diagnostics) = parse_program(program);
+        std::assert_eq!(Severity::Error, diagnostics[0].severity);
+        std::assert_eq!("immediate is out of range", diagnostics[0].message);
+    }
+}
+
+#[test]
+fn test_validate() {
+    let program = "start: addi t0, t1, 4096\nbeq t0, t1, nowhere\n.bogus\nstart: lui t0, 4\nbne t0, t1, start";
+    let diagnostics = validate(program);
+    std::assert_eq!(
+        vec![
+            (0, Severity::Error, "immediate is out of range"),
+            (1, Severity::Error, "no label or constant named `nowhere`"),
+            (
+                2,
+                Severity::Warning,
+                "unknown directive `.bogus` is ignored"
+            ),
+            (3, Severity::Error, "`start` is defined more than once"),
+        ],
+        diagnostics
+            .iter()
+            .map(|d| (d.line, d.severity, &d.message[..]))
+            .collect::<Vec<_>>()
+    );
+
+    // The first error is the one `assemble_program` returns.
+    std::assert_eq!(
+        Err(AssemblerError::DuplicateLabelError("start".to_owned())),
+        assemble_program(program)
+    );
+    std::assert!(validate("addi t0, t1, 0\nlui t0, 4").is_empty());
+}
+
+#[test]
+fn test_validate_keeps_layout() {
+    // A bad `li` still takes its two words, so later branches are checked
+    // against the right addresses.
+    let diagnostics = validate("li t0, 0x12345\nli t1, bogus\nbeq t0, t1, end\nend:");
+    std::assert_eq!(1, diagnostics.len());
+    std::assert_eq!(1, diagnostics[0].line);
+}
+
+#[test]
+fn test_comments_with_colons() {
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    assert_eq!(
+        instructions::ADDI_X5_X6_0,
+        assemble_ir(
+            "addi t0, t1, 0 # set flag: ready, then go: now",
+            &mut labels,
+            0
+        )
+        .unwrap()
+        .unwrap()
+    );
+    std::assert!(labels.is_empty());
+
+    let program =
+        "addi t0, t1, 0 # done: here, .word 1\n# exit: .globl start\nlui t0, 4 // x: .byte 2, 3";
+    let prog = assemble_program(program).unwrap();
+    std::assert_eq!(
+        vec![instructions::ADDI_X5_X6_0, instructions::LUI_X5_4],
+        prog.words
+    );
+    std::assert!(prog.symbols.is_empty());
+    std::assert!(prog.globals.is_empty());
+    std::assert!(parse_labels(program).unwrap().is_empty());
+}
+
+#[test]
+fn test_semicolon_comments() {
+    let tokens: Vec<String> = tokenize!("addi t0, t1, 0; set t0: done, now");
+    std::assert_eq!(vec!["addi", "t0", "t1", "0"], tokens);
+    let tokens: Vec<String> = tokenize!(".ascii \"a; b\" ; trailing");
+    std::assert_eq!(vec![".ascii", "\"a; b\""], tokens);
+
+    let prog = assemble_program("; header\naddi t0, t1, 0 ; x: .word 1\nlui t0, 4;").unwrap();
+    std::assert_eq!(
+        vec![instructions::ADDI_X5_X6_0, instructions::LUI_X5_4],
+        prog.words
+    );
+    std::assert!(prog.symbols.is_empty());
+}
+
+#[test]
+fn test_parse_literal() {
+    std::assert_eq!(Some(5), parse_literal("5"));
+    std::assert_eq!(Some(5), parse_literal("+5"));
+    std::assert_eq!(Some(-5), parse_literal("-5"));
+    std::assert_eq!(Some(0x10), parse_literal("+0x10"));
+    std::assert_eq!(Some(-0x10), parse_literal("-0X10"));
+    std::assert_eq!(Some(5), parse_literal("+0b101"));
+    std::assert_eq!(None, parse_literal("+"));
+    std::assert_eq!(None, parse_literal("+-5"));
+    std::assert_eq!(None, parse_literal("0x"));
+    std::assert_eq!(None, parse_literal("0x-5"));
+    std::assert_eq!(None, parse_literal("0x+5"));
+    std::assert_eq!(None, parse_literal("0b-1"));
+    std::assert_eq!(None, parse_literal("-0x-5"));
+    std::assert!(assemble_program("addi t0, zero, 0x-5").is_err());
+    std::assert_eq!(None, parse_literal("loop"));
+}
+
+#[test]
+fn test_assemble_plus_sign() {
+    let words = |program: &str| assemble_program(program).unwrap().words;
+    std::assert_eq!(words("addi x1, x0, 5"), words("addi x1, x0, +5"));
+    std::assert_eq!(words("li t0, 16"), words("li t0, +0x10"));
+    std::assert_eq!(words("addi x1, x0, 5"), words("addi x1, x0, +0b101"));
+    std::assert_eq!(1, words("li t0, +0x10").len());
+    std::assert_eq!(
+        Err(AssemblerError::InvalidImmediateError("+".to_owned())),
+        assemble_ir("addi x1, x0, +", &mut HashMap::new(), 0)
+    );
+}
+
+#[test]
+fn test_supported_mnemonics() {
+    let mnemonics = supported_mnemonics();
+    std::assert!(!mnemonics.is_empty());
+    for op in ["addi", "lw", "jal", "li", "mv", "srl"].iter() {
+        std::assert!(mnemonics.contains(op), "{}", op);
+    }
+    // The disassembly of `srl` and `sra` assembles back to the same word.
+    for text in ["srl a0, a1, a2", "sra a0, a1, a2"].iter() {
+        let ir = assemble_ir(text, &mut HashMap::new(), 0).unwrap().unwrap();
+        std::assert_eq!(*text, disassemble(ir).unwrap().to_string());
+    }
+    // Everything listed is accepted, either as an instruction or a
+    // pseudo-instruction.
+    for op in mnemonics {
+        let tokens = vec![op.to_string()];
+        std::assert!(
+            match_opcode(op).is_ok() || !matches!(pseudo::expand_pseudo(&tokens), Ok(None)),
+            "{}",
+            op
+        );
+    }
+}
+
+#[test]
+fn test_supported_directives() {
+    let directives = supported_directives();
+    std::assert!(!directives.is_empty());
+    std::assert!(directives.contains(&".word"));
+    std::assert!(directives.contains(&".globl"));
+    for directive in directives {
+        let mut assembler = Assembler::new();
+        assembler.strict(true);
+        let operands = match *directive {
+            ".equ" | ".set" | ".size" => "x, 0",
+            ".type" => "x, @function",
+            ".insn" => "r 0x33, 0, 0, x1, x2, x3",
+            ".file" => "\"a.c\"",
+            _ => "0",
+        };
+        assembler.insert_source(&format!("{} {}", directive, operands));
+        std::assert!(assembler.assemble().is_ok(), "{}", directive);
+    }
+}
+
+#[test]
+fn test_assemble_missing_operands() {
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    for (ir, expected) in [
+        ("addi x1, x2", "rd, rs1, imm"),
+        ("ori x1, x2", "rd, rs1, imm"),
+        ("add x1, x2", "rd, rs1, rs2"),
+        ("lw t0", "rd, offset(rs1)"),
+        ("beq t0, t1", "rs1, rs2, offset"),
+        ("lui t0", "rd, imm"),
+        ("jal", "rd, offset"),
+    ]
+    .iter()
+    {
+        std::assert_eq!(
+            Err(AssemblerError::OperandCountError {
+                op: ir.split(' ').next().unwrap().to_owned(),
+                expected
+            }),
+            assemble_ir(ir, &mut labels, 0),
+            "{}",
+            ir
+        );
+    }
+    std::assert_eq!(
+        Err(AssemblerError::OperandCountError {
+            op: "lui".to_owned(),
+            expected: "rd, imm"
+        }),
+        assemble_ir("lui t0, 4, 5", &mut labels, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::OperandCountError {
+            op: "addi".to_owned(),
+            expected: "rd, rs1, imm"
+        }),
+        assemble_program("addi x1, x2")
+    );
+}
+
+#[test]
+fn test_assemble_base() {
+    let program = "start: addi t0, t1, 0\n\
+                   beq t0, t1, start\n\
+                   lui t0, %hi(data)\n\
+                   data: .word data, start";
+    let mut assembler = Assembler::new();
+    assembler.base(0x8000_0000).insert_source(program);
+
+    let (labels, _) = assembler.parse_labels().unwrap();
+    std::assert_eq!(Some(&0x8000_000c), labels.get("data"));
+
+    let prog = assembler.assemble().unwrap();
+    let at_zero = assemble_program(program).unwrap();
+    // The branch is pc-relative, so it does not depend on the base.
+    std::assert_eq!(at_zero.words[1], prog.words[1]);
+    std::assert_eq!(
+        "lui t0, 0x80000",
+        disassemble(prog.words[2]).unwrap().to_string()
+    );
+    std::assert_eq!(0x8000_000c, prog.words[3]);
+    std::assert_eq!(0x8000_0000, prog.words[4]);
+}
+
+#[test]
+fn test_assemble_org_align() {
+    let mut assembler = Assembler::new();
+    assembler.strict(true);
+    assembler.insert_source(".org 0x2\naddi t0, t1, 0");
+    std::assert_eq!(
+        Err(AssemblerError::MisalignedInstructionError { pc: 2 }),
+        assembler.assemble()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.strict(true);
+    assembler.insert_source(
+        "addi t0, t1, 0\n\
+         .byte 1\n\
+         .org 0x9\n\
+         .byte 2\n\
+         .align 4\n\
+         start: addi t0, t1, 0\n\
+         .word start",
+    );
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        vec![
+            instructions::ADDI_X5_X6_0,
+            0x01,
+            0,
+            0x02,
+            instructions::ADDI_X5_X6_0,
+            0x10
+        ],
+        prog.words
+    );
+    std::assert_eq!(Some(&0x10), prog.symbols.get("start"));
+
+    std::assert_eq!(
+        Err(AssemblerError::InvalidImmediateError("0".to_owned())),
+        assemble_program("addi t0, t1, 0\n.org 0")
+    );
+
+    // Reaching the end of the address space is an error, not a panic. The
+    // last word is not usable, since the pc after it would be 2^32.
+    let mut assembler = Assembler::new();
+    assembler
+        .base(0xffff_fff0)
+        .insert_source("nop\nnop\nnop\nnop\nnop\nnop");
+    std::assert_eq!(
+        Err(AssemblerError::AddressOverflowError { pc: 0xffff_fffc }),
+        assembler.assemble()
+    );
+    std::assert_eq!(
+        Err(AssemblerError::AddressOverflowError { pc: 0xffff_fffc }),
+        assemble_program(".org 0xfffffff0\nnop\nnop\nnop\nnop\nnop")
+    );
+    std::assert_eq!(
+        vec![3, 4, 5],
+        assembler
+            .validate()
+            .iter()
+            .map(|d| d.line)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_decode_fields() {
+    let fields = decode_fields(instructions::ADDI_X5_X6_NEG_12);
+    std::assert_eq!(Some(InstructionFormat::Itype), fields.format);
+    std::assert_eq!(OPCODE_ARITHMETIC_IMM, fields.opcode);
+    std::assert_eq!((5, 6, FUNC3_ADD_SUB), (fields.rd, fields.rs1, fields.func3));
+    std::assert_eq!(-12, fields.imm);
+
+    let fields = decode_fields(instructions::SUB_X5_X5_X5);
+    std::assert_eq!(Some(InstructionFormat::Rtype), fields.format);
+    std::assert_eq!(OPCODE_ARITHMETIC, fields.opcode);
+    std::assert_eq!((5, 5, 5), (fields.rd, fields.rs1, fields.rs2));
+    std::assert_eq!((FUNC3_ADD_SUB, FUNC7_SUB), (fields.func3, fields.func7));
+    std::assert_eq!(0, fields.imm);
+
+    let fields = decode_fields(instructions::SW_X5_NEG_40_X5);
+    std::assert_eq!(Some(InstructionFormat::Stype), fields.format);
+    std::assert_eq!((5, 5, FUNC3_SW), (fields.rs1, fields.rs2, fields.func3));
+    std::assert_eq!(-40, fields.imm);
+
+    let fields = decode_fields(instructions::BNE_X0_X5_NEG_4);
+    std::assert_eq!(Some(InstructionFormat::Btype), fields.format);
+    std::assert_eq!((0, 5, FUNC3_BNE), (fields.rs1, fields.rs2, fields.func3));
+    std::assert_eq!(-4, fields.imm);
+
+    let fields = decode_fields(instructions::LUI_X5_4);
+    std::assert_eq!(Some(InstructionFormat::Utype), fields.format);
+    std::assert_eq!((OPCODE_LUI, 5, 4), (fields.opcode, fields.rd, fields.imm));
+
+    let fields = decode_fields(instructions::JAL_X0_NEG_8);
+    std::assert_eq!(Some(InstructionFormat::Jtype), fields.format);
+    std::assert_eq!((OPCODE_JAL, 0, -8), (fields.opcode, fields.rd, fields.imm));
+
+    let fields = decode_fields(0);
+    std::assert_eq!(None, fields.format);
+    std::assert_eq!(0, fields.imm);
+}
+
+#[test]
+fn test_assemble_constants() {
+    let prog = assemble_program(
+        "b = a + 1\n\
+         a = 1\n\
+         .equ size, end - start\n\
+         start: addi t0, zero, b\n\
+         .set mask, -1\n\
+         andi t0, t0, mask\n\
+         end: .word size, a+b",
+    )
+    .unwrap();
+    std::assert_eq!(Some(&1), prog.constants.get("a"));
+    std::assert_eq!(Some(&2), prog.constants.get("b"));
+    std::assert_eq!(Some(&8), prog.constants.get("size"));
+    std::assert_eq!(Some(&0xffff_ffff), prog.constants.get("mask"));
+    std::assert_eq!(None, prog.symbols.get("a"));
+    std::assert_eq!(None, prog.constants.get("start"));
+    std::assert_eq!(
+        "addi t0, zero, 2",
+        disassemble(prog.words[0]).unwrap().to_string()
+    );
+    std::assert_eq!(
+        "andi t0, t0, -1",
+        disassemble(prog.words[1]).unwrap().to_string()
+    );
+    std::assert_eq!(&[8, 3], &prog.words[2..]);
+
+    std::assert_eq!(
+        Err(AssemblerError::CircularConstantError("a".to_owned())),
+        assemble_program("a = b + 1\nb = a - 1")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchLabelError("missing".to_owned())),
+        assemble_program("a = missing")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::DuplicateLabelError("a".to_owned())),
+        assemble_program("a: addi t0, t1, 0\n.equ a, 1")
+    );
+}
+
+#[test]
+fn test_assemble_float_immediate() {
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    for (ir, token) in [
+        ("addi x1, x0, 1.0", "1.0"),
+        ("addi x1, x0, -3.14", "-3.14"),
+        ("lui x1, .5", ".5"),
+    ]
+    .iter()
+    {
+        std::assert_eq!(
+            Err(AssemblerError::FloatNotSupportedError {
+                token: (*token).to_owned()
+            }),
+            assemble_ir(ir, &mut labels, 0)
+        );
+    }
+
+    labels.insert(".l1".to_owned(), 8);
+    std::assert!(assemble_ir("jal x1, .l1", &mut labels, 0).is_ok());
+}
+
+#[test]
+fn test_warnings_as_errors() {
+    let program = "start: addi t0, t1, 0\n\
+                   loop: addi t0, t0, 1\n\
+                   bne t0, t1, loop";
+    let mut assembler = Assembler::new();
+    assembler.warn_unused_labels(true).insert_source(program);
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        vec![Diagnostic::warning(
+            0,
+            "label `start` is never used".to_owned()
+        )],
+        prog.diagnostics
+    );
+
+    assembler.warnings_as_errors(true);
+    std::assert_eq!(
+        Err(AssemblerError::WarningsDeniedError(prog.diagnostics)),
+        assembler.assemble()
+    );
+    std::assert_eq!(
+        vec![Diagnostic::error(
+            0,
+            "label `start` is never used".to_owned()
+        )],
+        assembler.validate()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler
+        .warn_unused_labels(true)
+        .warnings_as_errors(true)
+        .insert_source(&format!(".globl start\n{}", program));
+    std::assert!(assembler.assemble().is_ok());
+}
+
+#[test]
+fn test_relax_branches() {
+    let program = "beqz a0, far\n\
+                   bltu a0, a1, near\n\
+                   near: .word 0 : 1100\n\
+                   far: addi t0, t1, 0";
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble_program(program)
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.relax(true).insert_source(program);
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(Some(&4412), prog.symbols.get("far"));
+    std::assert_eq!(
+        "bne a0, zero, 8",
+        disassemble(prog.words[0]).unwrap().to_string()
+    );
+    // The jump is from 0x4, past the in-range branch and the data.
+    std::assert_eq!(
+        "jal zero, 4408",
+        disassemble(prog.words[1]).unwrap().to_string()
+    );
+    std::assert_eq!(
+        "bltu a0, a1, 4",
+        disassemble(prog.words[2]).unwrap().to_string()
+    );
+    std::assert_eq!(vec![0, 0, 1], prog.line_map[..3].to_vec());
+    std::assert_eq!(instructions::ADDI_X5_X6_0, *prog.words.last().unwrap());
+}
+
+#[test]
+fn test_operand_signature() {
+    std::assert_eq!(Some("rd, rs1, imm"), operand_signature("addi"));
+    std::assert_eq!(Some("rs2, offset(rs1)"), operand_signature("sw"));
+    std::assert_eq!(Some(""), operand_signature("nop"));
+    std::assert_eq!(None, operand_signature("fadd.s"));
+    for op in supported_mnemonics() {
+        std::assert!(operand_signature(op).is_some(), "{}", op);
+    }
+
+    let diagnostics = validate("addi x1");
+    std::assert_eq!(1, diagnostics.len());
+    std::assert!(
+        diagnostics[0].message.contains("rd, rs1, imm"),
+        "{}",
+        diagnostics[0].message
+    );
+}
+
+#[test]
+fn test_assemble_to_writer() {
+    let program = "start: addi t0, t1, 0\nlui t0, 4\n.word 1, 2, 3, 4 : 2\nj start";
+    let prog = assemble_program(program).unwrap();
+    for (format, expected) in [
+        (OutputFormat::LeBytes, prog.to_bytes(Endian::Little)),
+        (OutputFormat::BeBytes, prog.to_bytes(Endian::Big)),
+        (OutputFormat::IntelHex, prog.to_ihex().into_bytes()),
+        (
+            OutputFormat::Readmemh(MemWidth::Half),
+            prog.to_readmemh(MemWidth::Half).into_bytes(),
+        ),
+        (OutputFormat::Srec, prog.to_srec().into_bytes()),
+        (OutputFormat::Listing, prog.to_listing().into_bytes()),
+    ]
+    .iter()
+    {
+        let mut out = Vec::new();
+        assemble_to_writer(program, &mut out, *format).unwrap();
+        std::assert_eq!(*expected, out, "{:?}", format);
+    }
+
+    let mut out = Vec::new();
+    std::assert_eq!(
+        Err(AssemblerError::InvalidOperationError("bogus".to_owned())),
+        assemble_to_writer("bogus t0", &mut out, OutputFormat::LeBytes)
+    );
+}
+
+#[test]
+fn test_ihex_srec() {
+    let prog = assemble_program("addi t0, t1, 0").unwrap();
+    std::assert_eq!(":040000009302030064\n:00000001FF\n", prog.to_ihex());
+    std::assert_eq!(
+        "S0030000FC\nS30900000000930203005E\nS70500000000FA\n",
+        prog.to_srec()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler
+        .base(0x8000_fff8)
+        .insert_source(".word 0x03020100, 0x07060504, 0x0b0a0908");
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        ":0200000480007A\n\
+         :08FFF8000001020304050607E5\n\
+         :02000004800179\n\
+         :0400000008090A0BD6\n\
+         :00000001FF\n",
+        prog.to_ihex()
+    );
+    std::assert!(prog.to_srec().ends_with("S7058000FFF883\n"));
+}
+
+#[test]
+fn test_li_sizing() {
+    let program = "li x1, 0\n\
+                   a: li x1, 0x12345\n\
+                   b: li x1, 0x12000\n\
+                   c: li x1, -1\n\
+                   d: li x1, d\n\
+                   e: jal x0, a\n\
+                   k = 0x12345\n\
+                   li x1, k\n\
+                   f:";
+    let labels = parse_labels(program).unwrap();
+    for (label, addr) in [
+        ("a", 4),
+        ("b", 12),
+        ("c", 16),
+        ("d", 20),
+        ("e", 28),
+        ("f", 40),
+    ]
+    .iter()
+    {
+        std::assert_eq!(Some(addr), labels.get(*label), "{}", label);
+    }
+
+    let prog = assemble_program(program).unwrap();
+    std::assert_eq!(10, prog.words.len());
+    std::assert_eq!(labels, prog.symbols.into_iter().collect::<HashMap<_, _>>());
+    std::assert_eq!(
+        "jal zero, -24",
+        disassemble(prog.words[7]).unwrap().to_string()
+    );
+}
+
+#[test]
+fn test_pad_image() {
+    let mut assembler = Assembler::new();
+    assembler
+        .pad_to_pow2(true)
+        .pad_fill(0xff)
+        .insert_source("addi t0, t1, 0\n.byte 1 : 33\nend:");
+    std::assert_eq!(Ok(64), assembler.size());
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(Some(&40), prog.symbols.get("end"));
+    let bytes = prog.to_bytes(Endian::Little);
+    std::assert_eq!(64, bytes.len());
+    std::assert_eq!(vec![1; 33], bytes[4..37].to_vec());
+    // The directive pads itself to a word with zeros first.
+    std::assert_eq!(vec![0; 3], bytes[37..40].to_vec());
+    std::assert_eq!(vec![0xff; 24], bytes[40..].to_vec());
+    std::assert_eq!(16, prog.to_hex(Endian::Little).lines().count());
+
+    let mut assembler = Assembler::new();
+    assembler.pad_to(4096).insert_source("addi t0, t1, 0");
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(1024, prog.words.len());
+    std::assert_eq!(0, *prog.words.last().unwrap());
+
+    assembler.pad_to(2);
+    std::assert_eq!(
+        Err(AssemblerError::ImageTooLargeError {
+            needed: 4,
+            capacity: 2
+        }),
+        assembler.assemble()
+    );
+}
+
+#[test]
+fn test_parse_string() {
+    let tokens = tokenize!(".file \"My File.s\" # the \"name\"");
+    std::assert_eq!(vec![".file", "\"My File.s\""], tokens);
+    std::assert_eq!(Some("My File.s".to_owned()), parse_string(&tokens[1]));
+
+    let tokens = tokenize!(".file \"say \\\"hi\\\", # not a comment\"// comment");
+    std::assert_eq!(2, tokens.len());
+    std::assert_eq!(
+        Some("say \"hi\", # not a comment".to_owned()),
+        parse_string(&tokens[1])
+    );
+
+    std::assert_eq!(
+        Some("a\\b\n\t\0'".to_owned()),
+        parse_string("\"a\\\\b\\n\\t\\0\\'\"")
+    );
+    std::assert_eq!(Some(String::new()), parse_string("\"\""));
+    for bad in [
+        "name",
+        "\"open",
+        "\"bad \\q escape\"",
+        "\"a\"b\"",
+        "\"",
+        "\"end\\\"",
+    ]
+    .iter()
+    {
+        std::assert_eq!(None, parse_string(bad), "{}", bad);
+    }
+}
+
+#[test]
+fn test_assemble_line() {
+    let labels = HashMap::new();
+    let (words, pc) = assemble_line("li t0, 0x12345", &labels, 8).unwrap();
+    std::assert_eq!(
+        assemble_program("lui t0, 0x12\naddi t0, t0, 0x345")
+            .unwrap()
+            .words,
+        words
+    );
+    std::assert_eq!(16, pc);
+
+    std::assert_eq!(
+        Ok((vec![0x0000_006f], 4)),
+        assemble_line("here: jal zero, here", &labels, 0)
+    );
+    std::assert!(labels.is_empty());
+    std::assert_eq!(
+        Ok((Vec::new(), 12)),
+        assemble_line("  # nothing here", &labels, 12)
+    );
+}
+
+#[test]
+fn test_report_targets() {
+    let mut assembler = Assembler::new();
+    assembler.report_targets(true).warnings_as_errors(true);
+    assembler.insert_source(
+        "loop: addi t0, t0, 1\n\
+         beq t0, t1, loop\n\
+         j 8\n\
+         addi t0, t1, 0",
+    );
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        vec![
+            Diagnostic::info(1, "`beq` resolves to 0x0 (label `loop`)".to_owned()),
+            Diagnostic::info(2, "`jal` resolves to 0x10".to_owned()),
+        ],
+        prog.diagnostics
+    );
+    std::assert!(assembler
+        .validate()
+        .iter()
+        .all(|d| d.severity == Severity::Info));
+
+    assembler.report_targets(false);
+    std::assert!(assembler.assemble().unwrap().diagnostics.is_empty());
+}
+
+#[test]
+fn test_register_alias() {
+    let prog = assemble_program(
+        ".equ counter, t0\n\
+         limit = x6\n\
+         loop: addi counter, counter, 1\n\
+         mv a0, counter\n\
+         lw a1, 4(limit)\n\
+         bne counter, limit, loop",
+    )
+    .unwrap();
+    std::assert_eq!(Some(&5), prog.register_aliases.get("counter"));
+    std::assert_eq!(Some(&6), prog.register_aliases.get("limit"));
+    std::assert!(prog.constants.is_empty());
+    std::assert_eq!(
+        assemble_program(
+            "loop: addi t0, t0, 1\n\
+             mv a0, t0\n\
+             lw a1, 4(t1)\n\
+             bne t0, t1, loop"
+        )
+        .unwrap()
+        .words,
+        prog.words
+    );
+
+    std::assert_eq!(
+        Err(AssemblerError::ReservedNameError("t1".to_owned())),
+        assemble_program(".equ t1, t0")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ReservedNameError("a0".to_owned())),
+        assemble_program("a0 = 5")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("x32".to_owned())),
+        assemble_program(".equ r, x32")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::DuplicateLabelError("r".to_owned())),
+        assemble_program("r: .equ r, t0")
+    );
+}
+
+#[test]
+fn test_split_ordering() {
+    std::assert_eq!(
+        Ok(("amoadd.w", 0x0600_0000)),
+        split_ordering("amoadd.w.aqrl")
+    );
+    std::assert_eq!(Ok(("lr.w", 0x0400_0000)), split_ordering("lr.w.aq"));
+    std::assert_eq!(Ok(("sc.w", 0x0200_0000)), split_ordering("sc.w.rl"));
+    std::assert_eq!(Ok(("amomin.w", 0)), split_ordering("amomin.w"));
+    std::assert_eq!(Ok(("amominu.w", 0)), split_ordering("amominu.w"));
+    std::assert_eq!(Ok(("addi", 0)), split_ordering("addi"));
+    std::assert_eq!(
+        Err(AssemblerError::InvalidOrderingError(".xx".to_owned())),
+        split_ordering("amoswap.w.xx")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidOrderingError(".aq.rl".to_owned())),
+        split_ordering("lr.w.aq.rl")
+    );
+}
+
+#[test]
+fn test_branch_offset() {
+    std::assert_eq!(Ok(16), branch_offset(0x20, 0x30));
+    std::assert_eq!(Ok(-16), branch_offset(0x30, 0x20));
+    std::assert_eq!(Ok(4094), branch_offset(0, 4094));
+    std::assert_eq!(Ok(-4096), branch_offset(4096, 0));
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        branch_offset(0, 4096)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        branch_offset(4098, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateMisalignedError),
+        branch_offset(0x20, 0x23)
+    );
+
+    // Must agree with what the assembler encodes for the same distance.
+    let mut labels = HashMap::new();
+    labels.insert("loop".to_owned(), 0x10);
+    let ir = assemble_ir("bne t0, t1, loop", &mut labels, 0x20)
+        .unwrap()
+        .unwrap();
+    std::assert_eq!(Ok(decode_fields(ir).imm), branch_offset(0x20, 0x10));
+}
+
+#[test]
+fn test_jal_offset() {
+    std::assert_eq!(Ok(0x800), jal_offset(0x100, 0x900));
+    std::assert_eq!(Ok(-8), jal_offset(0x108, 0x100));
+    std::assert_eq!(Ok(0xf_fffe), jal_offset(0, 0xf_fffe));
+    std::assert_eq!(Ok(-0x10_0000), jal_offset(0x10_0000, 0));
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        jal_offset(0, 0x10_0000)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        jal_offset(0x10_0002, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateMisalignedError),
+        jal_offset(0, 0x101)
+    );
+}
+
+#[test]
+fn test_forward_data_reference() {
+    let prog = assemble_program(
+        ".text\n\
+         lui t0, %hi(table)\n\
+         addi t0, t0, %lo(table)\n\
+         lw t1, 0(t0)\n\
+         .data\n\
+         pad: .word 0, 0\n\
+         table: .word 0x1234",
+    )
+    .unwrap();
+    std::assert_eq!(6, prog.words.len());
+    std::assert_eq!(Some(&0x14), prog.symbols.get("table"));
+    std::assert_eq!(
+        assemble_ir("addi t0, t0, 0x14", &mut HashMap::new(), 4).unwrap(),
+        Some(prog.words[1])
+    );
+    std::assert_eq!(0x1234, prog.words[5]);
+
+    // `la` reaches the same forward `.data` symbol relative to its `auipc`.
+    let prog = assemble_program(
+        ".text\n\
+         la a0, table\n\
+         lw a1, 0(a0)\n\
+         .data\n\
+         pad: .word 0, 0\n\
+         table: .word 0x1234",
+    )
+    .unwrap();
+    std::assert_eq!(Some(&0x14), prog.symbols.get("table"));
+    std::assert_eq!(0x14, resolve_pcrel_pair(prog.words[0], prog.words[1], 0));
+    std::assert_eq!(0x1234, prog.words[5]);
+}
+
+#[test]
+fn test_la() {
+    let mut labels = HashMap::new();
+    let mut word = |ir: &str| assemble_ir(ir, &mut labels, 0).unwrap().unwrap();
+
+    // The lower part sign-extends, so the upper part carries.
+    let prog = assemble_program("nop\nla a0, far\n.org 0x804\nfar: nop").unwrap();
+    std::assert_eq!(Some(&0x804), prog.symbols.get("far"));
+    std::assert_eq!(word("auipc a0, 1"), prog.words[1]);
+    std::assert_eq!(word("addi a0, a0, -0x800"), prog.words[2]);
+    std::assert_eq!(0x804, resolve_pcrel_pair(prog.words[1], prog.words[2], 4));
+
+    // The pair is the same written out, and backward targets work.
+    std::assert_eq!(
+        assemble_program("start: nop\nauipc t0, %pcrel_hi(start)\naddi t0, t0, %pcrel_lo(start)")
+            .unwrap()
+            .words,
+        assemble_program("start: nop\nla t0, start").unwrap().words
+    );
+    std::assert_eq!(
+        0,
+        resolve_pcrel_pair(
+            assemble_ir("auipc t0, %pcrel_hi(0)", &mut HashMap::new(), 0x1000)
+                .unwrap()
+                .unwrap(),
+            assemble_ir("addi t0, t0, %pcrel_lo(0)", &mut HashMap::new(), 0x1004)
+                .unwrap()
+                .unwrap(),
+            0x1000
+        )
+    );
+
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchLabelError("nowhere".to_owned())),
+        assemble_program("la a0, nowhere")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidImmediateError(
+            "%pcrel_hi".to_owned()
+        )),
+        assemble_program("auipc a0, %pcrel_hi")
+    );
+    std::assert!(assemble_program("la a0").is_err());
+    let (instructions, diagnostics) = parse_program("la a0, end\nend: nop");
+    std::assert!(diagnostics.is_empty());
+    std::assert_eq!(3, instructions.len());
+    std::assert!(supported_mnemonics().contains(&"la"));
+}
+
+#[test]
+fn test_immediate_boundary_hint() {
+    let diagnostics = validate("addi x1, x0, 2048\nlw t0, -2049(t1)\naddi x1, x0, 4096");
+    std::assert_eq!(
+        vec![
+            (0, Severity::Error, "immediate is out of range"),
+            (
+                0,
+                Severity::Warning,
+                "2048 is one past the maximum 2047; did you mean a different instruction or a `li`?"
+            ),
+            (1, Severity::Error, "immediate is out of range"),
+            (
+                1,
+                Severity::Warning,
+                "-2049 is one past the minimum -2048; did you mean a different instruction or a `li`?"
+            ),
+            (2, Severity::Error, "immediate is out of range"),
+        ],
+        diagnostics
+            .iter()
+            .map(|d| (d.line, d.severity, &d.message[..]))
+            .collect::<Vec<_>>()
+    );
+    std::assert!(validate("addi x1, x0, 2047\naddi x1, x0, -2048").is_empty());
+}
+
+#[test]
+fn test_assemble_to_writers() {
+    let program = "start: addi t0, t1, 0\n.word 0xffffffff\nbne t0, t1, start";
+    let mut listing = Vec::new();
+    let mut hex = Vec::new();
+    assemble_to_writers(
+        program,
+        &mut [
+            (OutputFormat::Listing, &mut listing),
+            (OutputFormat::IntelHex, &mut hex),
+        ],
+    )
+    .unwrap();
+    std::assert_eq!(
+        "00000000: 00030293  addi t0, t1, 0\n\
+         00000004: ffffffff  .word 0xffffffff\n\
+         00000008: fe629ce3  bne t0, t1, -8  # 0x0 <start>\n",
+        String::from_utf8(listing).unwrap()
+    );
+    let prog = assemble_program(program).unwrap();
+    std::assert_eq!(prog.to_ihex(), String::from_utf8(hex).unwrap());
+
+    let mut out = Vec::new();
+    std::assert_eq!(
+        Err(AssemblerError::InvalidOperationError("bogus".to_owned())),
+        assemble_to_writers("bogus t0", &mut [(OutputFormat::Listing, &mut out)])
+    );
+    std::assert!(out.is_empty());
+}
+
+#[test]
+fn test_spaced_offset() {
+    let mut labels = HashMap::new();
+    for ir in [
+        "lw t0, -12(t1)",
+        "lw t0, -12 (t1)",
+        "lw t0, -12( t1 )",
+        "lw t0, -12 ( t1 )",
+        "lw t0,-12(t1)",
+        "lw t0, (-12) (t1)",
+    ]
+    .iter()
+    {
+        std::assert_eq!(vec!["lw", "t0", "-12", "t1"], tokenize!(ir), "{}", ir);
+        std::assert_eq!(
+            Ok(Some(
+                assemble_ir("lw t0, -12(t1)", &mut labels, 0)
+                    .unwrap()
+                    .unwrap()
+            )),
+            assemble_ir(ir, &mut labels, 0),
+            "{}",
+            ir
+        );
+    }
+}
+
+#[test]
+fn test_max_bytes() {
+    let mut assembler = Assembler::new();
+    assembler
+        .max_bytes(8)
+        .insert_source("addi t0, t1, 0\naddi t0, t1, 0\naddi t0, t1, 0\nbogus");
+    // The third line is the first that does not fit, and nothing after it
+    // is looked at.
+    std::assert_eq!(
+        Err(AssemblerError::ImageTooLargeError {
+            needed: 12,
+            capacity: 8
+        }),
+        assembler.assemble()
+    );
+    let diagnostics = assembler.validate();
+    std::assert_eq!(1, diagnostics.len());
+    std::assert_eq!(2, diagnostics[0].line);
+
+    // A huge `.org` is rejected before any of it is filled.
+    let mut assembler = Assembler::new();
+    assembler
+        .max_bytes(1024)
+        .insert_source(".org 0x40000000\nnop");
+    std::assert_eq!(
+        Err(AssemblerError::ImageTooLargeError {
+            needed: 0x4000_0000,
+            capacity: 1024
+        }),
+        assembler.assemble()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.max_bytes(8).insert_source("nop");
+    assembler.insert_bytes(None, &[1, 2, 3, 4, 5]);
+    std::assert_eq!(
+        Err(AssemblerError::ImageTooLargeError {
+            needed: 9,
+            capacity: 8
+        }),
+        assembler.assemble()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.max_bytes(8).pad_to(16).insert_source("nop");
+    std::assert_eq!(
+        Err(AssemblerError::ImageTooLargeError {
+            needed: 16,
+            capacity: 8
+        }),
+        assembler.assemble()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.max_bytes(8).insert_source("nop\nnop");
+    std::assert_eq!(2, assembler.assemble().unwrap().words.len());
+}
+
+#[test]
+fn test_optimize_set_branch() {
+    let program =
+        "top: seqz t0, a0\nbnez t0, done\naddi a1, a1, 1\ndone: snez t1, a1\nbeqz t1, top";
+    let mut assembler = Assembler::new();
+    assembler.optimize(true);
+    assembler.insert_source(program);
+    let prog = assembler.assemble().unwrap();
+
+    // Each pair is one branch, so `done` moves up a word.
+    std::assert_eq!(Some(&8), prog.symbols.get("done"));
+    let mut labels = HashMap::new();
+    std::assert_eq!(
+        vec![
+            assemble_ir("beq a0, zero, 8", &mut labels, 0)
+                .unwrap()
+                .unwrap(),
+            assemble_ir("addi a1, a1, 1", &mut labels, 0)
+                .unwrap()
+                .unwrap(),
+            assemble_ir("beq a1, zero, -8", &mut labels, 0)
+                .unwrap()
+                .unwrap(),
+        ],
+        prog.words
+    );
+    std::assert_eq!(vec![0, 2, 3], prog.line_map);
+
+    // Not folded: the flag is read elsewhere, the branch is a target, or
+    // its offset is a literal.
+    for program in [
+        "seqz t0, a0\nbnez t0, end\nadd a1, t0, a1\nend:",
+        "seqz t0, a0\nagain: bnez t0, again",
+        "seqz t0, a0\nbnez t0, 8",
+    ]
+    .iter()
+    {
+        let mut assembler = Assembler::new();
+        assembler.optimize(true);
+        assembler.insert_source(program);
+        std::assert_eq!(
+            assemble_program(program).unwrap().words,
+            assembler.assemble().unwrap().words,
+            "{}",
+            program
+        );
+    }
+}
+
+#[test]
+fn test_parse_instruction_pseudo() {
+    let ret = parse_instruction("ret").unwrap().unwrap();
+    std::assert_eq!(
+        Instruction::Pseudo {
+            name: "ret".to_owned(),
+            operands: vec![],
+        },
+        ret
+    );
+    std::assert_eq!("ret", ret.to_string());
+    std::assert_eq!(Ok(vec![0x0000_8067]), ret.encode(&HashMap::new(), 0));
+    std::assert_eq!(
+        Ok(Some(0x0000_8067)),
+        assemble_ir("jalr zero, ra, 0", &mut HashMap::new(), 0)
+    );
+
+    // Operands of pseudo-instructions are kept as written, labels included.
+    let mut labels = HashMap::new();
+    labels.insert("loop".to_owned(), 0x10);
+    let beqz = parse_instruction("top: beqz t0, loop").unwrap().unwrap();
+    std::assert_eq!("beqz t0, loop", beqz.to_string());
+    std::assert_eq!(
+        assemble_line("beq t0, zero, loop", &labels, 0x20).map(|(w, _)| w),
+        beqz.encode(&labels, 0x20)
+    );
+    std::assert_eq!(
+        2,
+        parse_instruction("li t0, 0x12345")
+            .unwrap()
+            .unwrap()
+            .encode(&labels, 0)
+            .unwrap()
+            .len()
+    );
+
+    // Base instructions are parsed into their typed form.
+    std::assert_eq!(
+        Ok(Some(Instruction::Itype {
+            op: "jalr".to_owned(),
+            rd: 5,
+            rs1: 5,
+            imm: 4,
+        })),
+        parse_instruction("jalr t0, t0, 4")
+    );
+    std::assert_eq!(
+        Ok(vec![instructions::JALR_X5_X5_4]),
+        parse_instruction("jalr t0, t0, 4")
+            .unwrap()
+            .unwrap()
+            .encode(&labels, 0)
+    );
+    std::assert_eq!(Ok(None), parse_instruction("label:"));
+}
+
+#[test]
+fn test_assemble_expect() {
+    let program = "addi t0, t1, 0\nlui t0, 4";
+    std::assert_eq!(
+        Ok(()),
+        testing::assemble_expect(program, "00030293\n000042B7")
+    );
+    std::assert_eq!(
+        Err("  00000000: 00030293\n\
+             - 00000004: 000052b7\n\
+             + 00000004: 000042b7\n\
+             - 00000008: 00000013\n"
+            .to_owned()),
+        testing::assemble_expect(program, "00030293 000052b7 00000013")
+    );
+    std::assert_eq!(
+        Err("+ 00000000: 00030293\n+ 00000004: 000042b7\n".to_owned()),
+        testing::assemble_expect(program, "")
+    );
+    std::assert_eq!(
+        Err("`nop` is not a hex word".to_owned()),
+        testing::assemble_expect(program, "nop")
+    );
+    std::assert_eq!(
+        Err("InvalidOperationError(\"bogus\")".to_owned()),
+        testing::assemble_expect("bogus", "")
+    );
+}
+
+#[test]
+fn test_self_test() {
+    std::assert_eq!(Ok(()), testing::self_test());
+}
+
+#[test]
+fn test_entry() {
+    let program = "nop\n.globl _start\n_start: addi t0, t1, 0\nj _start";
+    let u32_at =
+        |elf: &[u8], i: usize| u32::from_le_bytes([elf[i], elf[i + 1], elf[i + 2], elf[i + 3]]);
+
+    let mut assembler = Assembler::new();
+    assembler.entry("_start").insert_source(program);
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(Some(4), prog.entry);
+    // e_entry is the address of `_start`.
+    std::assert_eq!(prog.symbols["_start"], u32_at(&prog.to_elf(), 24));
+    // The S-record termination record agrees with it.
+    std::assert!(prog.to_srec().ends_with("S70500000004F6\n"));
+    std::assert!(prog.to_srec_sparse().ends_with("S70500000004F6\n"));
+
+    // Without an entry point, it is the base address.
+    let mut assembler = Assembler::new();
+    assembler.base(0x100).insert_source(program);
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(None, prog.entry);
+    std::assert_eq!(0x100, u32_at(&prog.to_elf(), 24));
+    std::assert!(prog.to_srec().ends_with("S70500000100F9\n"));
+
+    let mut assembler = Assembler::new();
+    assembler.entry("main").insert_source(program);
+    std::assert_eq!(
+        Err(AssemblerError::UndefinedEntryError("main".to_owned())),
+        assembler.assemble()
+    );
+}
+
+#[test]
+fn test_tokenize_unspaced_commas() {
+    let mut labels = HashMap::new();
+    let spaced = tokenize!("add x1, x2, x3");
+    std::assert_eq!(vec!["add", "x1", "x2", "x3"], spaced);
+    for ir in ["add x1,x2,x3", "add x1 ,x2 , x3", "add\tx1,\tx2,x3"].iter() {
+        std::assert_eq!(spaced, tokenize!(ir), "{}", ir);
+        std::assert_eq!(
+            assemble_ir("add x1, x2, x3", &mut labels, 0),
+            assemble_ir(ir, &mut labels, 0),
+            "{}",
+            ir
+        );
+    }
+}
+
+#[test]
+fn test_error_tokens() {
+    let mut labels = HashMap::new();
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("q9".to_owned())),
+        match_register("q9")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("xa".to_owned())),
+        match_register("xa")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidOperationError("frob".to_owned())),
+        match_opcode("frob")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidImmediateError("12abc".to_owned())),
+        parse_imm("12abc", &labels, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchLabelError("nowhere".to_owned())),
+        parse_imm("nowhere", &labels, 0)
+    );
+
+    // Errors from whole instructions carry the operand that failed.
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("t9".to_owned())),
+        assemble_ir("add t0, t9, t1", &mut labels, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidOperationError("ad".to_owned())),
+        assemble_ir("ad t0, t1, t2", &mut labels, 0)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchLabelError("done".to_owned())),
+        assemble_ir("beq t0, t1, done", &mut labels, 0)
+    );
+}
+
+#[test]
+fn test_p2align_max() {
+    let prog = assemble_program(
+        "nop\n\
+         .p2align 4, 0xff, 4\n\
+         a: nop\n\
+         .p2align 4, 0xff, 8\n\
+         b: nop\n\
+         .align 3, 0, 3\n\
+         c: nop",
+    )
+    .unwrap();
+    let nop = 0x0000_0013;
+    // The first alignment would skip 12 bytes, more than its maximum of 4,
+    // so it is dropped. The second skips exactly its maximum of 8.
+    std::assert_eq!(Some(&4), prog.symbols.get("a"));
+    std::assert_eq!(Some(&16), prog.symbols.get("b"));
+    std::assert_eq!(Some(&20), prog.symbols.get("c"));
+    std::assert_eq!(
+        vec![nop, nop, 0xffff_ffff, 0xffff_ffff, nop, nop],
+        prog.words
+    );
+
+    // An empty fill is the default fill, and the last operand the maximum.
+    let prog = assemble_program("nop\n.p2align 4,,8\na: nop\n.p2align 4,,12\nb: nop").unwrap();
+    std::assert_eq!(Some(&4), prog.symbols.get("a"));
+    std::assert_eq!(Some(&16), prog.symbols.get("b"));
+    std::assert_eq!(vec![nop, nop, 0, 0, nop], prog.words);
+    let prog = assemble_program("nop\n.align 3,,4\nc: nop").unwrap();
+    std::assert_eq!(Some(&8), prog.symbols.get("c"));
+    std::assert_eq!(vec![nop, 0, nop], prog.words);
+
+    std::assert_eq!(
+        assemble_program("nop\n.align 4").unwrap().words,
+        assemble_program("nop\n.p2align 4").unwrap().words
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble_program("nop\n.p2align 4, 0x100")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooManyTokensError),
+        assemble_program(".p2align 4, 0, 8, 1")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooManyTokensError),
+        assemble_program(".org 4, 0")
+    );
+}
+
+#[test]
+fn test_listing_base() {
+    let mut assembler = Assembler::new();
+    assembler
+        .base(0x8000_0000)
+        .insert_source("loop: addi t0, t0, 1\nbne t0, t1, loop\nj end\nend: nop");
+    std::assert_eq!(
+        "80000000: 00128293  addi t0, t0, 1\n\
+         80000004: fe629ee3  bne t0, t1, -4  # 0x80000000 <loop>\n\
+         80000008: 0040006f  jal zero, 4  # 0x8000000c <end>\n\
+         8000000c: 00000013  addi zero, zero, 0\n",
+        assembler.assemble().unwrap().to_listing()
+    );
+}
+
+#[test]
+fn test_load_store_widths() {
+    let mut labels = HashMap::new();
+    for (ir, expected) in [
+        ("lb t0, 0(t0)", instructions::LB_X5_0_X5),
+        ("lh t0, 0(t0)", instructions::LH_X5_0_X5),
+        ("lw t0, 0(t0)", instructions::LW_X5_0_X5),
+        ("lbu t0, 0(t0)", instructions::LBU_X5_0_X5),
+        ("lhu t0, 0(t0)", instructions::LHU_X5_0_X5),
+        ("sb t0, 0(t0)", instructions::SB_X5_0_X5),
+        ("sh t0, 0(t0)", instructions::SH_X5_0_X5),
+        ("sw t0, 0(t0)", instructions::SW_X5_0_X5),
+        ("sw t0, 16(t0)", instructions::SW_X5_16_X5),
+        ("sw t0, -40(t0)", instructions::SW_X5_NEG_40_X5),
+        ("sw a0, -20(s0)", instructions::SW_A0_NEG_20_S0),
+    ]
+    .iter()
+    {
+        std::assert_eq!(
+            Ok(Some(*expected)),
+            assemble_ir(ir, &mut labels, 0),
+            "{}",
+            ir
+        );
+        // Signed and unsigned loads round-trip as themselves.
+        std::assert_eq!(*ir, disassemble(*expected).unwrap().to_string());
+    }
+}
+
+#[test]
+fn test_parse_program() {
+    let valid = "top: addi t0, t0, 1\n\
+                 .equ n, 4\n\
+                 li t1, n\n\
+                 beqz t0, top\n\
+                 li t2, top\n\
+                 j top";
+    let (instructions, diagnostics) = parse_program(&format!("{}\nbogus t0\naddi t2, t0", valid));
+    std::assert_eq!(
+        vec![
+            (6, Severity::Error, "unknown operation `bogus`"),
+            (7, Severity::Error, "`addi` takes operands `rd, rs1, imm`"),
+        ],
+        diagnostics
+            .iter()
+            .map(|d| (d.line, d.severity, &d.message[..]))
+            .collect::<Vec<_>>()
+    );
+    // A symbolic `li` is split into the two words it was laid out with.
+    std::assert_eq!(
+        vec![
+            ("addi t0, t0, 1".to_owned(), 5..19),
+            ("lui t1, 0x0".to_owned(), 30..38),
+            ("addi t1, t1, 4".to_owned(), 30..38),
+            ("beqz t0, -12".to_owned(), 39..51),
+            ("lui t2, 0x0".to_owned(), 52..62),
+            ("addi t2, t2, 0".to_owned(), 52..62),
+            ("j -24".to_owned(), 63..68),
+        ],
+        instructions
+            .iter()
+            .map(|(i, span)| (i.to_string(), span.clone()))
+            .collect::<Vec<_>>()
+    );
+
+    // The instructions encode to the same words as the valid lines alone.
+    let instructions: Vec<Instruction> = instructions.into_iter().map(|(i, _)| i).collect();
+    std::assert_eq!(
+        assemble_program(valid).unwrap().words,
+        encode_program(&instructions).unwrap()
+    );
+
+    // Malformed input gives diagnostics, not panics.
+    for program in [
+        "addi",
+        "lw t0,",
+        ")",
+        "(((",
+        "beq t0",
+        "li",
+        "x: x:",
+        "jalr",
+        "sw t0, (",
+        "addi t0, t0, %hi(",
+        "= 4",
+        "lw t0, 4(t9)",
+    ]
+    .iter()
+    {
+        let (instructions, diagnostics) = parse_program(program);
+        std::assert!(instructions.is_empty(), "{}", program);
+        std::assert!(!diagnostics.is_empty(), "{}", program);
+    }
+}
+
+#[test]
+fn test_stack_heap() {
+    let prog = assemble_program(
+        "li sp, __stack_top\n\
+         li a0, __heap_start\n\
+         .heap 0x10\n\
+         .stack 0x400",
+    )
+    .unwrap();
+    // Four words of code, then the heap, then the stack.
+    std::assert_eq!(Some(&0x10), prog.symbols.get("__heap_start"));
+    std::assert_eq!(Some(&0x420), prog.symbols.get("__stack_top"));
+    std::assert_eq!((0x420 / 4) as usize, prog.words.len());
+    std::assert!(prog.words[4..].iter().all(|w| *w == 0));
+    std::assert_eq!(
+        assemble_program(
+            "lui sp, %hi(0x420)\naddi sp, sp, %lo(0x420)\nlui a0, 0\naddi a0, a0, 0x10"
+        )
+        .unwrap()
+        .words[..],
+        prog.words[..4]
+    );
+
+    // `la` reaches the same symbols relative to its `auipc`.
+    let prog = assemble_program("la sp, __stack_top\n.stack 0x400").unwrap();
+    std::assert_eq!(Some(&0x408), prog.symbols.get("__stack_top"));
+    std::assert_eq!(0x408, resolve_pcrel_pair(prog.words[0], prog.words[1], 0));
+
+    // Sizes are rounded up to a whole word.
+    let prog = assemble_program("nop\n.stack 5").unwrap();
+    std::assert_eq!(Some(&12), prog.symbols.get("__stack_top"));
+
+    std::assert_eq!(
+        Err(AssemblerError::DuplicateLabelError(
+            "__stack_top".to_owned()
+        )),
+        assemble_program(".stack 4\n.stack 4")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooFewTokensError),
+        assemble_program(".heap")
+    );
+
+    // Reserving past the end of the address space is an error, and is
+    // caught before anything is allocated.
+    std::assert_eq!(
+        Err(AssemblerError::AddressOverflowError { pc: 0xffff_fff0 }),
+        assemble_program(".stack 0xfffffff0\n.heap 0x100")
+    );
+    std::assert!(assemble_program(".stack 0xfffffff0\n.stack 0x100").is_err());
+    std::assert_eq!(
+        vec!["line at 0x00000004 reaches the end of the address space".to_owned()],
+        validate("nop\n.heap 0xfffffffc")
+            .into_iter()
+            .map(|d| d.message)
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_data_target() {
+    let mut assembler = Assembler::new();
+    assembler.strict(true).insert_source(
+        "beq t0, t1, table+4\n\
+         j table\n\
+         beq t0, t1, end\n\
+         table: .word 1, 2\n\
+         end: addi t0, t0, 1",
+    );
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        vec![
+            Diagnostic::warning(
+                0,
+                "branch target 0x10 is inside data at 0xc..0x14, not an instruction".to_owned()
+            ),
+            Diagnostic::warning(
+                1,
+                "branch target 0xc is inside data at 0xc..0x14, not an instruction".to_owned()
+            ),
+        ],
+        prog.diagnostics
+    );
+
+    assembler.strict(false);
+    std::assert!(assembler.assemble().unwrap().diagnostics.is_empty());
+}
+
+#[test]
+fn test_isa_string() {
+    let mut assembler = Assembler::new();
+    std::assert_eq!("rv32i", assembler.isa_string());
+    assembler.m_extension(true);
+    std::assert_eq!("rv32im", assembler.isa_string());
+    assembler.m_extension(false);
+    std::assert_eq!("rv32i", assembler.isa_string());
+}
+
+#[test]
+fn test_verify_encoding() {
+    let mut labels = HashMap::new();
+    let beq = assemble_ir("beq t0, t1, -4", &mut labels, 0)
+        .unwrap()
+        .unwrap();
+    std::assert_eq!(Ok(()), encode::verify_encoding(beq, &decode_fields(beq)));
+    // Flip bit 11 of the offset, which B-type keeps in bit 7.
+    let corrupted = beq ^ (1 << 7);
+    std::assert_eq!(
+        Err(AssemblerError::EncodingMismatchError {
+            ir: corrupted,
+            field: "imm",
+            expected: -4,
+            actual: -4 ^ (1 << 11),
+        }),
+        encode::verify_encoding(corrupted, &decode_fields(beq))
+    );
+
+    let add = assemble_ir("add t0, t1, t2", &mut labels, 0)
+        .unwrap()
+        .unwrap();
+    let mut expected = decode_fields(add);
+    expected.rs2 = 8;
+    std::assert_eq!(
+        Err(AssemblerError::EncodingMismatchError {
+            ir: add,
+            field: "rs2",
+            expected: 8,
+            actual: 7,
+        }),
+        encode::verify_encoding(add, &expected)
+    );
+}
+
+#[test]
+fn test_type_size() {
+    let prog = assemble_program(
+        ".type f, @function\n\
+         f: addi a0, a0, 1\n\
+         jalr zero, ra, 0\n\
+         .size f, .-f\n\
+         .type table, @object\n\
+         .type g, @gnu_indirect_function\n\
+         table: .word 1",
+    )
+    .unwrap();
+    std::assert_eq!(Some(&SymbolType::Function), prog.symbol_types.get("f"));
+    std::assert_eq!(Some(&8), prog.symbol_sizes.get("f"));
+    std::assert_eq!(Some(&SymbolType::Object), prog.symbol_types.get("table"));
+    std::assert_eq!(
+        vec![Diagnostic::warning(
+            5,
+            "unknown symbol type `@gnu_indirect_function` is ignored".to_owned()
+        )],
+        prog.diagnostics
+    );
+
+    // The symbol table has the null symbol, then `f`, then `table`.
+    let elf = prog.to_elf();
+    let u32_at = |i: usize| u32::from_le_bytes([elf[i], elf[i + 1], elf[i + 2], elf[i + 3]]);
+    let symtab = u32_at(32) as usize + 2 * 40;
+    let sym_off = u32_at(symtab + 16) as usize;
+    std::assert_eq!(0, u32_at(sym_off + 16 + 4));
+    std::assert_eq!(8, u32_at(sym_off + 16 + 8));
+    std::assert_eq!(0x02, elf[sym_off + 16 + 12]);
+    std::assert_eq!(8, u32_at(sym_off + 32 + 4));
+    std::assert_eq!(0, u32_at(sym_off + 32 + 8));
+    std::assert_eq!(0x01, elf[sym_off + 32 + 12]);
+
+    std::assert_eq!(
+        Err(AssemblerError::TooFewTokensError),
+        assemble_program(".size f")
+    );
+}
+
+#[test]
+fn test_resolve_pcrel_pair() {
+    let mut labels = HashMap::new();
+    let mut word = |ir: &str| assemble_ir(ir, &mut labels, 0).unwrap().unwrap();
+
+    // Bit 11 of the target clear, then set, where the upper part carries.
+    std::assert_eq!(
+        0x17ff,
+        resolve_pcrel_pair(word("auipc t0, 1"), word("addi t0, t0, 0x7ff"), 0)
+    );
+    std::assert_eq!(
+        0x1800,
+        resolve_pcrel_pair(word("auipc t0, 2"), word("addi t0, t0, -0x800"), 0)
+    );
+    std::assert_eq!(
+        0x10fc,
+        resolve_pcrel_pair(word("auipc t0, 1"), word("lw a0, -4(t0)"), 0x100)
+    );
+    std::assert_eq!(
+        0x0ff0,
+        resolve_pcrel_pair(word("auipc t0, 0"), word("sw a0, -16(t0)"), 0x1000)
+    );
+
+    // Targets behind the pc and across the top of the address space.
+    std::assert_eq!(
+        0x0,
+        resolve_pcrel_pair(word("auipc t0, 0xfffff"), word("addi t0, t0, 0"), 0x1000)
+    );
+    std::assert_eq!(
+        0x7ff,
+        resolve_pcrel_pair(word("auipc t0, 1"), word("addi t0, t0, 0x7ff"), 0xffff_f000)
+    );
+}
+
+#[test]
+fn test_insn() {
+    let words = |program: &str| assemble_program(program).map(|prog| prog.words);
+    std::assert_eq!(
+        words("add t0, t1, t2"),
+        words(".insn r 0x33, 0, 0, t0, t1, t2")
+    );
+    std::assert_eq!(
+        words("sub t0, t1, t2\naddi t0, t1, -5\nsw a0, -16(sp)"),
+        words(
+            ".insn r 0x33, 0, 0x20, t0, t1, t2\n\
+             .insn i 0x13, 0, t0, t1, -5\n\
+             .insn s 0x23, 2, a0, -16(sp)"
+        )
+    );
+    std::assert_eq!(
+        words("loop: beq t0, t1, loop\nlui t0, 0x12345\njal ra, loop"),
+        words(
+            "loop: .insn b 0x63, 0, t0, t1, loop\n\
+             .insn u 0x37, t0, 0x12345\n\
+             .insn j 0x6f, ra, loop"
+        )
+    );
+
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        words(".insn r 0x80, 0, 0, t0, t1, t2")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        words(".insn r 0x33, 8, 0, t0, t1, t2")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        words(".insn i 0x13, 0, t0, t1, 2048")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::TooFewTokensError),
+        words(".insn r 0x33, 0, 0, t0, t1")
+    );
+    std::assert_eq!(
+        Err(AssemblerError::InvalidOperationError("x".to_owned())),
+        words(".insn x 0x33")
+    );
+}
+
+#[test]
+fn test_assemble_units() {
+    let main = "_start: jal ra, helper\n.bogus\nj _start";
+    let helper = "helper: addi a0, a0, 1\njalr zero, ra, 0";
+    let prog = assemble_units(&[("main.s", main), ("helper.s", helper)]).unwrap();
+    std::assert_eq!(
+        assemble_program(&format!("{}\n{}", main, helper))
+            .unwrap()
+            .words,
+        prog.words
+    );
+    std::assert_eq!(Some(&0x8), prog.symbols.get("helper"));
+    std::assert_eq!(
+        vec![Diagnostic {
+            file: Some("main.s".to_owned()),
+            ..Diagnostic::warning(1, "unknown directive `.bogus` is ignored".to_owned())
+        }],
+        prog.diagnostics
+    );
+
+    std::assert_eq!(
+        Err(AssemblerError::UnitError {
+            file: "helper.s".to_owned(),
+            line: 1,
+            error: Box::new(AssemblerError::NoSuchLabelError("nowhere".to_owned())),
+        }),
+        assemble_units(&[("main.s", main), ("helper.s", "helper:\nj nowhere")])
+    );
+}
+
+#[test]
+fn test_symbol_map() {
+    let mut assembler = Assembler::new();
+    assembler.base(0x8000_0000).insert_source(
+        ".globl _start\n\
+         .globl main\n\
+         _start: jal ra, main\n\
+         loop: j loop\n\
+         main: addi a0, zero, 0\n\
+         done: jalr zero, ra, 0",
+    );
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        "80000000 _start\n\
+         80000004 loop\n\
+         80000008 main\n\
+         8000000c done\n",
+        prog.to_symbol_map(true)
+    );
+    std::assert_eq!(
+        "80000000 _start\n80000008 main\n",
+        prog.to_symbol_map(false)
+    );
+}
+
+#[test]
+fn test_source_listing() {
+    let source = "start:\taddi t0, t1, 0\n\tlui t0, 4\t# x\n\tli t0, 0x12345\n";
+    let prog = assemble_program(source).unwrap();
+    std::assert_eq!(
+        "00000000: 00030293  start:  addi t0, t1, 0\n\
+         00000004: 000042b7          lui t0, 4       # x\n\
+         00000008: 000122b7          li t0, 0x12345\n\
+         0000000c: 34528293\n",
+        prog.to_source_listing(source, DEFAULT_TAB_WIDTH)
+    );
+    std::assert_eq!(
+        "00000000: 00030293  start:  addi t0, t1, 0\n\
+         00000004: 000042b7      lui t0, 4   # x\n\
+         00000008: 000122b7      li t0, 0x12345\n\
+         0000000c: 34528293\n",
+        prog.to_source_listing(source, 4)
+    );
+}
+
+#[test]
+fn test_abs_jumps() {
+    let mut labels = HashMap::new();
+    let mut word = |ir: &str| assemble_ir(ir, &mut labels, 0).unwrap().unwrap();
+    std::assert_eq!(
+        vec![word("lui t0, 0x80000"), word("jalr zero, t0, 0")],
+        assemble_program("jump_abs 0x80000000").unwrap().words
+    );
+    // The low part sign-extends, so the upper part carries one.
+    std::assert_eq!(
+        vec![
+            word("addi a0, a0, 1"),
+            word("lui t0, 0x10"),
+            word("jalr ra, t0, -4"),
+            word("addi a0, a0, 1"),
+        ],
+        assemble_program("addi a0, a0, 1\ncall_abs 0xfffc\naddi a0, a0, 1")
+            .unwrap()
+            .words
+    );
+    std::assert_eq!(Ok(12), program_size("call_abs rom\nrom = 0x1000\nnop"));
+    std::assert_eq!(
+        Err(AssemblerError::OperandCountError {
+            op: "jump_abs".to_owned(),
+            expected: "addr"
+        }),
+        assemble_program("jump_abs")
+    );
+}
+
+#[test]
+fn test_insn_reserved_encoding() {
+    // A shift left with the arithmetic shift bit, a `sub`, a `jalr` with
+    // a func3, and a custom instruction.
+    let program = ".insn r 0x13, 1, 0x20, t0, t1, x3\n\
+                   .insn r 0x33, 0, 0x20, t0, t1, t2\n\
+                   .insn i 0x67, 2, t0, t1, 0\n\
+                   .insn r 0x0b, 7, 0x7f, t0, t1, t2";
+    let mut assembler = Assembler::new();
+    assembler.strict(true).insert_source(program);
+    std::assert_eq!(
+        vec![
+            Diagnostic::warning(
+                0,
+                "0x40331293 is a reserved encoding: its opcode has no instruction with func7 0x20"
+                    .to_owned()
+            ),
+            Diagnostic::warning(
+                2,
+                "0x000322e7 is a reserved encoding: its opcode has no instruction with func3 0x2"
+                    .to_owned()
+            ),
+        ],
+        assembler.assemble().unwrap().diagnostics
+    );
+
+    assembler.strict(false);
+    std::assert!(assembler.assemble().unwrap().diagnostics.is_empty());
+}
+
+#[test]
+fn test_csv() {
+    let prog = assemble_program(
+        "addi t0, t1, 0\n\
+         ret\n\
+         table: .word 0x00008067, 0",
+    )
+    .unwrap();
+    std::assert_eq!(
+        "index,address,hex,mnemonic,operands\n\
+         0,0x00000000,00030293,addi,\"t0, t1, 0\"\n\
+         1,0x00000004,00008067,jalr,\"zero, ra, 0\"\n\
+         2,0x00000008,00008067,.word,\"0x00008067\"\n\
+         3,0x0000000c,00000000,.word,\"0x00000000\"\n",
+        prog.to_csv()
+    );
+}
+
+#[test]
+fn test_label_names() {
+    // Mnemonics are only mnemonics first on a line.
+    let prog = assemble_program(
+        "add: add t0, t0, t1\n\
+         beq t0, t2, add\n\
+         j: j add",
+    )
+    .unwrap();
+    std::assert_eq!(Some(&0), prog.symbols.get("add"));
+    std::assert_eq!(
+        assemble_program("l: add t0, t0, t1\nbeq t0, t2, l\nj l")
+            .unwrap()
+            .words,
+        prog.words
+    );
+
+    for program in ["sp: nop", "x31: nop", "S0: nop", ".stack 4\nra:"] {
+        let name = program
+            .split([':', '\n'])
+            .find(|t| !t.starts_with('.'))
+            .unwrap()
+            .to_lowercase();
+        std::assert_eq!(
+            Err(AssemblerError::ReservedNameError(name)),
+            assemble_program(program).map(|prog| prog.words),
+            "{}",
+            program
+        );
+    }
+    let mut assembler = Assembler::new();
+    assembler.insert_bytes(Some("zero"), &[0]);
+    std::assert_eq!(
+        Err(AssemblerError::ReservedNameError("zero".to_owned())),
+        assembler.assemble().map(|prog| prog.words)
+    );
+}
+
+#[test]
+fn test_reassemble() {
+    let old = "start: addi t0, t1, 1\n\
+               li t2, 5 # five\n\
+               beq t0, t2, start\n\
+               .word 0";
+    let prev = assemble_program(old).unwrap();
+
+    // An edit in place only re-encodes its line, and later spans move.
+    let new = old.replace("t1, 1", "t1, 123").replace("5 #", "6 #");
+    let prog = reassemble(&prev, old, &new).unwrap();
+    std::assert_eq!(assemble_program(&new).unwrap(), prog);
+    std::assert_eq!(prev.words[2..], prog.words[2..]);
+    std::assert_ne!(prev.spans[2], prog.spans[2]);
+
+    // Changing a size, a label, or the line count falls back.
+    for new in [
+        old.replace("t2, 5", "t2, 0x12345"),
+        old.replace("start: addi", "again: addi")
+            .replace(", start", ", again"),
+        format!("nop\n{}", old),
+    ] {
+        std::assert_eq!(assemble_program(&new), reassemble(&prev, old, &new));
+    }
+}
+
+#[test]
+fn test_location_counter() {
+    let prog = assemble_program(
+        ".equ ahead, end-.\n\
+         start: addi t0, t1, 0\n\
+         addi t0, t0, 1\n\
+         .equ len, .-start\n\
+         .word len, ahead, .\n\
+         end: beq t0, t1, .",
+    )
+    .unwrap();
+    std::assert_eq!(Some(&8), prog.constants.get("len"));
+    std::assert_eq!(Some(&0x14), prog.constants.get("ahead"));
+    std::assert_eq!(vec![8, 0x14, 8], prog.words[2..5]);
+    std::assert_eq!(
+        assemble_program("end: beq t0, t1, end").unwrap().words,
+        prog.words[5..]
+    );
+}
+
+#[test]
+fn test_decimal_lines() {
+    let prog = assemble_program("addi t0, t1, 0\nret\n.word 0xffffffff").unwrap();
+    let decimal = to_decimal_lines(&prog.words);
+    std::assert_eq!("197267\n32871\n4294967295\n", decimal);
+    let hex = prog.to_hex(Endian::Big);
+    for (d, h) in decimal.lines().zip(hex.lines()) {
+        std::assert_eq!(u32::from_str_radix(h, 16).unwrap(), d.parse().unwrap());
+    }
+}
+
+#[test]
+fn test_transform() {
+    let source = "start: jal ra, func\n\
+                  addi t0, t0, 1\n\
+                  func: jal zero, start";
+    let mut assembler = Assembler::new();
+    assembler.insert_source(source);
+    assembler.transform(|instruction| {
+        let mut out = vec![instruction.clone()];
+        if instruction.op() == "jal" {
+            out.push(Instruction::Pseudo {
+                name: "nop".to_owned(),
+                operands: Vec::new(),
+            });
+        }
+        out
+    });
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(Some(&12), prog.symbols.get("func"));
+    std::assert_eq!(
+        assemble_program(
+            "start: jal ra, func\n\
+             nop\n\
+             addi t0, t0, 1\n\
+             func: jal zero, start\n\
+             nop"
+        )
+        .unwrap()
+        .words,
+        prog.words
+    );
+    std::assert_eq!(vec![0, 0, 1, 2, 2], prog.line_map);
+}
+
+#[test]
+fn test_auipc_target() {
+    let mut assembler = Assembler::new();
+    assembler
+        .base(0x1000)
+        .insert_source("auipc t0, 0x1\nlui t1, 0x1");
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        "00001000: 00001297  auipc t0, 0x1  # 0x2000\n\
+         00001004: 00001337  lui t1, 0x1\n",
+        prog.to_listing()
+    );
+    let auipc = disassemble(prog.words[0]).unwrap();
+    std::assert_eq!(Some(0x2000), auipc.target(0x1000));
+    std::assert_eq!(None, disassemble(prog.words[1]).unwrap().target(0x1004));
+}
+
+#[test]
+fn test_file_line() {
+    let mut assembler = Assembler::new();
+    assembler.strict(true).insert_source(
+        "addi t0, t0, 1\n\
+         .file \"main.c\"\n\
+         .line 10\n\
+         .cfi_startproc\n\
+         addi t0, t0, 2\n\
+         .file 2 \"util.h\"\n\
+         .line 3\n\
+         addi t0, t0, 3\n\
+         addi t0, t0, 4",
+    );
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(vec!["main.c", "util.h"], prog.files);
+    std::assert_eq!(None, prog.source_location(0));
+    std::assert_eq!(
+        Some(&SourceLocation {
+            file: Some("main.c".to_owned()),
+            line: 10
+        }),
+        prog.source_location(4)
+    );
+    for addr in [8, 12] {
+        std::assert_eq!(
+            Some(&SourceLocation {
+                file: Some("util.h".to_owned()),
+                line: 3
+            }),
+            prog.source_location(addr)
+        );
+    }
+    std::assert_eq!(1, prog.diagnostics.len());
+    std::assert_eq!(3, prog.diagnostics[0].line);
+    std::assert_eq!(
+        "debug directive `.cfi_startproc` is ignored",
+        prog.diagnostics[0].message
+    );
+}
+
+#[test]
+fn test_split_hi_lo() {
+    for (value, hi, lo) in [
+        (0, 0, 0),
+        (0x7ff, 0, 0x7ff),
+        (0x800, 1, -0x800),
+        (0x12345fff, 0x12346, -1),
+        (0x12345800, 0x12346, -0x800),
+        (0x123457ff, 0x12345, 0x7ff),
+        (0xffff_ffff, 0, -1),
+        (0x7fff_f800, 0x80000, -0x800),
+    ] {
+        std::assert_eq!((hi, lo), split_hi_lo(value), "{:#x}", value);
+        std::assert_eq!(value, (hi << 12).wrapping_add(lo as u32));
+    }
+    // `li` builds its value from the same parts.
+    std::assert_eq!(
+        assemble_program("lui t0, 0x12346\naddi t0, t0, -2048")
+            .unwrap()
+            .words,
+        assemble_program("li t0, 0x12345800").unwrap().words
+    );
+}
+
+#[test]
+fn test_unicode_whitespace() {
+    let expected = assemble_program("add t0, t1, t2").unwrap().words;
+    for line in [
+        "add\u{a0}t0,\u{a0}t1,\u{a0}t2",
+        "\u{feff}add t0, t1, t2\u{200b}",
+        "\u{3000}add\u{2003}t0,\u{200b}t1, t2  # comment\u{a0}",
+    ] {
+        std::assert_eq!(vec!["add", "t0", "t1", "t2"], tokenize!(line), "{:?}", line);
+        std::assert_eq!(expected, assemble_program(line).unwrap().words);
+    }
+    std::assert_eq!(2..16, code_span("\u{a0}add t0, t1, t2\u{a0}"));
+    std::assert_eq!(
+        assemble_program("lw t0, 4(sp)").unwrap().words,
+        assemble_program("lw t0, 4(\u{a0}sp\u{200b})")
+            .unwrap()
+            .words
+    );
+}
+
+#[test]
+fn test_jump_style() {
+    let source = "loop: addi t0, t0, 1\nj loop\njal ra, loop";
+    let listing = |style| {
+        let mut assembler = Assembler::new();
+        assembler.jump_style(style).insert_source(source);
+        assembler.assemble().unwrap()
+    };
+    let (jal, j) = (listing(JumpStyle::Jal), listing(JumpStyle::J));
+    std::assert_eq!(jal.words, j.words);
+    std::assert_eq!(
+        "00000000: 00128293  addi t0, t0, 1\n\
+         00000004: ffdff06f  jal zero, -4  # 0x0 <loop>\n\
+         00000008: ff9ff0ef  jal ra, -8  # 0x0 <loop>\n",
+        jal.to_listing()
+    );
+    std::assert_eq!(
+        "00000000: 00128293  addi t0, t0, 1\n\
+         00000004: ffdff06f  j -4  # 0x0 <loop>\n\
+         00000008: ff9ff0ef  jal ra, -8  # 0x0 <loop>\n",
+        j.to_listing()
+    );
+}
+
+#[test]
+fn test_rv32i_only() {
+    let assemble = |m: bool, rv32i_only: bool, source: &str| {
+        let mut assembler = Assembler::new();
+        assembler
+            .m_extension(m)
+            .rv32i_only(rv32i_only)
+            .insert_source(source);
+        assembler.assemble()
+    };
+    let disabled = |mnemonic: &str, extension| {
+        Err(AssemblerError::ExtensionDisabledError {
+            mnemonic: mnemonic.to_owned(),
+            extension,
+        })
+    };
+
+    // mul t0, t1, t2 and remu a0, a1, a2.
+    let prog = assemble(true, false, "mul t0, t1, t2\nremu a0, a1, a2").unwrap();
+    std::assert_eq!(vec![0x027302b3, 0x02c5f533], prog.words);
+    std::assert_eq!(
+        "mul t0, t1, t2",
+        disassemble(prog.words[0]).unwrap().to_string()
+    );
+    std::assert_eq!(disabled("mul", "M"), assemble(true, true, "mul t0, t1, t2"));
+    std::assert_eq!(
+        disabled("mul", "M"),
+        assemble(false, false, "mul t0, t1, t2")
+    );
+
+    // Unassembled extensions are reported as such, not as unknown.
+    std::assert_eq!(
+        disabled("csrr", "Zicsr"),
+        assemble(true, true, "csrr t0, 0")
+    );
+    std::assert_eq!(
+        disabled("amoadd.w.aq", "A"),
+        assemble(true, true, "amoadd.w.aq t0, t1, (t2)")
+    );
+    std::assert!(assemble(false, true, "add t0, t1, t2").is_ok());
+}
+
+#[test]
+fn test_immediate_format() {
+    let source = "addi x1, x0, -4\nlui x1, 0x12345\nlw x1, -8(x2)";
+    let listing = |format| {
+        let mut assembler = Assembler::new();
+        assembler.immediate_format(format).insert_source(source);
+        assembler.assemble().unwrap().to_listing()
+    };
+    std::assert_eq!(
+        "00000000: ffc00093  addi ra, zero, -4\n\
+         00000004: 123450b7  lui ra, 0x12345\n\
+         00000008: ff812083  lw ra, -8(sp)\n",
+        listing(ImmediateFormat::default())
+    );
+    std::assert_eq!(
+        "00000000: ffc00093  addi ra, zero, -0x4\n\
+         00000004: 123450b7  lui ra, 74565\n\
+         00000008: ff812083  lw ra, -0x8(sp)\n",
+        listing(ImmediateFormat {
+            signed: Radix::Hex,
+            upper: Radix::Decimal,
+        })
+    );
+    // Either way, the text assembles back to the same word.
+    let hex = ImmediateFormat {
+        signed: Radix::Hex,
+        upper: Radix::Hex,
+    };
+    for word in assemble_program(source).unwrap().words {
+        let text = disassemble(word)
+            .unwrap()
+            .to_string_with(JumpStyle::Jal, hex);
+        std::assert_eq!(vec![word], assemble_program(&text).unwrap().words);
+    }
+}
+
+#[test]
+fn test_sparse() {
+    let prog = assemble_program(
+        "addi t0, t0, 1\n\
+         addi t0, t0, 2\n\
+         .org 0x100\n\
+         addi t0, t0, 3\n\
+         .align 4\n\
+         .word 4",
+    )
+    .unwrap();
+    std::assert_eq!(vec![0x8..0x100, 0x104..0x110], prog.gaps);
+    let sparse = prog.to_sparse();
+    std::assert_eq!(
+        vec![0, 0x100, 0x110],
+        sparse.keys().copied().collect::<Vec<_>>()
+    );
+    std::assert_eq!(prog.to_bytes(Endian::Little)[..8], sparse[&0][..]);
+    std::assert_eq!(
+        prog.to_bytes(Endian::Little)[0x100..0x104],
+        sparse[&0x100][..]
+    );
+    std::assert_eq!(vec![4, 0, 0, 0], sparse[&0x110]);
+
+    let ihex = prog.to_ihex_sparse();
+    let records: Vec<&str> = ihex.lines().map(|l| &l[..9]).collect();
+    std::assert_eq!(
+        vec![":08000000", ":04010000", ":04011000", ":00000001"],
+        records
+    );
+    std::assert!(prog.to_ihex().lines().count() > records.len());
+    let srec = prog.to_srec_sparse();
+    let addrs: Vec<&str> = srec
+        .lines()
+        .filter(|l| l.starts_with("S3"))
+        .map(|l| &l[4..12])
+        .collect();
+    std::assert_eq!(vec!["00000000", "00000100", "00000110"], addrs);
+
+    // Without gaps, the sparse formats are the flat ones.
+    let prog = assemble_program("addi t0, t0, 1\nret").unwrap();
+    std::assert_eq!(prog.to_ihex(), prog.to_ihex_sparse());
+    std::assert_eq!(prog.to_srec(), prog.to_srec_sparse());
+}
+
+#[test]
+fn test_validate_operands() {
+    for reg in ["zero", "ra", "sp", "t0", "s11", "a7", "x0", "x31", "T0"] {
+        std::assert!(is_valid_register(reg), "{}", reg);
+    }
+    for reg in ["x32", "x", "fp0", "t7", "5", ""] {
+        std::assert!(!is_valid_register(reg), "{}", reg);
+    }
+
+    std::assert_eq!(Ok(-4), validate_immediate("-4", 12, true));
+    std::assert_eq!(Ok(2047), validate_immediate("0x7ff", 12, true));
+    std::assert_eq!(Ok(-2048), validate_immediate("-0x800", 12, true));
+    std::assert_eq!(Ok(0x12345), validate_immediate("0x12345", 20, false));
+    std::assert_eq!(Ok(0xfffff), validate_immediate("0XFFFFF", 20, false));
+    std::assert_eq!(Ok(5), validate_immediate("0b101", 5, false));
+    std::assert_eq!(Ok(12), validate_immediate("(2+4)+6", 12, true));
+    // The widest fields take any 32-bit value.
+    std::assert_eq!(Ok(-1), validate_immediate("0xffffffff", 32, true));
+    std::assert_eq!(Ok(0xffff_ffff), validate_immediate("-1", 32, false));
+    std::assert_eq!(Ok(-0x8000_0000), validate_immediate("0x80000000", 32, true));
+    std::assert_eq!(Ok(1), validate_immediate("1", 1, false));
+    std::assert_eq!(Ok(-1), validate_immediate("-1", 1, true));
+    for (token, bits, signed) in [
+        ("2048", 12, true),
+        ("-2049", 12, true),
+        ("0x100000", 20, false),
+        ("-1", 20, false),
+        ("32", 5, false),
+        ("0", 0, false),
+        ("0", 0, true),
+        ("0", 33, false),
+        ("0", 64, true),
+        ("0", u32::MAX, false),
+    ] {
+        std::assert_eq!(
+            Err(AssemblerError::ImmediateTooLargeError),
+            validate_immediate(token, bits, signed),
+            "{}",
+            token
+        );
+    }
+    std::assert!(matches!(
+        validate_immediate("0x100000000", 32, false),
+        Err(AssemblerError::ValueTooLargeError { .. })
+    ));
+    std::assert!(matches!(
+        validate_immediate("1.5", 12, true),
+        Err(AssemblerError::FloatNotSupportedError { .. })
+    ));
+    std::assert!(validate_immediate("label", 12, true).is_err());
+
+    // The same rules as assembling a whole line.
+    std::assert!(assemble_program("addi t0, t0, 2047").is_ok());
+    std::assert!(assemble_program("addi t0, t0, 2048").is_err());
+}
+
+#[test]
+fn test_loader_image() {
+    let mut assembler = Assembler::new();
+    assembler
+        .base(0x8000_0000)
+        .entry("main")
+        .insert_source("nop\nmain: addi t0, t0, 1\nret");
+    let prog = assembler.assemble().unwrap();
+    let image = to_loader_image(&prog, 0x5256_3332);
+    std::assert_eq!(LOADER_HEADER_LEN + 12, image.len());
+    std::assert_eq!(
+        [0x32, 0x33, 0x56, 0x52, 0x04, 0x00, 0x00, 0x80, 12, 0, 0, 0],
+        image[..LOADER_HEADER_LEN]
+    );
+    std::assert_eq!(prog.to_bytes(Endian::Little), image[LOADER_HEADER_LEN..]);
+
+    // Without an entry point, the loader starts at the base.
+    let prog = assemble_program("nop").unwrap();
+    std::assert_eq!([0, 0, 0, 0], to_loader_image(&prog, 0)[4..8]);
+}
+
+#[test]
+fn test_circular_definitions() {
+    for (source, name) in [
+        (".equ a, a+1", "a"),
+        ("a = a", "a"),
+        (".equ a, b\n.equ b, a", "a"),
+        ("x = y + 1\ny = z - 1\nz = x\nw = 1", "x"),
+        (".set a, 4\n.equ b, (b)+a", "b"),
+    ] {
+        std::assert_eq!(
+            Err(AssemblerError::CircularConstantError(name.to_owned())),
+            assemble_program(source),
+            "{}",
+            source
+        );
+    }
+    // Every constant in a cycle is reported, but nothing else.
+    let diagnostics = validate("x = y\ny = x\nz = 1\naddi t0, t0, z");
+    std::assert_eq!(
+        vec![0, 1],
+        diagnostics.iter().map(|d| d.line).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_field_listing() {
+    let prog = assemble_program("add t0, t1, t2\nsw t0, -4(sp)\n.word 0").unwrap();
+    let listing = prog.to_field_listing();
+    let lines: Vec<&str> = listing.lines().collect();
+    std::assert_eq!(
+        vec![
+            "00000000: 007302b3  add t0, t1, t2",
+            "          func7=0000000 rs2=00111 rs1=00110 func3=000 rd=00101 opcode=0110011",
+            "00000004: fe512e23  sw t0, -4(sp)",
+            "          imm[11:5]=1111111 rs2=00101 rs1=00010 func3=010 imm[4:0]=11100 opcode=0100011",
+            "00000008: 00000000  .word 0x00000000",
+        ],
+        lines
+    );
+}
+
+#[test]
+fn test_norelax_region() {
+    let assemble = |source: &str| {
+        let mut assembler = Assembler::new();
+        assembler.relax(true).insert_source(source);
+        assembler.assemble()
+    };
+    let far = ".org 0x2000\nfar: ret";
+
+    // After the region is popped, branches are relaxed again.
+    let prog = assemble(&format!(
+        ".option push\n.option norelax\nnop\n.option pop\nbeqz a0, far\n{}",
+        far
+    ))
+    .unwrap();
+    std::assert_eq!(
+        assemble_program("nop\nbnez a0, 8\nj far\n.org 0x2000\nfar: ret")
+            .unwrap()
+            .words,
+        prog.words
+    );
+    std::assert_eq!(Some(&0x2000), prog.symbols.get("far"));
+
+    // Inside it, the branch is left as written, so it is out of range.
+    std::assert_eq!(
+        Err(AssemblerError::ImmediateTooLargeError),
+        assemble(&format!(
+            ".option push\n.option norelax\nbeqz a0, far\n.option pop\n{}",
+            far
+        ))
+    );
+    std::assert!(assemble(&format!(
+        ".option norelax\n.option relax\nbeqz a0, far\n{}",
+        far
+    ))
+    .is_ok());
+
+    // A call in range is shrunk to a `jal`, but not inside the region,
+    // where it keeps both words.
+    let prog =
+        assemble("call f\n.option push\n.option norelax\ncall f\n.option pop\nf: ret").unwrap();
+    std::assert_eq!(
+        assemble_program("jal ra, 12\nauipc ra, 0\njalr ra, ra, 8\nret")
+            .unwrap()
+            .words,
+        prog.words
+    );
+    std::assert_eq!(Some(&12), prog.symbols.get("f"));
+}
+
+#[test]
+fn test_call() {
+    // Without relaxing, a call is an `auipc` and a `jalr` relative to it.
+    let prog = assemble_program("nop\ncall f\nf: ret").unwrap();
+    std::assert_eq!(
+        assemble_program("nop\nauipc ra, 0\njalr ra, ra, 8\nret")
+            .unwrap()
+            .words,
+        prog.words
+    );
+    std::assert_eq!(0xc, resolve_pcrel_pair(prog.words[1], prog.words[2], 4));
+
+    // When relaxing, a call out of range of a `jal` keeps both words, and
+    // the layout after it moves to fit.
+    let mut assembler = Assembler::new();
+    assembler
+        .relax(true)
+        .insert_source("call far\ncall near\nnear: nop\n.org 0x100008\nfar: ret");
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(
+        assemble_program("auipc ra, 0x100\njalr ra, ra, 8\njal ra, 4\nnop")
+            .unwrap()
+            .words,
+        prog.words[..4]
+    );
+    std::assert_eq!(Some(&12), prog.symbols.get("near"));
+
+    std::assert!(assemble_program("call").is_err());
+    std::assert!(supported_mnemonics().contains(&"call"));
+}
+
+#[test]
+fn test_classify_lines() {
+    use LineKind::*;
+
+    let program = "# startup\n.globl _start\n_start: li a0, 4\nloop:\n  beqz a0, done  # exit\n\nN = 3\ntable: .word 1, 2, N\n.insn r 0x33, 0, 0, a0, a1, a2\ndone: .align 2\n.stack 64";
+    std::assert_eq!(
+        vec![
+            Blank,
+            Directive,
+            Instruction,
+            Label,
+            Instruction,
+            Blank,
+            Directive,
+            Data,
+            Instruction,
+            Directive,
+            Data,
+        ],
+        classify_lines(program)
+    );
+    std::assert_eq!(program.split('\n').count(), classify_lines(program).len());
+}
+
+#[test]
+fn test_reproducible_symbols() {
+    let program =
+        ".globl main\nmain: j loop\nzeta: nop\nalpha: nop\nloop: j main\nmid: .word 0\nbeta:";
+    let first = assemble_program(program).unwrap();
+    let second = assemble_program(program).unwrap();
+    std::assert_eq!(first.to_symbol_map(true), second.to_symbol_map(true));
+    std::assert_eq!(first.to_elf(), second.to_elf());
+
+    // Symbols iterate by name, whatever order they were defined in.
+    std::assert_eq!(
+        vec!["alpha", "beta", "loop", "main", "mid", "zeta"],
+        first.symbols.keys().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_auipcz() {
+    std::assert_eq!(
+        assemble_ir("auipc a0, 0", &mut HashMap::new(), 0).unwrap(),
+        assemble_ir("auipcz a0", &mut HashMap::new(), 0).unwrap()
+    );
+    std::assert_eq!(Some("rd"), operand_signature("auipcz"));
+
+    let mut assembler = Assembler::new();
+    assembler
+        .base(0x1000)
+        .insert_source("nop\nhere: auipcz a0\naddi a1, a0, 8");
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(vec![0x0000_0013, 0x0000_0517, 0x0085_0593], prog.words);
+    std::assert_eq!(Some(&0x1004), prog.symbols.get("here"));
+    std::assert_eq!(
+        Some(0x1004),
+        disassemble(prog.words[1]).unwrap().target(0x1004)
+    );
+    std::assert_eq!(
+        Err(AssemblerError::OperandCountError {
+            op: "auipcz".to_owned(),
+            expected: "rd"
+        }),
+        assemble_ir("auipcz", &mut HashMap::new(), 0)
+    );
+}
+
+#[test]
+fn test_image_endianness() {
+    let image = |instructions, data| {
+        let mut assembler = Assembler::new();
+        assembler
+            .instruction_endian(instructions)
+            .data_endian(data)
+            .insert_source("addi a0, zero, 1\n.word 0x11223344");
+        assembler.assemble().unwrap().to_image()
+    };
+    let (le_ins, be_ins) = ([0x13, 0x05, 0x10, 0x00], [0x00, 0x10, 0x05, 0x13]);
+    let (le_data, be_data) = ([0x44, 0x33, 0x22, 0x11], [0x11, 0x22, 0x33, 0x44]);
+
+    for (instructions, data, expected) in [
+        (Endian::Little, Endian::Little, [le_ins, le_data]),
+        (Endian::Big, Endian::Big, [be_ins, be_data]),
+        (Endian::Big, Endian::Little, [be_ins, le_data]),
+        (Endian::Little, Endian::Big, [le_ins, be_data]),
+    ] {
+        std::assert_eq!(
+            expected.concat(),
+            image(instructions, data),
+            "{:?} {:?}",
+            instructions,
+            data
+        );
+    }
+
+    let prog = assemble_program("addi a0, zero, 1\n.word 0x11223344").unwrap();
+    std::assert_eq!(prog.to_bytes(Endian::Little), prog.to_image());
+}
+
+#[test]
+fn test_format_error() {
+    let located = |assembler: &Assembler| assembler.assemble_located().unwrap_err();
+
+    let program = "start:\n\taddi a0, a0, 1\n\tADDI a0, X99, 1  # typo\n\tj start";
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    let err = located(&assembler);
+    // The error carries its line, column, and the span of its token.
+    std::assert_eq!(
+        AssemblerError::LocatedError {
+            line: 2,
+            column: 10,
+            span: 33..36,
+            error: Box::new(AssemblerError::NoSuchRegisterError("x99".to_owned())),
+        },
+        err
+    );
+    std::assert_eq!("X99", &program[33..36]);
+    std::assert_eq!(
+        "error: no register named `x99`\n \
+         --> line 3:11\n  \
+         |\n\
+         3 | \tADDI a0, X99, 1  # typo\n  \
+         | \t         ^^^\n",
+        format_error(program, &err)
+    );
+    std::assert_eq!("line 3:11: no register named `x99`", err.to_string());
+    std::assert_eq!(
+        Err(AssemblerError::NoSuchRegisterError("x99".to_owned())),
+        assembler.assemble()
+    );
+
+    // A wrong operand count points at the mnemonic.
+    let program = "nop\n  addi a0, a0";
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    std::assert_eq!(
+        "error: `addi` takes operands `rd, rs1, imm`\n \
+         --> line 2:3\n  \
+         |\n\
+         2 |   addi a0, a0\n  \
+         |   ^^^^\n",
+        format_error(program, &located(&assembler))
+    );
+
+    // Without a token, the caret is at the start of the line's code.
+    let program = "nop\n  beq a0, a1, 0x10000";
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    std::assert!(format_error(program, &located(&assembler))
+        .ends_with("error: immediate is out of range\n --> line 2:3\n  |\n2 |   beq a0, a1, 0x10000\n  |   ^\n"));
+    let program = "nop\nend: beq a0, a1, 0x10000";
+    let mut assembler = Assembler::new();
+    assembler.insert_source(program);
+    std::assert!(format_error(program, &located(&assembler)).ends_with("     ^\n"));
+
+    // Errors that only happen with an option are located with it.
+    let program = "nop\n.bogus 1";
+    let mut assembler = Assembler::new();
+    assembler.strict(true).insert_source(program);
+    let err = located(&assembler);
+    std::assert!(matches!(err, AssemblerError::LocatedError { line: 1, .. }));
+    std::assert!(format_error(program, &err).ends_with("2 | .bogus 1\n  | ^^^^^^\n"));
+
+    // Errors without a location are just the message.
+    std::assert_eq!(
+        "error: failed to read or write\n",
+        format_error(program, &AssemblerError::IOError)
+    );
+}
+
+#[test]
+fn test_assemble_artifacts() {
+    let program = "_start: li a0, 3\nloop: addi a0, a0, -1\nbnez a0, loop\nend: j end";
+    let artifacts = assemble_artifacts(program, 0x100).unwrap();
+
+    let mut assembler = Assembler::new();
+    assembler.base(0x100).insert_source(program);
+    let prog = assembler.assemble().unwrap();
+    std::assert_eq!(prog.to_bytes(Endian::Little), artifacts.bytes);
+
+    // The HEX data records hold the image, starting at the base.
+    let mut decoded = Vec::new();
+    for record in artifacts.ihex.lines() {
+        if &record[7..9] == "00" {
+            std::assert_eq!(
+                0x100 + decoded.len(),
+                usize::from_str_radix(&record[3..7], 16).unwrap()
+            );
+            let len = usize::from_str_radix(&record[1..3], 16).unwrap();
+            decoded.extend(
+                (0..len).map(|i| u8::from_str_radix(&record[9 + 2 * i..11 + 2 * i], 16).unwrap()),
+            );
+        }
+    }
+    std::assert_eq!(artifacts.bytes, decoded);
+
+    // Every label is in the symbol map, and the listing has every word.
+    std::assert_eq!(
+        "00000100 _start\n00000104 loop\n0000010c end\n",
+        artifacts.symbol_map
+    );
+    let listed: Vec<u32> = artifacts
+        .listing
+        .lines()
+        .map(|l| u32::from_str_radix(&l[10..18], 16).unwrap())
+        .collect();
+    std::assert_eq!(prog.words, listed);
+    std::assert!(artifacts.listing.starts_with("00000100:"));
+
+    std::assert!(assemble_artifacts("addi a0, x99, 1", 0).is_err());
+}
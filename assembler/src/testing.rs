@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use crate::{
+    assembler::{assemble_ir, assemble_program},
+    disassembler::disassemble,
+    error::AssemblerError,
+    program::OutputFormat,
+};
+
+/// Assemble `program` and compare its words to `expected_hex`, a list of
+/// words written as hex like a listing shows them, `00030293`, separated
+/// by any whitespace.
+///
+/// Parameters:
+///     `program: &str`: The program
+///     `expected_hex: &str`: The words it should assemble to
+///
+/// Returns:
+///     `Result<(), String>`: Nothing if every word matches. Otherwise, a
+///     diff of the words with their addresses, with ` ` before matching
+///     words, `-` before expected words, and `+` before assembled words.
+///     Errors assembling the program or reading `expected_hex` are
+///     returned as a message instead.
+pub fn assemble_expect(program: &str, expected_hex: &str) -> Result<(), String> {
+    let expected = expected_hex
+        .split_whitespace()
+        .map(|w| u32::from_str_radix(w, 16).map_err(|_| format!("`{}` is not a hex word", w)))
+        .collect::<Result<Vec<u32>, String>>()?;
+    let prog = assemble_program(program).map_err(|err| format!("{:?}", err))?;
+    if prog.words == expected {
+        return Ok(());
+    }
+
+    let mut diff = String::new();
+    for i in 0..expected.len().max(prog.words.len()) {
+        let addr = prog.base.wrapping_add(4 * i as u32);
+        match (expected.get(i), prog.words.get(i)) {
+            (Some(e), Some(a)) if e == a => diff += &format!("  {:08x}: {:08x}\n", addr, a),
+            (e, a) => {
+                if let Some(e) = e {
+                    diff += &format!("- {:08x}: {:08x}\n", addr, e);
+                }
+                if let Some(a) = a {
+                    diff += &format!("+ {:08x}: {:08x}\n", addr, a);
+                }
+            }
+        }
+    }
+    Err(diff)
+}
+
+/// The program assembled by `self_test`, with one instruction of every
+/// format, a backward branch, and a forward jump.
+const SELF_TEST_PROGRAM: &str = "_start: addi t0, zero, 10
+    lui t1, 0x12345
+loop: add t2, t2, t0
+    addi t0, t0, -1
+    sw t2, -4(sp)
+    lw t3, -4(sp)
+    bne t0, zero, loop
+    jal ra, done
+    srai t4, t3, 3
+done: jalr zero, ra, 0";
+
+/// The image `SELF_TEST_PROGRAM` must assemble to, as little-endian bytes.
+const SELF_TEST_IMAGE: &[u8] = &[
+    0x93, 0x02, 0xa0, 0x00, 0x37, 0x53, 0x34, 0x12, 0xb3, 0x83, 0x53, 0x00, 0x93, 0x82, 0xf2, 0xff,
+    0x23, 0x2e, 0x71, 0xfe, 0x03, 0x2e, 0xc1, 0xff, 0xe3, 0x98, 0x02, 0xfe, 0xef, 0x00, 0x80, 0x00,
+    0x93, 0x5e, 0x3e, 0x40, 0x67, 0x80, 0x00, 0x00,
+];
+
+/// A way `self_test` found the assembler to misbehave.
+#[derive(Debug, PartialEq)]
+pub enum SelfTestMismatch {
+    /// The program did not assemble.
+    Assemble(AssemblerError),
+    /// The raw image is `actual` bytes long instead of `expected`.
+    Length { expected: usize, actual: usize },
+    /// The byte at `offset` of the raw image is wrong.
+    Byte {
+        offset: usize,
+        expected: u8,
+        actual: u8,
+    },
+    /// The word at `addr` did not disassemble to text that assembles back
+    /// to it. `text` is the disassembly, if any, and `reassembled` the word
+    /// it assembled to, if any.
+    RoundTrip {
+        addr: u32,
+        word: u32,
+        text: Option<String>,
+        reassembled: Option<u32>,
+    },
+}
+
+/// Assemble a small canonical program, write it as raw bytes, compare them
+/// to a known-good image, and check that every word disassembles to text
+/// that assembles back to the same word. This exercises the assembler, an
+/// exporter, and the disassembler together, so downstream crates can call
+/// it to check the crate works in their environment.
+///
+/// Returns:
+///     `Result<(), Vec<SelfTestMismatch>>`: Nothing if everything matches,
+///     or every mismatch found, in order.
+pub fn self_test() -> Result<(), Vec<SelfTestMismatch>> {
+    let prog = match assemble_program(SELF_TEST_PROGRAM) {
+        Ok(prog) => prog,
+        Err(err) => return Err(vec![SelfTestMismatch::Assemble(err)]),
+    };
+    let mut mismatches = Vec::new();
+
+    let mut image = Vec::new();
+    // Writing to a `Vec` never fails.
+    prog.write(&mut image, OutputFormat::LeBytes).unwrap();
+    if image.len() != SELF_TEST_IMAGE.len() {
+        mismatches.push(SelfTestMismatch::Length {
+            expected: SELF_TEST_IMAGE.len(),
+            actual: image.len(),
+        });
+    }
+    for (offset, (expected, actual)) in SELF_TEST_IMAGE.iter().zip(&image).enumerate() {
+        if expected != actual {
+            mismatches.push(SelfTestMismatch::Byte {
+                offset,
+                expected: *expected,
+                actual: *actual,
+            });
+        }
+    }
+
+    for (i, word) in prog.words.iter().enumerate() {
+        let addr = prog.base.wrapping_add(4 * i as u32);
+        let text = disassemble(*word).ok().map(|ins| ins.to_string());
+        let reassembled = text
+            .as_ref()
+            .and_then(|text| assemble_ir(text, &mut HashMap::new(), addr).ok()?);
+        if reassembled != Some(*word) {
+            mismatches.push(SelfTestMismatch::RoundTrip {
+                addr,
+                word: *word,
+                text,
+                reassembled,
+            });
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches)
+    }
+}